@@ -1,6 +1,6 @@
 use super::{step::Step, Conflict, DependencyError, DependencyResult, Requirement};
 use crate::store::{backend::ReadBackend, id::PackageId, object::Blob, Store};
-use daggy::{Dag, NodeIndex};
+use daggy::{petgraph::visit::EdgeRef, Dag, NodeIndex};
 use std::collections::{HashMap, HashSet};
 
 /// A directed acyclic graph for dependency resolution.
@@ -34,6 +34,7 @@ pub struct DependencyGraph<'a> {
     objects: HashSet<&'a Blob>,
     visited: HashMap<&'a Requirement, NodeIndex<usize>>,
     inserted: HashMap<PackageId, NodeIndex<usize>>,
+    roots: HashMap<&'a Requirement, NodeIndex<usize>>,
 }
 
 // TODO add resolve_packages function that resolves the graph
@@ -78,14 +79,15 @@ impl<'a> DependencyGraph<'a> {
     /// # Ok(())
     ///# }
     /// ```
-    pub fn resolve<S, B: ReadBackend>(
+    pub fn resolve<S, B: ReadBackend, const BAR: bool>(
         &mut self,
         requirements: impl IntoIterator<Item = &'a Requirement>,
-        store: &'a Store<S, B>,
+        store: &'a Store<S, B, BAR>,
     ) -> DependencyResult<()> {
         let mut choices = HashMap::new();
 
-        let _nodes = self.resolve_multiple(requirements, store, &mut choices)?;
+        let nodes = self.resolve_multiple(requirements, store, &mut choices)?;
+        self.roots.extend(nodes);
         self.resolve_choices(choices, store)?;
 
         // TODO really all choices resolved ?
@@ -119,10 +121,42 @@ impl<'a> DependencyGraph<'a> {
         self.relations.graph().node_weights()
     }
 
-    fn resolve_multiple<S, B: ReadBackend>(
+    /// Returns every path from the resolved requirements to the package.
+    /// Every step of a path is the [Requirement] together with the [PackageId] it resolved to.
+    pub fn paths(&self, target: &PackageId) -> Vec<Vec<(&'a Requirement, PackageId)>> {
+        let mut paths = Vec::new();
+        for (req, node) in &self.roots {
+            self.collect_paths(req, *node, target, &mut Vec::new(), &mut paths);
+        }
+        paths.sort();
+        paths
+    }
+
+    fn collect_paths(
+        &self,
+        req: &'a Requirement,
+        node: NodeIndex<usize>,
+        target: &PackageId,
+        path: &mut Vec<(&'a Requirement, PackageId)>,
+        paths: &mut Vec<Vec<(&'a Requirement, PackageId)>>,
+    ) {
+        if let Some(id) = self.relations.node_weight(node).and_then(Step::as_resolved) {
+            path.push((req, id));
+            if &id == target {
+                paths.push(path.clone());
+            } else {
+                for edge in self.relations.graph().edges(node) {
+                    self.collect_paths(edge.weight(), edge.target(), target, path, paths);
+                }
+            }
+            path.pop();
+        }
+    }
+
+    fn resolve_multiple<S, B: ReadBackend, const BAR: bool>(
         &mut self,
         requirements: impl IntoIterator<Item = &'a Requirement>,
-        store: &'a Store<S, B>,
+        store: &'a Store<S, B, BAR>,
         choices: &mut HashMap<&'a Requirement, NodeIndex<usize>>,
     ) -> DependencyResult<HashMap<&'a Requirement, NodeIndex<usize>>> {
         let mut nodes = HashMap::new();
@@ -161,10 +195,10 @@ impl<'a> DependencyGraph<'a> {
         Ok(nodes)
     }
 
-    fn resolve_single<S, B: ReadBackend>(
+    fn resolve_single<S, B: ReadBackend, const BAR: bool>(
         &mut self,
         req: &'a Requirement,
-        store: &'a Store<S, B>,
+        store: &'a Store<S, B, BAR>,
         choices: &mut HashMap<&'a Requirement, NodeIndex<usize>>,
     ) -> DependencyResult<NodeIndex<usize>> {
         let options = store
@@ -206,10 +240,10 @@ impl<'a> DependencyGraph<'a> {
         Ok(node)
     }
 
-    fn resolve_choices<S, B: ReadBackend>(
+    fn resolve_choices<S, B: ReadBackend, const BAR: bool>(
         &mut self,
         choices: HashMap<&'a Requirement, NodeIndex<usize>>,
-        store: &'a Store<S, B>,
+        store: &'a Store<S, B, BAR>,
     ) -> DependencyResult<()> {
         let mut future_choices = HashMap::new();
         for (req, node) in choices {
@@ -280,6 +314,7 @@ fn conflicts<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::extra::hash;
     use crate::store::{LocalStore, StoreError};
     use crate::support::*;
     use std::assert_matches::assert_matches;
@@ -324,6 +359,53 @@ mod tests {
         assert_eq!(4, graph.resolved_packages().count());
     }
 
+    #[test]
+    fn dependency_graph_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let store_path = temp_dir.child("store");
+
+        let mut graph = DependencyGraph::new();
+        let mut store = LocalStore::init(store_path).unwrap();
+
+        let one = pkg("one", temp_dir.child("one"));
+        let one_req = req("one", ">0.0.0");
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+
+        let two = pkg_req("two", temp_dir.child("two"), [one_req.clone()]);
+        let two_req = req("two", ">0.0.0");
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+
+        let three = pkg_req("three", temp_dir.child("three"), [one_req.clone(), two_req]);
+        let three_req = req("three", ">0.0.0");
+        let three_id = hash::root_hash(&three.path, three.name()).unwrap();
+
+        store
+            .extend(vec![one, two, three])
+            .collect::<Result<Vec<PathBuf>, StoreError>>()
+            .unwrap();
+
+        graph.resolve([&three_req, &one_req], &store).unwrap();
+
+        let paths = graph
+            .paths(&one_id)
+            .into_iter()
+            .map(|path| {
+                path.into_iter()
+                    .map(|(req, id)| (req.name().as_str(), id))
+                    .collect()
+            })
+            .collect::<Vec<Vec<_>>>();
+        assert_eq!(
+            paths,
+            [
+                vec![("one", one_id)],
+                vec![("three", three_id), ("one", one_id)],
+                vec![("three", three_id), ("two", two_id), ("one", one_id)],
+            ]
+        );
+        assert_eq!(graph.paths(&three_id).len(), 1);
+    }
+
     #[test]
     fn dependency_graph_resolve_ok_not_resolved() {
         let temp_dir = TempDir::new().unwrap();
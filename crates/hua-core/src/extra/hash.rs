@@ -1,32 +1,129 @@
-use super::path;
-use rs_merkle::{Hasher, MerkleTree};
+//! Canonical hashing of packages.
+//!
+//! Every hash starts with the [HASH_VERSION] and a tag for the kind of entry,
+//! followed by the length prefixed path of the entry relative to the package.
+//! Lengths and modes are encoded as little endian integers, all hashes are Blake3.
+//!
+//! - Blob: `version 'b' path mode:u32 size:u64 contents`
+//! - Link: `version 'l' path target_len:u64 target`
+//! - Tree: `version 't' path count:u64 children`
+//! - Package: `version 'p' package_name count:u64 children`
+//!
+//! As the path is part of the id, identical files in different directories of a package
+//! have different ids and every [ObjectId] belongs to exactly one path.
+//!
+//! The children of a tree are the ids of its direct entries sorted by their file name
+//! bytes, so the ids do not depend on the file system or the order of directory entries.
+//! The mode only contains the permission bits without any write bits.
+//!
+//! As every id only depends on its path and the entries below it, subdirectories are hashed
+//! in parallel and large files are memory mapped, which results in the same ids as hashing
+//! sequentially.
+
+use rayon::prelude::*;
+use relative_path::{RelativePath, RelativePathBuf};
 use std::{
     collections::BTreeMap,
-    fs::File,
-    io::{self, Read},
+    fs::{self, DirEntry},
+    io::{self, Error, ErrorKind},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
     path::Path,
 };
-use walkdir::WalkDir;
 
 use crate::store::{
     id::{ObjectId, PackageId, RawId},
-    object::{Blob, Tree},
+    object::{Blob, Link, Tree},
 };
 
-/// Provides a Blake3 [Hasher] for the [MerkleTree].
-#[derive(Clone)]
-pub struct Blake3;
+/// The version of the canonical serialization.
+/// Has to be incremented whenever the serialization changes,
+/// so that stores can be migrated.
+pub const HASH_VERSION: u8 = 2;
+
+const BLOB_TAG: u8 = b'b';
+const LINK_TAG: u8 = b'l';
+const TREE_TAG: u8 = b't';
+const PACKAGE_TAG: u8 = b'p';
+
+fn hasher(tag: u8, path: &[u8]) -> blake3::Hasher {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[HASH_VERSION, tag]);
+    hasher.update(&(path.len() as u64).to_le_bytes());
+    hasher.update(path);
+    hasher
+}
 
-impl Hasher for Blake3 {
-    type Hash = RawId;
+fn finalize(hasher: blake3::Hasher) -> RawId {
+    <[u8; 32]>::from(hasher.finalize()).into()
+}
+
+fn hash_children(tag: u8, name: &[u8], children: &[RawId]) -> RawId {
+    let mut hasher = hasher(tag, name);
+    hasher.update(&(children.len() as u64).to_le_bytes());
+    for child in children {
+        hasher.update(&<Vec<u8>>::from(*child));
+    }
+    finalize(hasher)
+}
 
-    fn hash(data: &[u8]) -> Self::Hash {
-        let mut hasher = blake3::Hasher::new();
+/// Hashes the file under the path with the given normalized mode,
+/// `relative` is the path of the file inside its package.
+/// The file is memory mapped and, if `parallel` is set, large files
+/// are hashed on multiple threads.
+pub fn hash_blob(relative: &[u8], mode: u32, path: &Path, parallel: bool) -> io::Result<RawId> {
+    let size = fs::symlink_metadata(path)?.len();
 
-        hasher.update(data);
-        let hash = <[u8; 32]>::from(hasher.finalize());
-        hash.into()
+    let mut hasher = hasher(BLOB_TAG, relative);
+    hasher.update(&mode.to_le_bytes());
+    hasher.update(&size.to_le_bytes());
+    if parallel {
+        hasher.update_mmap_rayon(path)?;
+    } else {
+        hasher.update_mmap(path)?;
     }
+
+    Ok(finalize(hasher))
+}
+
+/// Hashes a symbolic link with its target.
+pub fn hash_link(relative: &[u8], target: &Path) -> RawId {
+    let target = target.as_os_str().as_bytes();
+
+    let mut hasher = hasher(LINK_TAG, relative);
+    hasher.update(&(target.len() as u64).to_le_bytes());
+    hasher.update(target);
+    finalize(hasher)
+}
+
+/// Hashes a directory out of the ids of its sorted entries.
+pub fn hash_tree(relative: &[u8], children: &[RawId]) -> RawId {
+    hash_children(TREE_TAG, relative, children)
+}
+
+/// Hashes a package out of its name and the ids of the sorted entries of its directory.
+pub fn hash_package(package_name: &str, children: &[RawId]) -> PackageId {
+    hash_children(PACKAGE_TAG, package_name.as_bytes(), children).into()
+}
+
+/// Hashes the file or symbolic link under `relative` inside the package directory `root`.
+pub fn hash_path(root: &Path, relative: &RelativePath) -> io::Result<ObjectId> {
+    let path = relative.to_path(root);
+    let name = relative.as_str().as_bytes();
+    let metadata = fs::symlink_metadata(&path)?;
+
+    let hash = if metadata.file_type().is_symlink() {
+        hash_link(name, &path.read_link()?)
+    } else if metadata.is_file() {
+        let mode = Blob::normalize_mode(metadata.permissions().mode());
+        hash_blob(name, mode, &path, false)?
+    } else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unsupported file type of {path:?}"),
+        ));
+    };
+
+    Ok(hash.into())
 }
 
 pub fn root_hash(path: &Path, name: &str) -> io::Result<PackageId> {
@@ -39,8 +136,6 @@ pub fn verify(package_id: PackageId, path: &Path, name: &str) -> io::Result<bool
     Ok(pkg_hash.root == package_id)
 }
 
-// TODO: use PartialTrees and merge them when we get to upper directories
-
 /// Calculates the hashes for the directories of an [crate::store::package::Package].
 #[derive(Debug)]
 pub struct PackageHash {
@@ -53,10 +148,35 @@ pub struct PackageHash {
 
     /// All [ObjectId] of all [Blob] in the directory.
     pub blobs: BTreeMap<Blob, ObjectId>,
+
+    /// All [ObjectId] of all [Link] in the directory.
+    pub links: BTreeMap<Link, ObjectId>,
+
+    /// The size of all blobs in bytes.
+    pub size: u64,
+}
+
+/// The objects found below a directory.
+#[derive(Default)]
+struct PartialHash {
+    trees: BTreeMap<Tree, ObjectId>,
+    blobs: BTreeMap<Blob, ObjectId>,
+    links: BTreeMap<Link, ObjectId>,
+    size: u64,
+}
+
+impl PartialHash {
+    fn merge(&mut self, other: Self) {
+        self.trees.extend(other.trees);
+        self.blobs.extend(other.blobs);
+        self.links.extend(other.links);
+        self.size += other.size;
+    }
 }
 
 impl PackageHash {
-    /// Calculates the hashes of an directory
+    /// Calculates the hashes of an directory,
+    /// independent subdirectories and large files are hashed in parallel.
     ///
     /// # Arguments
     ///
@@ -76,85 +196,171 @@ impl PackageHash {
     /// # Ok(())
     /// # }
     pub fn from_path<P: AsRef<Path>>(path: P, package_name: &str) -> io::Result<Self> {
-        let mut trees = BTreeMap::new();
-        let mut blobs = BTreeMap::new();
+        Self::from_path_with(path, package_name, true)
+    }
 
-        let mut tree = MerkleTree::<Blake3>::new();
-        let mut dir_children: Vec<RawId> = Vec::new();
+    /// Calculates the hashes of an directory on the current thread only.
+    /// Returns exactly the same ids as [PackageHash::from_path].
+    pub fn from_path_sequential<P: AsRef<Path>>(path: P, package_name: &str) -> io::Result<Self> {
+        Self::from_path_with(path, package_name, false)
+    }
 
+    fn from_path_with<P: AsRef<Path>>(
+        path: P,
+        package_name: &str,
+        parallel: bool,
+    ) -> io::Result<Self> {
         let root_path = path.as_ref().canonicalize()?;
 
-        for entry in WalkDir::new(&root_path).contents_first(true) {
-            let entry = entry?;
-            let path = entry.path();
-
-            if path == root_path {
-                let hash = Blake3::hash(package_name.as_bytes());
-                tree.insert(hash);
-                tree.commit();
-
-                return Ok(Self {
-                    root: unsafe { tree.root().unwrap_unchecked() }.into(),
-                    trees,
-                    blobs,
-                });
-            } else if path.is_file() {
-                let name = path.file_name().unwrap().to_str().unwrap();
-                let mut bytes = <Vec<u8>>::from(name);
-
-                let mut file = File::open(path)?;
-                file.read(&mut bytes)?;
-
-                let hash = Blake3::hash(&bytes);
-
-                tree.insert(hash);
-                dir_children.push(hash);
-
-                let path = path::relative_path_between(&root_path, path)?;
-                blobs.insert(Blob::new(path), hash.into());
-            } else if path.is_dir() {
-                let name = path.file_name().unwrap().to_str().unwrap();
-
-                let hash = Blake3::hash(name.as_bytes());
-                tree.insert(hash);
-                tree.commit();
-
-                let inner_children = std::mem::replace(&mut dir_children, Vec::new());
-                let path = path::relative_path_between(&root_path, path)?;
-                let root = {
-                    // Calculate root from another tree so that subelemnts of parallel directories
-                    // are not included
-                    // Use partial trees
-                    let mut tree = MerkleTree::<Blake3>::from_leaves(&inner_children);
-                    tree.insert(hash);
-                    tree.commit();
-                    unsafe { tree.root().unwrap_unchecked() }
-                };
-
-                trees.insert(
-                    Tree::new(
-                        path,
-                        inner_children
-                            .into_iter()
-                            .map(Into::into)
-                            .collect::<Vec<ObjectId>>(),
-                    ),
-                    root.into(),
-                );
-            } else if path.is_symlink() {
-                todo!()
-            }
-        }
-        unreachable!()
+        let (children, partial) = hash_dir(&root_path, &root_path, parallel)?;
+        let PartialHash {
+            trees,
+            blobs,
+            links,
+            size,
+        } = partial;
+
+        Ok(Self {
+            root: hash_package(package_name, &children),
+            trees,
+            blobs,
+            links,
+            size,
+        })
+    }
+}
+
+/// Hashes all entries of a directory.
+/// Returns the ids of the entries sorted by their file names
+/// and all objects found below the directory.
+fn hash_dir(root: &Path, dir: &Path, parallel: bool) -> io::Result<(Vec<RawId>, PartialHash)> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    // Sorted so that the hash does not depend on the order of the directory entries
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let hashes = if parallel {
+        entries
+            .par_iter()
+            .map(|entry| hash_entry(root, entry, parallel))
+            .collect::<io::Result<Vec<_>>>()?
+    } else {
+        entries
+            .iter()
+            .map(|entry| hash_entry(root, entry, parallel))
+            .collect::<io::Result<Vec<_>>>()?
+    };
+
+    let mut children = Vec::with_capacity(hashes.len());
+    let mut partial = PartialHash::default();
+
+    for (hash, other) in hashes {
+        children.push(hash);
+        partial.merge(other);
     }
+
+    Ok((children, partial))
+}
+
+fn hash_entry(root: &Path, entry: &DirEntry, parallel: bool) -> io::Result<(RawId, PartialHash)> {
+    let path = entry.path();
+    let file_type = entry.file_type()?;
+
+    let relative = path
+        .strip_prefix(root)
+        .map_err(|err| Error::new(ErrorKind::Other, err))
+        .and_then(|relative| {
+            RelativePathBuf::from_path(relative)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+        })?;
+    let name = relative.as_str().as_bytes();
+
+    let mut partial = PartialHash::default();
+
+    let hash = if file_type.is_symlink() {
+        // The link itself is hashed, never the file it points to
+        let target = path.read_link()?;
+        let hash = hash_link(name, &target);
+
+        partial
+            .links
+            .insert(Link::new(relative, target), hash.into());
+        hash
+    } else if file_type.is_file() {
+        let metadata = entry.metadata()?;
+        let mode = Blob::normalize_mode(metadata.permissions().mode());
+        let hash = hash_blob(name, mode, &path, parallel)?;
+
+        partial.size += metadata.len();
+        partial
+            .blobs
+            .insert(Blob::with_mode(relative, mode), hash.into());
+        hash
+    } else if file_type.is_dir() {
+        let (children, inner) = hash_dir(root, &path, parallel)?;
+        let hash = hash_tree(name, &children);
+
+        partial.merge(inner);
+        partial.trees.insert(
+            Tree::new(
+                relative,
+                children
+                    .into_iter()
+                    .map(Into::into)
+                    .collect::<Vec<ObjectId>>(),
+            ),
+            hash.into(),
+        );
+        hash
+    } else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unsupported file type of {path:?}"),
+        ));
+    };
+
+    Ok((hash, partial))
 }
 
 #[cfg(test)]
 mod tests {
     use super::PackageHash;
-    use std::fs::{self, File};
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        fs::{self, File, Permissions},
+        os::unix::{self, fs::PermissionsExt},
+        path::Path,
+    };
     use temp_dir::TempDir;
 
+    const GOLDEN_EMPTY: &str = "ccc5e0749e2795424e3880297e7ef23497294fd04192ef22d6f8a914723d0dae";
+    const GOLDEN_ROOT: &str = "021427589471c3c051cd8c777688e2f03cad733d1d73b181018b914b737a722f";
+    const GOLDEN_OBJECTS: [(&str, &str); 6] = [
+        (
+            "bin",
+            "b4bd667b7547e8cf33d1b4251fb8a7f8d18be0ec2b1e1412f3d008c03a710c39",
+        ),
+        (
+            "bin/hello",
+            "c167b0759beb27b786196ad5653b7c1b6dae0f38d625ddb84f79d8af3df687b5",
+        ),
+        (
+            "lib",
+            "66794005fe415ba1c0f80966042db6ab7f28b7e02f1ecbfd7ccb071f5254ee5e",
+        ),
+        (
+            "lib/libfoo.so",
+            "3d83a808ddbb7f5e596b6c2b6fcff20cce41c2bcde7c08037d8ccf8fe06cecf6",
+        ),
+        (
+            "lib/libfoo.so.1",
+            "6d2bc755ad973470f4d9aad6cb76d30a6ec144db3b110de6e30b4540787db842",
+        ),
+        (
+            "share",
+            "404477c5bd1ff919bbc71855ae2b690db7ca7b89f193e689f1b5c2abf3833525",
+        ),
+    ];
+
     #[test]
     fn package_hash_from_path_ok() {
         let temp_dir = TempDir::new().unwrap();
@@ -169,6 +375,173 @@ mod tests {
         let _ok = PackageHash::from_path(pkg_dir, "pkg").unwrap();
     }
 
+    #[test]
+    fn package_hash_from_path_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let hash = |target: &str| {
+            let pkg_dir = temp_dir.child(target);
+            let lib_dir = pkg_dir.join("lib");
+            fs::create_dir_all(&lib_dir).unwrap();
+            let _file = File::create(lib_dir.join("libfoo.so.1.2")).unwrap();
+            unix::fs::symlink(target, lib_dir.join("libfoo.so")).unwrap();
+
+            PackageHash::from_path(pkg_dir, "pkg").unwrap()
+        };
+
+        let one = hash("libfoo.so.1.2");
+        let other = hash("libfoo.so.1");
+
+        assert_eq!(one.blobs.len(), 1);
+        assert_eq!(one.links.len(), 1);
+        let (link, _id) = one.links.iter().next().unwrap();
+        assert_eq!(link.link.as_str(), "lib/libfoo.so");
+        assert_eq!(link.target.to_str(), Some("libfoo.so.1.2"));
+
+        assert_ne!(one.root, other.root);
+    }
+
+    #[test]
+    fn package_hash_from_path_mode() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let hash = |name: &str, mode: u32| {
+            let pkg_dir = temp_dir.child(name);
+            let bin_dir = pkg_dir.join("bin");
+            fs::create_dir_all(&bin_dir).unwrap();
+            let file_path = bin_dir.join("some_file");
+            let _file = File::create(&file_path).unwrap();
+            fs::set_permissions(&file_path, Permissions::from_mode(mode)).unwrap();
+
+            PackageHash::from_path(pkg_dir, "pkg").unwrap()
+        };
+
+        let writable = hash("writable", 0o755);
+        let executable = hash("executable", 0o555);
+        let plain = hash("plain", 0o644);
+
+        let (blob, _id) = writable.blobs.iter().next().unwrap();
+        assert_eq!(blob.mode, 0o555);
+
+        assert_eq!(writable.root, executable.root);
+        assert_ne!(executable.root, plain.root);
+    }
+
+    #[test]
+    fn package_hash_from_path_contents() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let hash = |name: &str, contents: &[u8]| {
+            let pkg_dir = temp_dir.child(name);
+            fs::create_dir_all(&pkg_dir).unwrap();
+            fs::write(pkg_dir.join("file"), contents).unwrap();
+
+            PackageHash::from_path(pkg_dir, "pkg").unwrap().root
+        };
+
+        // Differ only after the length of the file name
+        let one = hash("one", b"file contents one");
+        let two = hash("two", b"file contents two");
+        let three = hash("three", b"file contents one");
+
+        assert_ne!(one, two);
+        assert_eq!(one, three);
+    }
+
+    #[test]
+    fn package_hash_from_path_duplicate_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let pkg_dir = temp_dir.child("pkg");
+        for dir in ["a", "b", "c/a"] {
+            fs::create_dir_all(pkg_dir.join(dir)).unwrap();
+            fs::write(pkg_dir.join(dir).join("__init__.py"), b"").unwrap();
+        }
+
+        let hash = PackageHash::from_path(&pkg_dir, "pkg").unwrap();
+
+        // Every path has its own id, even if the name and contents are the same
+        let blobs = hash.blobs.values().collect::<BTreeSet<_>>();
+        assert_eq!(blobs.len(), 3);
+        let trees = hash.trees.values().collect::<BTreeSet<_>>();
+        assert_eq!(trees.len(), 4);
+    }
+
+    fn golden_package(path: &Path) {
+        let bin_dir = path.join("bin");
+        let lib_dir = path.join("lib");
+        fs::create_dir_all(&bin_dir).unwrap();
+        fs::create_dir_all(&lib_dir).unwrap();
+        fs::create_dir_all(path.join("share")).unwrap();
+
+        fs::write(bin_dir.join("hello"), b"#!/bin/sh\necho hello\n").unwrap();
+        fs::set_permissions(bin_dir.join("hello"), Permissions::from_mode(0o755)).unwrap();
+        fs::write(lib_dir.join("libfoo.so.1"), b"foo").unwrap();
+        fs::set_permissions(lib_dir.join("libfoo.so.1"), Permissions::from_mode(0o644)).unwrap();
+        unix::fs::symlink("libfoo.so.1", lib_dir.join("libfoo.so")).unwrap();
+    }
+
+    #[test]
+    fn package_hash_golden_vectors() {
+        let temp_dir = TempDir::new().unwrap();
+        let pkg_dir = temp_dir.child("golden");
+        golden_package(&pkg_dir);
+
+        let hash = PackageHash::from_path(&pkg_dir, "golden").unwrap();
+
+        assert_eq!(hash.root.to_string(), GOLDEN_ROOT);
+
+        let ids = hash
+            .blobs
+            .iter()
+            .map(|(blob, id)| (blob.path.as_str(), id.to_string()))
+            .chain(
+                hash.links
+                    .iter()
+                    .map(|(link, id)| (link.link.as_str(), id.to_string())),
+            )
+            .chain(
+                hash.trees
+                    .iter()
+                    .map(|(tree, id)| (tree.path.as_str(), id.to_string())),
+            )
+            .collect::<BTreeMap<_, _>>();
+
+        assert_eq!(ids.len(), GOLDEN_OBJECTS.len());
+        for (path, id) in GOLDEN_OBJECTS {
+            assert_eq!(ids[path], id, "{path}");
+        }
+    }
+
+    #[test]
+    fn package_hash_parallel_sequential() {
+        let temp_dir = TempDir::new().unwrap();
+        let pkg_dir = temp_dir.child("golden");
+        golden_package(&pkg_dir);
+
+        // Large enough to be hashed on multiple threads
+        let contents = (0..(1 << 20)).map(|i| i as u8).collect::<Vec<u8>>();
+        fs::write(pkg_dir.join("share/large"), &contents).unwrap();
+
+        let parallel = PackageHash::from_path(&pkg_dir, "golden").unwrap();
+        let sequential = PackageHash::from_path_sequential(&pkg_dir, "golden").unwrap();
+
+        assert_eq!(parallel.root, sequential.root);
+        assert_eq!(parallel.blobs, sequential.blobs);
+        assert_eq!(parallel.trees, sequential.trees);
+        assert_eq!(parallel.links, sequential.links);
+        assert_eq!(parallel.size, contents.len() as u64 + 21 + 3);
+    }
+
+    #[test]
+    fn package_hash_from_path_empty() {
+        let temp_dir = TempDir::new().unwrap();
+        let pkg_dir = temp_dir.child("empty");
+        fs::create_dir(&pkg_dir).unwrap();
+
+        let hash = PackageHash::from_path(&pkg_dir, "empty").unwrap();
+        assert_eq!(hash.root.to_string(), GOLDEN_EMPTY);
+    }
+
     // #[test]
     // fn package_hash_from_path_err() {
     //     let _err = PackageHash::from_path("..", "pkg").unwrap_err();
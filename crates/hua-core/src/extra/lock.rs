@@ -0,0 +1,105 @@
+use fs2::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a busy lock is tried again while waiting.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The kind of access a [FileLock] grants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of processes may read at the same time.
+    Shared,
+    /// A single process may read and write.
+    Exclusive,
+}
+
+/// An advisory lock on a file shared between processes.
+/// The lock is released when it is dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    mode: LockMode,
+}
+
+impl FileLock {
+    /// Locks the file at the path, which is created if it does not exist.
+    /// If the lock is held by another process, it is tried again until the timeout
+    /// elapsed. Returns an error of kind [io::ErrorKind::WouldBlock] if the lock could
+    /// not be acquired.
+    pub fn acquire(path: &Path, mode: LockMode, timeout: Option<Duration>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        let start = Instant::now();
+
+        loop {
+            let result = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(&file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+            };
+
+            match result {
+                Ok(()) => return Ok(Self { file, mode }),
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    if timeout.map_or(true, |timeout| start.elapsed() >= timeout) {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, e));
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.mode == LockMode::Exclusive
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileLock, LockMode};
+    use std::{io, time::Duration};
+    use temp_dir::TempDir;
+
+    #[test]
+    fn file_lock_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("lock");
+
+        let first = FileLock::acquire(&path, LockMode::Shared, None).unwrap();
+        let second = FileLock::acquire(&path, LockMode::Shared, None).unwrap();
+        let err = FileLock::acquire(&path, LockMode::Exclusive, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(first);
+        drop(second);
+        let exclusive = FileLock::acquire(&path, LockMode::Exclusive, None).unwrap();
+        assert!(exclusive.is_exclusive());
+
+        let timeout = Some(Duration::from_millis(100));
+        let err = FileLock::acquire(&path, LockMode::Shared, timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(exclusive);
+        assert!(FileLock::acquire(&path, LockMode::Shared, timeout).is_ok());
+    }
+}
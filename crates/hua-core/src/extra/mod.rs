@@ -4,6 +4,8 @@ use semver::{Comparator, Op, Version, VersionReq};
 pub mod fs;
 /// Hashing operations.
 pub mod hash;
+/// File locks shared between processes.
+pub mod lock;
 /// Memory operations.
 pub mod mem;
 /// Path manipulation.
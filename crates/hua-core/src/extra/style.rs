@@ -2,6 +2,8 @@ use std::borrow::Cow;
 
 use indicatif::ProgressStyle;
 
+pub use indicatif::HumanBytes;
+
 pub struct ProgressBar {
     bar: indicatif::ProgressBar,
 }
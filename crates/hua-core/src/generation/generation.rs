@@ -1,8 +1,13 @@
-use crate::{dependency::Requirement, extra::path::ComponentPathBuf, store::id::PackageId};
+use super::*;
+use crate::{
+    dependency::Requirement,
+    extra::path::ComponentPathBuf,
+    store::{backend::ReadBackend, id::PackageId, Store},
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::{
-    fmt,
+    fmt, fs,
     path::{Path, PathBuf},
 };
 
@@ -69,4 +74,37 @@ impl Generation {
     pub fn contains_requirement(&self, requirement: &Requirement) -> bool {
         self.requirements.contains(requirement)
     }
+
+    /// Replaces the ids of migrated packages and links the packages again.
+    pub fn relink<B: ReadBackend<Source = PathBuf>>(
+        &mut self,
+        migrated: &BTreeMap<PackageId, PackageId>,
+        store: &Store<PathBuf, B>,
+    ) -> GenerationResult<()> {
+        if !self.packages.iter().any(|id| migrated.contains_key(id)) {
+            return Ok(());
+        }
+
+        self.packages = self
+            .packages
+            .drain()
+            .map(|id| *migrated.get(&id).unwrap_or(&id))
+            .collect();
+
+        for dir in [
+            &self.component_paths.binary,
+            &self.component_paths.config,
+            &self.component_paths.library,
+            &self.component_paths.share,
+        ] {
+            if dir.exists() {
+                fs::remove_dir_all(dir).context(IoSnafu)?;
+            }
+        }
+        self.component_paths.create_dirs(false).context(IoSnafu)?;
+
+        store
+            .link_packages(&self.packages, &self.component_paths)
+            .context(StoreSnafu)
+    }
 }
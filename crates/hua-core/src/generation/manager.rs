@@ -6,7 +6,7 @@ use crate::{
     store::{backend::ReadBackend, id::PackageId, Store},
 };
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::{
     fs,
     path::{Path, PathBuf},
@@ -153,6 +153,10 @@ impl GenerationManager {
             .flat_map(|(_id, gen)| gen.packages().iter())
     }
 
+    pub fn get(&self, id: usize) -> Option<&Generation> {
+        self.generations.get(&id)
+    }
+
     pub fn current_requirements(&self) -> &HashSet<Requirement> {
         self.get_current().requirements()
     }
@@ -171,6 +175,18 @@ impl GenerationManager {
         }
     }
 
+    /// Replaces the ids of migrated packages in all generations.
+    pub fn relink<B: ReadBackend<Source = PathBuf>>(
+        &mut self,
+        migrated: &BTreeMap<PackageId, PackageId>,
+        store: &Store<PathBuf, B>,
+    ) -> GenerationResult<()> {
+        for generation in self.generations.values_mut() {
+            generation.relink(migrated, store)?;
+        }
+        Ok(())
+    }
+
     pub fn switch_to(
         &mut self,
         id: usize,
@@ -42,28 +42,201 @@ pub mod url {
 
 pub mod config {
     use std::{
+        collections::{BTreeMap, BTreeSet},
         error::Error,
         fs,
         os::unix,
         path::{Path, PathBuf},
+        time::Duration,
     };
 
-    use serde::{Deserialize, Serialize};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use url::Url;
 
-    use crate::{GID, UID};
+    use crate::{
+        store::{backend::BackendKind, gc::GcPolicy, signature::PublicKey},
+        GID, UID,
+    };
+
+    /// The lowest priority of a cache
+    pub const MIN_PRIORITY: u8 = 1;
+    /// The highest priority of a cache
+    pub const MAX_PRIORITY: u8 = 10;
+    /// The priority of caches added without one
+    pub const DEFAULT_PRIORITY: u8 = 5;
+
+    fn default_priority() -> u8 {
+        DEFAULT_PRIORITY
+    }
+
+    fn default_enabled() -> bool {
+        true
+    }
+
+    /// Writes the timeout of a cache as whole seconds.
+    fn serialize_timeout<S: Serializer>(
+        timeout: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        timeout
+            .map(|timeout| timeout.as_secs())
+            .serialize(serializer)
+    }
+
+    fn deserialize_timeout<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+    }
+
+    /// The settings of a cache.
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    pub struct CacheConfig {
+        pub url: Url,
+        /// Caches with a higher priority are searched first, from [MIN_PRIORITY] to [MAX_PRIORITY].
+        #[serde(default = "default_priority")]
+        pub priority: u8,
+        /// Disabled caches are kept in the config, but never searched.
+        #[serde(default = "default_enabled")]
+        pub enabled: bool,
+        /// Requests to the cache are aborted after the timeout, given in seconds.
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "serialize_timeout",
+            deserialize_with = "deserialize_timeout"
+        )]
+        pub timeout: Option<Duration>,
+        /// The keys whose signatures are accepted for the cache.
+        #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+        pub trusted_keys: BTreeSet<PublicKey>,
+    }
+
+    impl CacheConfig {
+        pub fn new(url: Url) -> Self {
+            Self {
+                url,
+                priority: DEFAULT_PRIORITY,
+                enabled: true,
+                timeout: None,
+                trusted_keys: BTreeSet::new(),
+            }
+        }
+
+        /// Parses the url of a cache, plain paths are local cache directories which
+        /// become `file://` urls. The url always ends with a slash, so that the files
+        /// of the cache are found below it.
+        pub fn parse_url(value: &str) -> Result<Url, Box<dyn Error>> {
+            let mut url = match Url::parse(value) {
+                Ok(url) => url,
+                Err(_) => {
+                    let path = fs::canonicalize(value).unwrap_or_else(|_| PathBuf::from(value));
+                    Url::from_directory_path(&path)
+                        .map_err(|_| format!("{path:?} is not an absolute path"))?
+                }
+            };
+            if !url.path().ends_with('/') {
+                let path = format!("{}/", url.path());
+                url.set_path(&path);
+            }
+            Ok(url)
+        }
+
+        pub fn with_priority(mut self, priority: u8) -> Self {
+            self.priority = priority;
+            self
+        }
+
+        pub fn with_trusted_keys(mut self, keys: impl IntoIterator<Item = PublicKey>) -> Self {
+            self.trusted_keys.extend(keys);
+            self
+        }
+    }
+
+    /// A cache in the config, which is only an url in configs written by older versions.
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum CacheEntry {
+        Url(Url),
+        Config(CacheConfig),
+    }
+
+    /// The serialized fields of [Config], which may still hold the trusted keys of
+    /// older versions next to the caches.
+    #[derive(Deserialize)]
+    struct ConfigData {
+        path: PathBuf,
+        #[serde(default)]
+        caches: Vec<CacheEntry>,
+        #[serde(default)]
+        backend: BackendKind,
+        #[serde(default)]
+        trusted_keys: BTreeMap<Url, BTreeSet<PublicKey>>,
+        #[serde(default)]
+        gc: GcPolicy,
+    }
+
+    impl TryFrom<ConfigData> for Config {
+        type Error = String;
+
+        fn try_from(mut data: ConfigData) -> Result<Self, String> {
+            let caches = data
+                .caches
+                .into_iter()
+                .map(|entry| match entry {
+                    CacheEntry::Url(url) => CacheConfig::new(url),
+                    CacheEntry::Config(cache) => cache,
+                })
+                .map(|cache| {
+                    let keys = data.trusted_keys.remove(&cache.url).unwrap_or_default();
+                    cache.with_trusted_keys(keys)
+                })
+                .collect::<Vec<_>>();
+
+            if let Some(cache) = caches
+                .iter()
+                .find(|cache| !(MIN_PRIORITY..=MAX_PRIORITY).contains(&cache.priority))
+            {
+                return Err(format!(
+                    "The priority of cache {} has to be between {MIN_PRIORITY} and {MAX_PRIORITY}",
+                    cache.url
+                ));
+            }
+
+            Ok(Self {
+                path: data.path,
+                backend: data.backend,
+                caches,
+                gc: data.gc,
+            })
+        }
+    }
 
     #[derive(Debug, Deserialize, Serialize, Clone, Default)]
+    #[serde(try_from = "ConfigData")]
     pub struct Config {
         path: PathBuf,
-        caches: Vec<Url>,
+        /// The database format of the store.
+        /// Plain values have to come before the tables, otherwise toml fails to serialize them.
+        #[serde(default)]
+        backend: BackendKind,
+        caches: Vec<CacheConfig>,
+        /// The policy of the garbage collection.
+        #[serde(default)]
+        gc: GcPolicy,
     }
 
     impl Config {
-        pub fn init<P: AsRef<Path>>(path: P, caches: Vec<Url>) -> Result<Self, Box<dyn Error>> {
+        pub fn init<P: AsRef<Path>>(
+            path: P,
+            caches: Vec<CacheConfig>,
+            backend: BackendKind,
+        ) -> Result<Self, Box<dyn Error>> {
             let config = Self {
                 caches,
                 path: path.as_ref().to_owned(),
+                backend,
+                gc: GcPolicy::default(),
             };
             let bytes = toml::to_vec(&config)?;
             fs::write(&config.path, bytes)?;
@@ -78,12 +251,53 @@ pub mod config {
             Ok(config)
         }
 
-        pub fn add_cache(&mut self, cache: Url) {
-            self.caches.push(cache);
+        /// Adds the cache, returns false if its url is already a cache.
+        pub fn add_cache(&mut self, cache: CacheConfig) -> bool {
+            if self.get_cache(&cache.url).is_some() {
+                false
+            } else {
+                self.caches.push(cache);
+                true
+            }
+        }
+
+        /// Removes the cache with the url together with its settings.
+        pub fn remove_cache(&mut self, url: &Url) -> Option<CacheConfig> {
+            let index = self.caches.iter().position(|cache| &cache.url == url)?;
+            Some(self.caches.remove(index))
+        }
+
+        pub fn get_cache(&self, url: &Url) -> Option<&CacheConfig> {
+            self.caches.iter().find(|cache| &cache.url == url)
+        }
+
+        pub fn get_cache_mut(&mut self, url: &Url) -> Option<&mut CacheConfig> {
+            self.caches.iter_mut().find(|cache| &cache.url == url)
+        }
+
+        /// Trusts signatures of the key for the cache.
+        /// Returns false if the key was already trusted, or `None` if the url is not a cache.
+        pub fn trust_key(&mut self, cache: &Url, key: PublicKey) -> Option<bool> {
+            self.get_cache_mut(cache)
+                .map(|cache| cache.trusted_keys.insert(key))
+        }
+
+        pub fn trusted_keys(&self, cache: &Url) -> impl Iterator<Item = &PublicKey> {
+            self.get_cache(cache)
+                .into_iter()
+                .flat_map(|cache| cache.trusted_keys.iter())
+        }
+
+        pub fn backend(&self) -> BackendKind {
+            self.backend
+        }
+
+        pub fn set_backend(&mut self, backend: BackendKind) {
+            self.backend = backend;
         }
 
-        pub fn remove_cache(&mut self, index: usize) -> Url {
-            self.caches.remove(index)
+        pub fn gc_policy(&self) -> GcPolicy {
+            self.gc
         }
 
         pub fn flush(&self) -> Result<(), Box<dyn Error>> {
@@ -94,12 +308,105 @@ pub mod config {
             Ok(())
         }
 
-        pub fn caches(&self) -> &Vec<Url> {
+        pub fn caches(&self) -> &Vec<CacheConfig> {
             &self.caches
         }
 
-        pub fn to_caches(self) -> Vec<Url> {
+        pub fn to_caches(self) -> Vec<CacheConfig> {
             self.caches
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::store::signature::SecretKey;
+        use temp_dir::TempDir;
+
+        #[test]
+        fn config_round_trip() {
+            let temp_dir = TempDir::new().unwrap();
+            let path = temp_dir.child("config.toml");
+            let url = Url::parse("https://cache.example/").unwrap();
+            let key = SecretKey::generate("cache-1").unwrap().public_key();
+            let cache = CacheConfig::new(url.clone()).with_priority(8);
+
+            let mut config = Config::init(&path, vec![cache], BackendKind::Sqlite).unwrap();
+            assert_eq!(config.trust_key(&url, key.clone()), Some(true));
+            config.flush().unwrap();
+
+            let config = Config::open(&path).unwrap();
+            assert_eq!(config.caches().len(), 1);
+            assert_eq!(config.get_cache(&url).unwrap().priority, 8);
+            assert_eq!(config.backend(), BackendKind::Sqlite);
+            assert_eq!(config.trusted_keys(&url).collect::<Vec<_>>(), [&key]);
+        }
+
+        #[test]
+        fn config_caches() {
+            let key = SecretKey::generate("cache-1").unwrap().public_key();
+            let old = format!(
+                r#"
+                path = "/hua/config.toml"
+                caches = ["https://one.example/", "https://two.example/"]
+
+                [trusted_keys]
+                "https://two.example/" = ["{key}"]
+                "#
+            );
+            let mut config = toml::from_str::<Config>(&old).unwrap();
+            let one = Url::parse("https://one.example/").unwrap();
+            let two = Url::parse("https://two.example/").unwrap();
+
+            assert_eq!(config.caches().len(), 2);
+            assert_eq!(config.get_cache(&one), Some(&CacheConfig::new(one.clone())));
+            assert_eq!(config.trusted_keys(&two).collect::<Vec<_>>(), [&key]);
+
+            assert!(!config.add_cache(CacheConfig::new(one.clone())));
+            let three = Url::parse("https://three.example/").unwrap();
+            assert!(config.add_cache(CacheConfig::new(three.clone()).with_priority(9)));
+            assert_eq!(config.trust_key(&three, key.clone()), Some(true));
+            assert_eq!(config.trust_key(&three, key.clone()), Some(false));
+            assert_eq!(
+                config.trust_key(&Url::parse("https://other.example/").unwrap(), key),
+                None
+            );
+            config.get_cache_mut(&two).unwrap().enabled = false;
+            config.get_cache_mut(&two).unwrap().timeout = Some(Duration::from_secs(30));
+
+            let new = toml::to_string(&config).unwrap();
+            assert!(new.contains("timeout = 30"));
+            let reopened = toml::from_str::<Config>(&new).unwrap();
+            assert_eq!(reopened.caches(), config.caches());
+            assert_eq!(reopened.get_cache(&three).unwrap().priority, 9);
+
+            assert_eq!(
+                CacheConfig::parse_url("https://cache.example/hua").unwrap(),
+                Url::parse("https://cache.example/hua/").unwrap()
+            );
+            assert_eq!(
+                CacheConfig::parse_url("/mnt/usb/store").unwrap(),
+                Url::parse("file:///mnt/usb/store/").unwrap()
+            );
+            assert!(CacheConfig::parse_url("relative/store").is_err());
+
+            assert_eq!(config.remove_cache(&one).unwrap().url, one);
+            assert!(config.remove_cache(&one).is_none());
+            assert_eq!(config.caches().len(), 2);
+
+            for priority in [0, 11] {
+                let invalid = format!(
+                    r#"
+                    path = "/hua/config.toml"
+
+                    [[caches]]
+                    url = "https://one.example/"
+                    priority = {priority}
+                    "#
+                );
+                let err = toml::from_str::<Config>(&invalid).unwrap_err();
+                assert!(err.to_string().contains("priority"), "{err}");
+            }
+        }
+    }
 }
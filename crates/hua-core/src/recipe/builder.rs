@@ -2,7 +2,7 @@ use super::*;
 use crate::{
     dependency::Requirement,
     recipe::Recipe,
-    store::{id::PackageId, LocalStore},
+    store::{id::PackageId, report::PackageReport, LocalStore},
 };
 use cached_path::Cache;
 use console::style;
@@ -12,6 +12,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
     fmt,
+    hash::{Hash, Hasher},
     path::{Path, PathBuf},
 };
 
@@ -71,6 +72,15 @@ impl Derivation {
         RelativePathBuf::from(format!("{}-{}-{}", self.name, self.version, id))
     }
 
+    /// Returns true if the name and the version are each a single component of a path,
+    /// so [Derivation::relative_path] stays in the directory of the store.
+    pub fn has_valid_path(&self) -> bool {
+        let is_component = |value: &str| {
+            !value.is_empty() && value != "." && value != ".." && !value.contains(['/', '\0'])
+        };
+        is_component(&self.name) && is_component(&self.version.to_string())
+    }
+
     // pub fn url_in_store(&self, store_url: &Url, id: &PackageId) -> Url {
     //     let name_version_id = format!("{}-{}-{}", self.name, self.version, id);
     //     store_url
@@ -79,6 +89,23 @@ impl Derivation {
     // }
 }
 
+// The requirements are left out, as HashSet does not implement Hash.
+// Derivations which only differ in their requirements are still told apart by Eq.
+impl Hash for Derivation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.version.hash(state);
+        self.desc.hash(state);
+        self.archs.hash(state);
+        self.platforms.hash(state);
+        self.source.hash(state);
+        self.licenses.hash(state);
+        self.vars.hash(state);
+        self.script.hash(state);
+        self.target_dir.hash(state);
+    }
+}
+
 impl fmt::Display for Derivation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Derivation {}\n", style(&self.name).blue())?;
@@ -120,3 +147,18 @@ pub fn build_recipe(
         .build()?
         .install(store)
 }
+
+pub fn repair_recipe(
+    drv: Derivation,
+    package_id: &PackageId,
+    store: &mut LocalStore,
+    cache: &Cache,
+) -> RecipeResult<PackageReport> {
+    let recipe = Recipe::new(drv);
+
+    recipe
+        .fetch(&cache)?
+        .prepare_requirements(&store)?
+        .build()?
+        .repair(store, package_id)
+}
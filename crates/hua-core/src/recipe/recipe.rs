@@ -2,7 +2,10 @@ use super::*;
 use crate::{
     jail::{Bind, JailBuilder},
     shell::ShellBuilder,
-    store::{backend::ReadBackend, package::LocalPackageSource, LocalStore, Store},
+    store::{
+        backend::ReadBackend, id::PackageId, package::LocalPackageSource, report::PackageReport,
+        LocalStore, Store,
+    },
 };
 use cached_path::{Cache, Options};
 use fs_extra::dir::CopyOptions;
@@ -189,10 +192,29 @@ impl Recipe {
         debug!("Calculated link path at {link:?}");
 
         unix::fs::symlink(&path, &link).context(IoSnafu)?;
+        store.gc_roots().add_link(&link).context(StoreSnafu)?;
 
         info!("Created result link");
         Ok(link)
     }
+
+    /// Repairs the already stored package of the recipe with the built files.
+    pub fn repair(
+        self,
+        store: &mut LocalStore,
+        package_id: &PackageId,
+    ) -> RecipeResult<PackageReport> {
+        let absolute_target_dir = self
+            .absolute_target_dir
+            .ok_or(RecipeError::MissingTargetDir)?;
+
+        let report = store
+            .repair(package_id, absolute_target_dir)
+            .context(StoreSnafu)?;
+
+        info!("Package repaired in store {package_id}");
+        Ok(report)
+    }
 }
 
 #[cfg(test)]
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, process};
 
 use crate::{
     dependency::Requirement,
@@ -15,6 +15,8 @@ use temp_dir::TempDir;
 pub enum ShellError {
     #[snafu(display("IoError: {source}"))]
     IoError { source: std::io::Error },
+    #[snafu(display("StoreError: {source}"))]
+    StoreError { source: crate::store::StoreError },
     #[snafu(display("GenerationError: {source}"))]
     GenerationError {
         source: crate::generation::GenerationError,
@@ -54,6 +56,21 @@ impl ShellBuilder {
             .build(&store)
             .context(GenerationSnafu)?;
 
+        // The packages of the shell are kept by the garbage collection until it exits
+        let roots = store.gc_roots();
+        for id in generation.packages() {
+            let path =
+                store
+                    .packages()
+                    .path_in_store(id, store.path())
+                    .ok_or(ShellError::NotInStore {
+                        name: id.to_string(),
+                    })?;
+            roots
+                .add_process(process::id(), &path)
+                .context(StoreSnafu)?;
+        }
+
         self.generation = Some(generation);
         Ok(self)
     }
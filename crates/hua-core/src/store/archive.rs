@@ -0,0 +1,86 @@
+//! Archives to move packages between stores without a network connection.
+//!
+//! An archive is a tar file, optionally compressed with zstd. It starts with the
+//! [PACKAGES_DB] of the exported packages, followed by their directories laid out
+//! like in the store.
+
+use super::PACKAGES_DB;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use tar::{Archive, Builder, Header};
+
+/// The first bytes of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Returns the digest of the archive at the path, which is signed to authorize
+/// the upload of the archive to a cache.
+pub fn digest(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Writes the database and the package directories into a new archive at the path.
+/// The directories are given together with their path inside the archive.
+pub fn write<'a>(
+    path: &Path,
+    compress: bool,
+    database: &[u8],
+    dirs: impl IntoIterator<Item = (String, &'a Path)>,
+) -> io::Result<()> {
+    let file = File::create(path)?;
+
+    if compress {
+        let encoder = zstd::Encoder::new(file, 0)?;
+        let encoder = write_to(encoder, database, dirs)?;
+        encoder.finish()?.flush()
+    } else {
+        write_to(file, database, dirs)?.flush()
+    }
+}
+
+fn write_to<'a, W: Write>(
+    writer: W,
+    database: &[u8],
+    dirs: impl IntoIterator<Item = (String, &'a Path)>,
+) -> io::Result<W> {
+    let mut builder = Builder::new(writer);
+    // Links are part of the packages and must not be resolved
+    builder.follow_symlinks(false);
+
+    let mut header = Header::new_gnu();
+    header.set_size(database.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, PACKAGES_DB, database)?;
+
+    for (name, dir) in dirs {
+        builder.append_dir_all(name, dir)?;
+    }
+
+    builder.into_inner()
+}
+
+/// Unpacks an archive, compressed or not, into the destination.
+pub fn unpack(path: &Path, dest: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+
+    let mut magic = [0; 4];
+    let compressed = file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+
+    if compressed {
+        unpack_from(zstd::Decoder::new(file)?, dest)
+    } else {
+        unpack_from(file, dest)
+    }
+}
+
+fn unpack_from<R: Read>(reader: R, dest: &Path) -> io::Result<()> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.unpack(dest)
+}
@@ -1,42 +1,130 @@
-use super::{object::Objects, package::Packages, *};
-use crate::{extra::persist::Pot, GID, UID};
-use rustbreak::PathDatabase;
-use snafu::ResultExt;
-use std::{
-    fs,
-    os::unix::{self, prelude::PermissionsExt},
-    path::PathBuf,
+use super::{
+    database_path, lock, object::Objects, package::Packages, BackendKind, RustbreakBackend,
+    SqliteBackend, *,
 };
+use crate::extra::lock::LockMode;
+use snafu::ResultExt;
+use std::{fs, path::PathBuf, time::Duration};
+
+/// The hidden file a sqlite database is written to while a store is converted
+const SQLITE_STAGING: &str = ".packages.sqlite.tmp";
 
+/// The backend of a local store, which is either of the [BackendKind]s.
+/// Its source is the path of the [PACKAGES_DB], the other databases are looked up next to it.
 #[derive(Debug)]
-pub struct LocalBackend {
-    path: PathBuf,
-    db: PathDatabase<(Objects, Packages), Pot>,
-    objects: Objects,
-    packages: Packages,
+pub enum LocalBackend {
+    Rustbreak(RustbreakBackend),
+    Sqlite(SqliteBackend),
+}
+
+impl LocalBackend {
+    /// Opens the database found next to the path, see [BackendKind::detect],
+    /// while holding a lock of the given mode.
+    /// Waits for other processes to release their lock until the timeout elapsed.
+    pub fn open_with(
+        path: PathBuf,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> StoreResult<Self> {
+        match BackendKind::detect(&path) {
+            BackendKind::Rustbreak => {
+                RustbreakBackend::open_with(path, mode, timeout).map(Self::Rustbreak)
+            }
+            BackendKind::Sqlite => {
+                let path = database_path(&path, BackendKind::Sqlite);
+                SqliteBackend::open_with(path, mode, timeout).map(Self::Sqlite)
+            }
+        }
+    }
+
+    /// Creates a new database of the given kind next to the path.
+    pub fn init_with(path: PathBuf, kind: BackendKind) -> StoreResult<Self> {
+        let lock = lock(&path, LockMode::Exclusive, None)?;
+        let path = database_path(&path, kind);
+
+        match kind {
+            BackendKind::Rustbreak => {
+                RustbreakBackend::create(path, Objects::new(), Packages::new(), lock)
+                    .map(Self::Rustbreak)
+            }
+            BackendKind::Sqlite => {
+                SqliteBackend::create(path, Objects::new(), Packages::new(), lock).map(Self::Sqlite)
+            }
+        }
+    }
+
+    pub fn kind(&self) -> BackendKind {
+        match self {
+            Self::Rustbreak(_) => BackendKind::Rustbreak,
+            Self::Sqlite(_) => BackendKind::Sqlite,
+        }
+    }
+
+    /// Writes the contents into a new database of the given kind next to the path
+    /// and removes the current database afterwards.
+    /// The lock is kept during the conversion, so the backend must be opened exclusively.
+    pub fn convert(self, path: PathBuf, kind: BackendKind) -> StoreResult<Self> {
+        if self.kind() == kind {
+            return Ok(self);
+        }
+
+        let old = database_path(&path, self.kind());
+        let (objects, packages, lock) = match self {
+            Self::Rustbreak(backend) => backend.into_parts(),
+            Self::Sqlite(backend) => backend.into_parts(),
+        };
+        if !lock.is_exclusive() {
+            return Err(StoreError::ReadOnly { path });
+        }
+
+        // The sqlite database takes precedence when the store is opened, so it is
+        // only moved into place once it is complete.
+        let backend = match kind {
+            BackendKind::Rustbreak => {
+                let new = database_path(&path, kind);
+                if new.exists() {
+                    fs::remove_file(&new).context(IoSnafu)?;
+                }
+                RustbreakBackend::create(new, objects, packages, lock).map(Self::Rustbreak)?
+            }
+            BackendKind::Sqlite => {
+                let staging = path.with_file_name(SQLITE_STAGING);
+                let new = database_path(&path, kind);
+                if staging.exists() {
+                    fs::remove_file(&staging).context(IoSnafu)?;
+                }
+
+                let (_, _, lock) =
+                    SqliteBackend::create(staging.clone(), objects, packages, lock)?.into_parts();
+                fs::rename(staging, &new).context(IoSnafu)?;
+                SqliteBackend::open_locked(new, lock).map(Self::Sqlite)?
+            }
+        };
+        fs::remove_file(old).context(IoSnafu)?;
+
+        Ok(backend)
+    }
 }
 
 impl ReadBackend for LocalBackend {
     type Source = PathBuf;
 
     fn open(path: PathBuf) -> StoreResult<Self> {
-        let db = PathDatabase::load_from_path(path.clone()).context(RustbreakLoadSnafu)?;
-        let (objects, packages) = db.get_data(false).context(RustbreakLoadDataSnafu)?;
-
-        Ok(Self {
-            path,
-            db,
-            objects,
-            packages,
-        })
+        Self::open_with(path, LockMode::Exclusive, None)
     }
 
     fn objects(&self) -> &Objects {
-        &self.objects
+        match self {
+            Self::Rustbreak(backend) => backend.objects(),
+            Self::Sqlite(backend) => backend.objects(),
+        }
     }
 
     fn packages(&self) -> &Packages {
-        &self.packages
+        match self {
+            Self::Rustbreak(backend) => backend.packages(),
+            Self::Sqlite(backend) => backend.packages(),
+        }
     }
 }
 
@@ -44,36 +132,27 @@ impl WriteBackend for LocalBackend {
     type Source = PathBuf;
 
     fn init(path: PathBuf) -> StoreResult<Self> {
-        let db = PathDatabase::create_at_path(path.clone(), (Objects::new(), Packages::new()))
-            .context(RustbreakCreateSnafu)?;
-
-        let mut perm = fs::metadata(&path).context(IoSnafu)?.permissions();
-        perm.set_mode(0o644);
-        fs::set_permissions(&path, perm).context(IoSnafu)?;
-        unix::fs::chown(&path, UID, GID).context(IoSnafu)?;
-
-        Ok(Self {
-            path,
-            db,
-            objects: Objects::new(),
-            packages: Packages::new(),
-        })
+        Self::init_with(path, BackendKind::default())
     }
 
     fn objects_mut(&mut self) -> &mut Objects {
-        &mut self.objects
+        match self {
+            Self::Rustbreak(backend) => backend.objects_mut(),
+            Self::Sqlite(backend) => backend.objects_mut(),
+        }
     }
 
     fn packages_mut(&mut self) -> &mut Packages {
-        &mut self.packages
+        match self {
+            Self::Rustbreak(backend) => backend.packages_mut(),
+            Self::Sqlite(backend) => backend.packages_mut(),
+        }
     }
 
     fn flush(self) -> StoreResult<()> {
-        self.db
-            .put_data((self.objects, self.packages), true)
-            .context(RustbreakSaveDataSnafu)?;
-        unix::fs::chown(self.path, UID, GID).context(IoSnafu)?;
-
-        Ok(())
+        match self {
+            Self::Rustbreak(backend) => backend.flush(),
+            Self::Sqlite(backend) => backend.flush(),
+        }
     }
 }
@@ -1,12 +1,29 @@
 use super::{object::Objects, package::Packages, *};
+use crate::extra::lock::{FileLock, LockMode};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 pub use local::LocalBackend;
 pub use memory::MemoryBackend;
 pub use remote::RemoteBackend;
+pub use rustbreak::RustbreakBackend;
+pub use sqlite::SqliteBackend;
 
 mod local;
 mod memory;
 mod remote;
+mod rustbreak;
+mod sqlite;
+
+/// The filename of the sqlite database of the store
+pub const PACKAGES_SQLITE: &str = "packages.sqlite";
+/// The hidden file locked by every process which opens the store
+const STORE_LOCK: &str = ".packages.db.lock";
 
 pub trait ReadBackend: Sized + std::fmt::Debug {
     type Source;
@@ -24,3 +41,74 @@ pub trait WriteBackend: Sized + std::fmt::Debug {
     fn packages_mut(&mut self) -> &mut Packages;
     fn flush(self) -> StoreResult<()>;
 }
+
+/// The database format of a local store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// A single [PACKAGES_DB] which is rewritten on every change.
+    #[default]
+    Rustbreak,
+    /// A [PACKAGES_SQLITE] database with indexed tables.
+    Sqlite,
+}
+
+impl BackendKind {
+    /// Returns the kind of the database in the directory of the given database path.
+    /// A sqlite database takes precedence, as it is only created by [LocalBackend::init_with]
+    /// or a conversion.
+    pub fn detect(path: &Path) -> Self {
+        if path.with_file_name(PACKAGES_SQLITE).exists() {
+            Self::Sqlite
+        } else {
+            Self::Rustbreak
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Rustbreak => PACKAGES_DB,
+            Self::Sqlite => PACKAGES_SQLITE,
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rustbreak => f.write_str("rustbreak"),
+            Self::Sqlite => f.write_str("sqlite"),
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> StoreResult<Self> {
+        match s {
+            "rustbreak" => Ok(Self::Rustbreak),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(StoreError::UnknownBackend { name: s.to_owned() }),
+        }
+    }
+}
+
+/// Locks the store of the database at the path through a hidden lock file next to it.
+/// All backends share the same lock file, so that a store can be converted safely.
+fn lock(path: &Path, mode: LockMode, timeout: Option<Duration>) -> StoreResult<FileLock> {
+    FileLock::acquire(&path.with_file_name(STORE_LOCK), mode, timeout).map_err(|source| {
+        if source.kind() == io::ErrorKind::WouldBlock {
+            StoreError::Busy {
+                path: path.to_owned(),
+            }
+        } else {
+            StoreError::IoError { source }
+        }
+    })
+}
+
+/// Returns the path of the database of the given kind next to the database at the path.
+fn database_path(path: &Path, kind: BackendKind) -> PathBuf {
+    path.with_file_name(kind.file_name())
+}
@@ -1,11 +1,12 @@
-use cached_path::CacheBuilder;
-use rustbreak::PathDatabase;
+use cached_path::{Cache, CacheBuilder};
+use log::debug;
 use snafu::ResultExt;
+use std::{fs, path::PathBuf};
 use url::Url;
 
 use super::{object::Objects, package::Packages, *};
 
-use crate::extra::persist::Pot;
+use crate::store::signature::{self, PublicKey, Signature, PACKAGES_DB_SIG};
 
 use super::ReadBackend;
 
@@ -15,20 +16,68 @@ pub struct RemoteBackend {
     packages: Packages,
 }
 
-impl ReadBackend for RemoteBackend {
-    type Source = Url;
-
-    fn open(source: Self::Source) -> crate::store::StoreResult<Self> {
+impl RemoteBackend {
+    /// Downloads the packages database and checks its signature against the trusted keys.
+    pub fn open_trusted(source: Url, trusted: &[PublicKey]) -> StoreResult<Self> {
         let cache = CacheBuilder::default().build().context(CacheSnafu)?;
+        Self::open_cached(source, trusted, &cache)
+    }
 
-        let path = cache.cached_path(source.as_str()).context(CacheSnafu)?;
+    /// Like [RemoteBackend::open_trusted], but downloads through the given cache.
+    pub fn open_cached(source: Url, trusted: &[PublicKey], cache: &Cache) -> StoreResult<Self> {
+        let path = fetch(cache, &source)?;
+        let data = fs::read(path).context(IoSnafu)?;
 
-        let db = PathDatabase::<(Objects, Packages), Pot>::load_from_path(path)
-            .context(RustbreakLoadSnafu)?;
-        let (objects, packages) = db.get_data(false).context(RustbreakLoadDataSnafu)?;
+        let signature = if trusted.is_empty() {
+            None
+        } else {
+            fetch_signature(cache, &source)?
+        };
+        signature::check(&source, &data, signature.as_ref(), trusted)?;
+
+        let (objects, packages) = pot::from_slice(&data).context(PotSnafu)?;
 
         Ok(Self { objects, packages })
     }
+}
+
+/// Returns the local path of the file at the url.
+/// Files of `file://` caches are read in place, others are downloaded into the cache.
+fn fetch(cache: &Cache, url: &Url) -> StoreResult<PathBuf> {
+    if url.scheme() == "file" {
+        url.to_file_path()
+            .map_err(|_| StoreError::InvalidCachePath { url: url.clone() })
+    } else {
+        cache.cached_path(url.as_str()).context(CacheSnafu)
+    }
+}
+
+/// Returns the signature published next to the packages database, if there is one.
+fn fetch_signature(cache: &Cache, source: &Url) -> StoreResult<Option<Signature>> {
+    let url = source.join(PACKAGES_DB_SIG).context(UrlParseSnafu)?;
+
+    let path = match fetch(cache, &url) {
+        Ok(path) if path.exists() => path,
+        Ok(path) => {
+            debug!("No signature at {path:?}");
+            return Ok(None);
+        }
+        Err(e) => {
+            debug!("No signature at {url}: {e}");
+            return Ok(None);
+        }
+    };
+    let signature = fs::read_to_string(path).context(IoSnafu)?.parse()?;
+
+    Ok(Some(signature))
+}
+
+impl ReadBackend for RemoteBackend {
+    type Source = Url;
+
+    fn open(source: Self::Source) -> crate::store::StoreResult<Self> {
+        Self::open_trusted(source, &[])
+    }
 
     fn packages(&self) -> &Packages {
         &self.packages
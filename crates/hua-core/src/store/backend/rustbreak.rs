@@ -0,0 +1,123 @@
+use super::{lock, object::Objects, package::Packages, *};
+use crate::{
+    extra::{
+        lock::{FileLock, LockMode},
+        persist::Pot,
+    },
+    GID, UID,
+};
+use ::rustbreak::PathDatabase;
+use snafu::ResultExt;
+use std::{
+    fs,
+    os::unix::{self, prelude::PermissionsExt},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Keeps the whole store in a single [PACKAGES_DB] which is rewritten on every flush.
+#[derive(Debug)]
+pub struct RustbreakBackend {
+    path: PathBuf,
+    db: PathDatabase<(Objects, Packages), Pot>,
+    objects: Objects,
+    packages: Packages,
+    lock: FileLock,
+}
+
+impl RustbreakBackend {
+    /// Opens the database while holding a lock of the given mode.
+    /// Waits for other processes to release their lock until the timeout elapsed.
+    pub fn open_with(
+        path: PathBuf,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> StoreResult<Self> {
+        let lock = lock(&path, mode, timeout)?;
+
+        let db = PathDatabase::load_from_path(path.clone()).context(RustbreakLoadSnafu)?;
+        let (objects, packages) = db.get_data(false).context(RustbreakLoadDataSnafu)?;
+
+        Ok(Self {
+            path,
+            db,
+            objects,
+            packages,
+            lock,
+        })
+    }
+
+    /// Creates the database with the given contents under an already acquired lock.
+    pub(super) fn create(
+        path: PathBuf,
+        objects: Objects,
+        packages: Packages,
+        lock: FileLock,
+    ) -> StoreResult<Self> {
+        let db = PathDatabase::create_at_path(path.clone(), (objects.clone(), packages.clone()))
+            .context(RustbreakCreateSnafu)?;
+
+        let mut perm = fs::metadata(&path).context(IoSnafu)?.permissions();
+        perm.set_mode(0o644);
+        fs::set_permissions(&path, perm).context(IoSnafu)?;
+        unix::fs::chown(&path, UID, GID).context(IoSnafu)?;
+
+        Ok(Self {
+            path,
+            db,
+            objects,
+            packages,
+            lock,
+        })
+    }
+
+    pub(super) fn into_parts(self) -> (Objects, Packages, FileLock) {
+        (self.objects, self.packages, self.lock)
+    }
+}
+
+impl ReadBackend for RustbreakBackend {
+    type Source = PathBuf;
+
+    fn open(path: PathBuf) -> StoreResult<Self> {
+        Self::open_with(path, LockMode::Exclusive, None)
+    }
+
+    fn objects(&self) -> &Objects {
+        &self.objects
+    }
+
+    fn packages(&self) -> &Packages {
+        &self.packages
+    }
+}
+
+impl WriteBackend for RustbreakBackend {
+    type Source = PathBuf;
+
+    fn init(path: PathBuf) -> StoreResult<Self> {
+        let lock = lock(&path, LockMode::Exclusive, None)?;
+        Self::create(path, Objects::new(), Packages::new(), lock)
+    }
+
+    fn objects_mut(&mut self) -> &mut Objects {
+        &mut self.objects
+    }
+
+    fn packages_mut(&mut self) -> &mut Packages {
+        &mut self.packages
+    }
+
+    fn flush(self) -> StoreResult<()> {
+        if !self.lock.is_exclusive() {
+            return Err(StoreError::ReadOnly { path: self.path });
+        }
+
+        self.db
+            .put_data((self.objects, self.packages), true)
+            .context(RustbreakSaveDataSnafu)?;
+        unix::fs::chown(self.path, UID, GID).context(IoSnafu)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use super::{lock, object::Objects, package::Packages, *};
+use crate::{
+    extra::lock::{FileLock, LockMode},
+    recipe::Derivation,
+    GID, UID,
+};
+use rusqlite::{params, types::Type, Connection, Row, Transaction};
+use snafu::ResultExt;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::{self, prelude::PermissionsExt},
+    path::PathBuf,
+    time::Duration,
+};
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS packages (
+        id BLOB PRIMARY KEY,
+        name TEXT NOT NULL,
+        version TEXT NOT NULL,
+        drv BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS packages_name ON packages (name);
+    CREATE TABLE IF NOT EXISTS objects (
+        id BLOB PRIMARY KEY,
+        object BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS membership (
+        package BLOB NOT NULL REFERENCES packages (id) ON DELETE CASCADE,
+        object BLOB NOT NULL,
+        PRIMARY KEY (package, object)
+    );
+    CREATE INDEX IF NOT EXISTS membership_object ON membership (object);
+    CREATE TABLE IF NOT EXISTS usage (
+        package BLOB PRIMARY KEY REFERENCES packages (id) ON DELETE CASCADE,
+        time INTEGER NOT NULL
+    );
+";
+
+/// Keeps the store in a [PACKAGES_SQLITE] database with indexed tables for the packages,
+/// the objects and the objects of every package.
+/// All rows are read into memory when the database is opened, only the rows of the objects
+/// and packages changed since then are written on flush.
+#[derive(Debug)]
+pub struct SqliteBackend {
+    path: PathBuf,
+    conn: Connection,
+    objects: Objects,
+    packages: Packages,
+    lock: FileLock,
+}
+
+/// Reads the id in the column of the row.
+fn get_id<T: TryFrom<Vec<u8>>>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let bytes = row.get::<_, Vec<u8>>(idx)?;
+    T::try_from(bytes).map_err(|_| {
+        rusqlite::Error::FromSqlConversionFailure(idx, Type::Blob, "Invalid id".into())
+    })
+}
+
+fn load(conn: &Connection) -> StoreResult<(Objects, Packages)> {
+    let mut objects = Objects::new();
+    let mut stmt = conn
+        .prepare("SELECT id, object FROM objects")
+        .context(SqliteSnafu)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((get_id::<ObjectId>(row, 0)?, row.get::<_, Vec<u8>>(1)?))
+        })
+        .context(SqliteSnafu)?;
+    for row in rows {
+        let (id, object) = row.context(SqliteSnafu)?;
+        objects.insert(id, pot::from_slice(&object).context(PotSnafu)?);
+    }
+
+    let mut children = HashMap::<PackageId, HashSet<ObjectId>>::new();
+    let mut stmt = conn
+        .prepare("SELECT package, object FROM membership")
+        .context(SqliteSnafu)?;
+    let rows = stmt
+        .query_map([], |row| Ok((get_id(row, 0)?, get_id(row, 1)?)))
+        .context(SqliteSnafu)?;
+    for row in rows {
+        let (package, object) = row.context(SqliteSnafu)?;
+        children.entry(package).or_default().insert(object);
+    }
+
+    let mut packages = Packages::new();
+    let version = conn
+        .query_row(
+            "SELECT value FROM meta WHERE key = 'hash_version'",
+            [],
+            |row| row.get(0),
+        )
+        .context(SqliteSnafu)?;
+    packages.set_hash_version(version);
+
+    let mut stmt = conn
+        .prepare("SELECT id, drv FROM packages")
+        .context(SqliteSnafu)?;
+    let rows = stmt
+        .query_map([], |row| Ok((get_id(row, 0)?, row.get::<_, Vec<u8>>(1)?)))
+        .context(SqliteSnafu)?;
+    for row in rows {
+        let (id, drv) = row.context(SqliteSnafu)?;
+        let drv = pot::from_slice::<Derivation>(&drv).context(PotSnafu)?;
+        packages.insert(id, drv, children.remove(&id).unwrap_or_default());
+    }
+
+    let mut stmt = conn
+        .prepare("SELECT package, time FROM usage")
+        .context(SqliteSnafu)?;
+    let rows = stmt
+        .query_map([], |row| Ok((get_id(row, 0)?, row.get::<_, u64>(1)?)))
+        .context(SqliteSnafu)?;
+    for row in rows {
+        let (id, time) = row.context(SqliteSnafu)?;
+        packages.set_last_used(&id, time);
+    }
+
+    Ok((objects, packages))
+}
+
+/// Writes the rows of the given objects and packages, the rows of ids which are no longer
+/// in the store are deleted. Packages are deleted and inserted again together with their
+/// objects and usage time.
+fn save(
+    tx: &Transaction,
+    objects: &Objects,
+    packages: &Packages,
+    object_ids: impl IntoIterator<Item = ObjectId>,
+    package_ids: impl IntoIterator<Item = PackageId>,
+) -> StoreResult<()> {
+    for id in object_ids {
+        if let Some(object) = objects.get(&id) {
+            let object = pot::to_vec(object).context(PotSnafu)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO objects (id, object) VALUES (?1, ?2)",
+                params![id.as_bytes(), object],
+            )
+            .context(SqliteSnafu)?;
+        } else {
+            tx.execute("DELETE FROM objects WHERE id = ?1", [id.as_bytes()])
+                .context(SqliteSnafu)?;
+        }
+    }
+
+    for id in package_ids {
+        // The objects and usage of the package are deleted together with its row
+        tx.execute("DELETE FROM packages WHERE id = ?1", [id.as_bytes()])
+            .context(SqliteSnafu)?;
+        let Some((drv, children)) = packages.get_full(&id) else {
+            continue;
+        };
+
+        let bytes = pot::to_vec(drv).context(PotSnafu)?;
+        tx.execute(
+            "INSERT INTO packages (id, name, version, drv) VALUES (?1, ?2, ?3, ?4)",
+            params![id.as_bytes(), drv.name, drv.version.to_string(), bytes],
+        )
+        .context(SqliteSnafu)?;
+
+        for object in children.iter() {
+            tx.execute(
+                "INSERT INTO membership (package, object) VALUES (?1, ?2)",
+                params![id.as_bytes(), object.as_bytes()],
+            )
+            .context(SqliteSnafu)?;
+        }
+
+        if let Some(time) = packages.last_used(&id) {
+            tx.execute(
+                "INSERT INTO usage (package, time) VALUES (?1, ?2)",
+                params![id.as_bytes(), time],
+            )
+            .context(SqliteSnafu)?;
+        }
+    }
+
+    tx.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES ('hash_version', ?1)",
+        [packages.hash_version()],
+    )
+    .context(SqliteSnafu)?;
+
+    Ok(())
+}
+
+impl SqliteBackend {
+    /// Opens the database while holding a lock of the given mode.
+    /// Waits for other processes to release their lock until the timeout elapsed.
+    pub fn open_with(
+        path: PathBuf,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> StoreResult<Self> {
+        let lock = lock(&path, mode, timeout)?;
+        Self::open_locked(path, lock)
+    }
+
+    /// Opens the database under an already acquired lock.
+    pub(super) fn open_locked(path: PathBuf, lock: FileLock) -> StoreResult<Self> {
+        if !path.exists() {
+            return Err(StoreError::NotExisting { path });
+        }
+        let conn = Connection::open(&path).context(SqliteSnafu)?;
+        conn.pragma_update(None, "foreign_keys", true)
+            .context(SqliteSnafu)?;
+        // Creates the tables missing in databases of older versions
+        conn.execute_batch(SCHEMA).context(SqliteSnafu)?;
+        let (mut objects, mut packages) = load(&conn)?;
+        // Only the changes after loading are written
+        objects.take_changed();
+        packages.take_changed();
+
+        Ok(Self {
+            path,
+            conn,
+            objects,
+            packages,
+            lock,
+        })
+    }
+
+    /// Creates the database with the given contents under an already acquired lock.
+    pub(super) fn create(
+        path: PathBuf,
+        mut objects: Objects,
+        mut packages: Packages,
+        lock: FileLock,
+    ) -> StoreResult<Self> {
+        let mut conn = Connection::open(&path).context(SqliteSnafu)?;
+        conn.execute_batch(SCHEMA).context(SqliteSnafu)?;
+
+        objects.take_changed();
+        packages.take_changed();
+        let object_ids = objects.iter().map(|(id, _object)| *id).collect::<Vec<_>>();
+        let package_ids = packages
+            .iter()
+            .map(|(id, _drv, _children)| *id)
+            .collect::<Vec<_>>();
+        let tx = conn.transaction().context(SqliteSnafu)?;
+        save(&tx, &objects, &packages, object_ids, package_ids)?;
+        tx.commit().context(SqliteSnafu)?;
+
+        let mut perm = fs::metadata(&path).context(IoSnafu)?.permissions();
+        perm.set_mode(0o644);
+        fs::set_permissions(&path, perm).context(IoSnafu)?;
+        unix::fs::chown(&path, UID, GID).context(IoSnafu)?;
+
+        Ok(Self {
+            path,
+            conn,
+            objects,
+            packages,
+            lock,
+        })
+    }
+
+    pub(super) fn into_parts(self) -> (Objects, Packages, FileLock) {
+        (self.objects, self.packages, self.lock)
+    }
+}
+
+impl ReadBackend for SqliteBackend {
+    type Source = PathBuf;
+
+    fn open(path: PathBuf) -> StoreResult<Self> {
+        Self::open_with(path, LockMode::Exclusive, None)
+    }
+
+    fn objects(&self) -> &Objects {
+        &self.objects
+    }
+
+    fn packages(&self) -> &Packages {
+        &self.packages
+    }
+}
+
+impl WriteBackend for SqliteBackend {
+    type Source = PathBuf;
+
+    fn init(path: PathBuf) -> StoreResult<Self> {
+        let lock = lock(&path, LockMode::Exclusive, None)?;
+        Self::create(path, Objects::new(), Packages::new(), lock)
+    }
+
+    fn objects_mut(&mut self) -> &mut Objects {
+        &mut self.objects
+    }
+
+    fn packages_mut(&mut self) -> &mut Packages {
+        &mut self.packages
+    }
+
+    fn flush(mut self) -> StoreResult<()> {
+        if !self.lock.is_exclusive() {
+            return Err(StoreError::ReadOnly { path: self.path });
+        }
+
+        let object_ids = self.objects.take_changed();
+        let package_ids = self.packages.take_changed();
+        let tx = self.conn.transaction().context(SqliteSnafu)?;
+        save(&tx, &self.objects, &self.packages, object_ids, package_ids)?;
+        tx.commit().context(SqliteSnafu)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use super::{id::PackageId, StoreError, StoreResult};
+use console::style;
+use indicatif::{HumanBytes, HumanDuration};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const AGE_UNITS: [(&str, u64); 5] = [
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("m", 60),
+    ("s", 1),
+];
+
+const SIZE_UNITS: [(&str, u64); 5] = [
+    ("T", 1 << 40),
+    ("G", 1 << 30),
+    ("M", 1 << 20),
+    ("K", 1 << 10),
+    ("B", 1),
+];
+
+/// Returns the current unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Splits a value like `7d` into its number and unit.
+fn split_unit(value: &str) -> Option<(u64, &str)> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number = value[..end].parse().ok()?;
+    Some((number, value[end..].trim()))
+}
+
+/// Formats the value with the largest unit it is a multiple of.
+fn format_unit(f: &mut fmt::Formatter<'_>, value: u64, units: &[(&str, u64)]) -> fmt::Result {
+    let (unit, factor) = units
+        .iter()
+        .find(|(_unit, factor)| value % factor == 0)
+        .unwrap_or(&units[units.len() - 1]);
+    write!(f, "{}{unit}", value / factor)
+}
+
+/// A duration given in weeks, days, hours, minutes or seconds, like `7d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Age(Duration);
+
+impl Age {
+    pub fn as_secs(&self) -> u64 {
+        self.0.as_secs()
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl From<Duration> for Age {
+    fn from(duration: Duration) -> Self {
+        Self(duration)
+    }
+}
+
+impl FromStr for Age {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> StoreResult<Self> {
+        split_unit(s)
+            .and_then(|(number, unit)| {
+                let (_unit, factor) = AGE_UNITS.iter().find(|(name, _)| *name == unit)?;
+                number.checked_mul(*factor)
+            })
+            .map(|secs| Self(Duration::from_secs(secs)))
+            .ok_or_else(|| StoreError::InvalidAge {
+                value: s.to_owned(),
+            })
+    }
+}
+
+impl fmt::Display for Age {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        format_unit(f, self.as_secs(), &AGE_UNITS)
+    }
+}
+
+impl TryFrom<String> for Age {
+    type Error = StoreError;
+
+    fn try_from(value: String) -> StoreResult<Self> {
+        value.parse()
+    }
+}
+
+impl From<Age> for String {
+    fn from(age: Age) -> Self {
+        age.to_string()
+    }
+}
+
+/// A size given in bytes or binary kilo, mega, giga or terabytes, like `10G`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Size(u64);
+
+impl Size {
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for Size {
+    fn from(bytes: u64) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for Size {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> StoreResult<Self> {
+        split_unit(s)
+            .and_then(|(number, unit)| {
+                let unit = unit.to_ascii_uppercase();
+                let unit = unit.trim_end_matches("IB").trim_end_matches('B');
+                let unit = if unit.is_empty() { "B" } else { unit };
+                let (_unit, factor) = SIZE_UNITS.iter().find(|(name, _)| *name == unit)?;
+                number.checked_mul(*factor)
+            })
+            .map(Self)
+            .ok_or_else(|| StoreError::InvalidSize {
+                value: s.to_owned(),
+            })
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        format_unit(f, self.0, &SIZE_UNITS)
+    }
+}
+
+impl TryFrom<String> for Size {
+    type Error = StoreError;
+
+    fn try_from(value: String) -> StoreResult<Self> {
+        value.parse()
+    }
+}
+
+impl From<Size> for String {
+    fn from(size: Size) -> Self {
+        size.to_string()
+    }
+}
+
+/// Decides which unused packages the garbage collection removes.
+/// Without any limit all unused packages are removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcPolicy {
+    /// Removes the packages which were not used for longer than the age.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<Age>,
+    /// Removes the least recently used packages until the store is not larger than the size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<Size>,
+}
+
+impl GcPolicy {
+    /// Returns true if the policy removes all unused packages.
+    pub fn is_unlimited(&self) -> bool {
+        self.older_than.is_none() && self.max_size.is_none()
+    }
+
+    /// Returns the policy with the limits of the other policy where they are set.
+    pub fn merge(self, other: GcPolicy) -> Self {
+        Self {
+            older_than: other.older_than.or(self.older_than),
+            max_size: other.max_size.or(self.max_size),
+        }
+    }
+}
+
+/// An unused package collected by the garbage collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcPackage {
+    pub id: PackageId,
+    pub name: String,
+    pub version: String,
+    /// The size of the blobs no remaining package contains.
+    pub freed_size: u64,
+    /// The unix time in seconds the package was inserted or last found in use.
+    pub last_used: u64,
+}
+
+impl fmt::Display for GcPackage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unused = Duration::from_secs(now().saturating_sub(self.last_used));
+        write!(
+            f,
+            "{} {} {}\n\tfrees {}, unused for {}",
+            style(&self.name).blue(),
+            self.version,
+            self.id,
+            HumanBytes(self.freed_size),
+            HumanDuration(unused),
+        )
+    }
+}
+
+/// The packages collected by the garbage collection, least recently used first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub packages: Vec<GcPackage>,
+    /// The size of the blobs of the store before the collection.
+    pub size_before: u64,
+    /// The size of the blobs of the store after the collection.
+    pub size_after: u64,
+}
+
+impl GcReport {
+    pub fn freed_size(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &PackageId> {
+        self.packages.iter().map(|package| &package.id)
+    }
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for package in &self.packages {
+            writeln!(f, "{package}")?;
+        }
+        write!(
+            f,
+            "{} {} packages freeing {}, store size {} -> {}",
+            style("Total").green(),
+            self.packages.len(),
+            HumanBytes(self.freed_size()),
+            HumanBytes(self.size_before),
+            HumanBytes(self.size_after),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gc_policy_parse() {
+        assert_eq!("7d".parse::<Age>().unwrap().as_secs(), 7 * 24 * 60 * 60);
+        assert_eq!("90m".parse::<Age>().unwrap().as_secs(), 90 * 60);
+        assert_eq!("14d".parse::<Age>().unwrap().to_string(), "2w");
+        assert_eq!("90m".parse::<Age>().unwrap().to_string(), "90m");
+        assert!("7".parse::<Age>().is_err());
+        assert!("d".parse::<Age>().is_err());
+        assert!("7y".parse::<Age>().is_err());
+
+        assert_eq!("10G".parse::<Size>().unwrap().bytes(), 10 << 30);
+        assert_eq!("512 MiB".parse::<Size>().unwrap().bytes(), 512 << 20);
+        assert_eq!("2kb".parse::<Size>().unwrap().bytes(), 2 << 10);
+        assert_eq!("100".parse::<Size>().unwrap().bytes(), 100);
+        assert_eq!("1024M".parse::<Size>().unwrap().to_string(), "1G");
+        assert!("10X".parse::<Size>().is_err());
+        assert!("-1G".parse::<Size>().is_err());
+
+        let policy = GcPolicy {
+            older_than: Some("30d".parse().unwrap()),
+            max_size: None,
+        };
+        let toml = toml::to_string(&policy).unwrap();
+        assert_eq!(toml.trim(), r#"older_than = "30d""#);
+        assert_eq!(toml::from_str::<GcPolicy>(&toml).unwrap(), policy);
+        assert!(toml::from_str::<GcPolicy>(r#"max_size = "10Q""#).is_err());
+
+        let merged = policy.merge(GcPolicy {
+            older_than: Some("1d".parse().unwrap()),
+            max_size: Some("1G".parse().unwrap()),
+        });
+        assert_eq!(merged.older_than.unwrap().to_string(), "1d");
+        assert_eq!(merged.max_size.unwrap().to_string(), "1G");
+        assert!(GcPolicy::default().is_unlimited());
+    }
+}
@@ -0,0 +1,197 @@
+use super::{backend::ReadBackend, id::PackageId, IoSnafu, Store, StoreResult};
+use crate::{GID, UID};
+use log::warn;
+use snafu::ResultExt;
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::{self, ffi::OsStrExt},
+    path::{Path, PathBuf},
+};
+
+/// The directory next to the store which holds the garbage collection roots
+pub const GC_ROOTS_DIR: &str = "gcroots";
+/// The prefix of roots which point to another link, like the result link of a build
+const LINK_PREFIX: &str = "link-";
+/// The prefix of roots which are only kept while their process is running
+const PROCESS_PREFIX: &str = "process-";
+
+/// A symbolic link in the [GcRoots] which keeps a package alive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcRoot {
+    /// The path of the root inside the roots directory.
+    pub path: PathBuf,
+    /// The path the root points to.
+    pub target: PathBuf,
+    /// The package the root keeps alive, or `None` if the root is stale.
+    pub package: Option<PackageId>,
+}
+
+impl GcRoot {
+    /// Returns true if the root does not keep any package alive anymore.
+    pub fn is_stale(&self) -> bool {
+        self.package.is_none()
+    }
+}
+
+/// A directory of symbolic links to packages in the store, which are kept alive by
+/// the garbage collection together with their closure.
+/// Roots pointing to a removed link, a path outside of the store or
+/// belonging to a process which exited are stale.
+#[derive(Debug, Clone)]
+pub struct GcRoots {
+    path: PathBuf,
+}
+
+impl GcRoots {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn create_dir(&self) -> StoreResult<()> {
+        if !self.path.exists() {
+            fs::create_dir_all(&self.path).context(IoSnafu)?;
+            unix::fs::chown(&self.path, UID, GID).context(IoSnafu)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the root, replacing an existing root of the same name.
+    fn insert(&self, name: String, target: &Path) -> StoreResult<PathBuf> {
+        self.create_dir()?;
+
+        let root = self.path.join(name);
+        if root.symlink_metadata().is_ok() {
+            fs::remove_file(&root).context(IoSnafu)?;
+        }
+        unix::fs::symlink(target, &root).context(IoSnafu)?;
+
+        Ok(root)
+    }
+
+    /// Registers a symbolic link, like the result link of a build, as root
+    /// of the package it points to. The root becomes stale once the link is removed.
+    pub fn add_link(&self, link: &Path) -> StoreResult<PathBuf> {
+        let link = if link.is_absolute() {
+            link.to_owned()
+        } else {
+            std::env::current_dir().context(IoSnafu)?.join(link)
+        };
+        let hash = blake3::hash(link.as_os_str().as_bytes());
+
+        self.insert(format!("{LINK_PREFIX}{}", hash.to_hex()), &link)
+    }
+
+    /// Registers the package directory as root while the process is running.
+    pub fn add_process(&self, pid: u32, package: &Path) -> StoreResult<PathBuf> {
+        let name = package.file_name().unwrap_or_default().to_string_lossy();
+
+        self.insert(format!("{PROCESS_PREFIX}{pid}-{name}"), package)
+    }
+
+    /// Removes all roots registered for the process.
+    pub fn remove_process(&self, pid: u32) -> StoreResult<()> {
+        let prefix = format!("{PROCESS_PREFIX}{pid}-");
+
+        for root in self.entries()? {
+            if file_name(&root).starts_with(&prefix) {
+                fs::remove_file(root).context(IoSnafu)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> StoreResult<Vec<PathBuf>> {
+        match fs::read_dir(&self.path) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<_>>()
+                .context(IoSnafu),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(source) => Err(source).context(IoSnafu),
+        }
+    }
+
+    /// Returns all roots together with the package of the store they keep alive.
+    /// Entries which are no symbolic links are skipped with a warning.
+    pub fn list<B: ReadBackend, const BAR: bool>(
+        &self,
+        store: &Store<PathBuf, B, BAR>,
+    ) -> StoreResult<Vec<GcRoot>> {
+        let store_path = fs::canonicalize(store.path()).context(IoSnafu)?;
+        let packages = store
+            .packages()
+            .iter()
+            .map(|(id, drv, _objects)| (drv.relative_path(id).into_string(), *id))
+            .collect::<HashMap<_, _>>();
+
+        let mut roots = Vec::new();
+        for path in self.entries()? {
+            let target = match fs::read_link(&path) {
+                Ok(target) => target,
+                Err(e) => {
+                    warn!("Skipping {path:?} in the roots, it is no symbolic link: {e}");
+                    continue;
+                }
+            };
+
+            let alive = match file_name(&path).strip_prefix(PROCESS_PREFIX) {
+                Some(rest) => rest
+                    .split('-')
+                    .next()
+                    .map_or(false, |pid| Path::new("/proc").join(pid).exists()),
+                None => true,
+            };
+
+            // The root is followed through all links until it reaches the store
+            let package = fs::canonicalize(&path)
+                .ok()
+                .filter(|_| alive)
+                .and_then(|resolved| {
+                    let relative = resolved.strip_prefix(&store_path).ok()?;
+                    let dir = relative.components().next()?.as_os_str().to_str()?;
+                    packages.get(dir).copied()
+                });
+
+            roots.push(GcRoot {
+                path,
+                target,
+                package,
+            });
+        }
+        roots.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(roots)
+    }
+
+    /// Removes all stale roots and returns them.
+    pub fn prune<B: ReadBackend, const BAR: bool>(
+        &self,
+        store: &Store<PathBuf, B, BAR>,
+    ) -> StoreResult<Vec<GcRoot>> {
+        let stale = self
+            .list(store)?
+            .into_iter()
+            .filter(GcRoot::is_stale)
+            .collect::<Vec<_>>();
+
+        for root in &stale {
+            fs::remove_file(&root.path).context(IoSnafu)?;
+        }
+
+        Ok(stale)
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
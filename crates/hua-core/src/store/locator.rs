@@ -1,47 +1,236 @@
-use super::{package::RemotePackageSource, RemoteStore, StoreResult};
-use crate::recipe::Derivation;
-use url::Url;
+use super::{package::RemotePackageSource, CacheSnafu, RemoteStore, StoreError, StoreResult};
+use crate::{config::CacheConfig, recipe::Derivation};
+use cached_path::CacheBuilder;
+use console::style;
+use log::warn;
+use snafu::ResultExt;
+use std::{cell::OnceCell, cmp::Reverse, fmt, time::Duration};
 
+/// How long the downloaded packages database of a cache is used without asking the cache again
+pub const FRESHNESS_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// A cache of the [Locator], which is opened on first use.
 #[derive(Debug)]
-pub struct Locator {
-    remotes: Vec<RemoteStore>,
+struct LocatorCache {
+    config: CacheConfig,
+    store: OnceCell<StoreResult<RemoteStore>>,
+}
+
+/// The state of a cache of the [Locator].
+#[derive(Debug)]
+pub enum CacheStatus<'a> {
+    /// The cache is disabled in the config and never opened.
+    Disabled,
+    /// No search needed the cache yet.
+    Unopened,
+    /// The packages database of the cache was downloaded and verified.
+    Available { packages: usize },
+    /// The cache could not be opened, it is skipped by every search.
+    Failed(&'a StoreError),
 }
 
-// TODO: open remote stores only if package was not found before so that failing remotes
-// do not cause the whole thing to blow up
+impl<'a> fmt::Display for CacheStatus<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "{}", style("disabled").dim()),
+            Self::Unopened => write!(f, "{}", style("unopened").dim()),
+            Self::Available { packages } => {
+                write!(f, "{} {packages} packages", style("available").green())
+            }
+            Self::Failed(e) => write!(f, "{} {e}", style("failed").red()),
+        }
+    }
+}
+
+/// Searches the caches for packages which are missing in the local store.
+/// A cache is only opened once a search reaches it, caches which cannot be opened
+/// are logged and skipped, so that a broken cache does not stop the others.
+#[derive(Debug)]
+pub struct Locator {
+    caches: Vec<LocatorCache>,
+    freshness: Option<Duration>,
+}
 
 impl Locator {
-    pub fn new(remotes: impl IntoIterator<Item = Url>) -> StoreResult<Self> {
-        let remotes = remotes
+    /// Prepares the caches, which are searched by their priority, the highest first.
+    /// Caches of the same priority are searched in the given order.
+    /// Their signatures are checked against their trusted keys when they are opened.
+    pub fn new(caches: impl IntoIterator<Item = CacheConfig>) -> Self {
+        let mut caches = caches
             .into_iter()
-            .map(|url| RemoteStore::open(url))
-            .collect::<StoreResult<_>>()?;
+            .map(|config| LocatorCache {
+                config,
+                store: OnceCell::new(),
+            })
+            .collect::<Vec<_>>();
+        caches.sort_by_key(|cache| Reverse(cache.config.priority));
+
+        Self {
+            caches,
+            freshness: Some(FRESHNESS_LIFETIME),
+        }
+    }
 
-        Ok(Self { remotes })
+    /// Sets how long a downloaded packages database is used without asking the cache again,
+    /// by default the [FRESHNESS_LIFETIME]. With `None` the cache is always asked.
+    pub fn with_freshness(mut self, freshness: Option<Duration>) -> Self {
+        self.freshness = freshness;
+        self
     }
 
+    /// Returns the opened cache, or `None` if it is disabled or could not be opened.
+    fn open<'a>(&self, cache: &'a LocatorCache) -> Option<&'a RemoteStore> {
+        if !cache.config.enabled {
+            return None;
+        }
+
+        cache
+            .store
+            .get_or_init(|| {
+                let config = &cache.config;
+                let mut builder = CacheBuilder::default();
+                if let Some(freshness) = self.freshness {
+                    builder = builder.freshness_lifetime(freshness.as_secs());
+                }
+                if let Some(timeout) = config.timeout {
+                    builder = builder.timeout(timeout);
+                }
+                let trusted = config.trusted_keys.iter().cloned().collect::<Vec<_>>();
+
+                let result = builder.build().context(CacheSnafu).and_then(|download| {
+                    RemoteStore::open_cached(config.url.clone(), &trusted, &download)
+                });
+                if let Err(e) = &result {
+                    warn!("Skipping cache {}: {e}", config.url);
+                }
+                result
+            })
+            .as_ref()
+            .ok()
+    }
+
+    /// Opens every cache which was not opened by a search yet.
+    pub fn open_all(&self) {
+        for cache in &self.caches {
+            self.open(cache);
+        }
+    }
+
+    /// Returns the caches in the order they are searched together with their state.
+    pub fn status(&self) -> impl Iterator<Item = (&CacheConfig, CacheStatus)> {
+        self.caches.iter().map(|cache| {
+            let status = match cache.store.get() {
+                _ if !cache.config.enabled => CacheStatus::Disabled,
+                None => CacheStatus::Unopened,
+                Some(Ok(store)) => CacheStatus::Available {
+                    packages: store.packages().iter().count(),
+                },
+                Some(Err(e)) => CacheStatus::Failed(e),
+            };
+            (&cache.config, status)
+        })
+    }
+
+    /// Searches the enabled caches by their priority for packages built from the derivation.
     pub fn search<'a>(
         &'a self,
         drv: &'a Derivation,
     ) -> impl Iterator<Item = RemotePackageSource> + 'a {
-        self.remotes
+        self.caches
             .iter()
-            .map(move |remote| {
+            .filter_map(|cache| Some((cache.config.timeout, self.open(cache)?)))
+            .map(move |(timeout, remote)| {
                 remote
                     .packages()
                     .filter(move |_, other_drv, _| drv == other_drv)
-                    .map(|(id, drv, ids)| {
+                    .map(move |(id, drv, ids)| {
                         let base = remote.url().clone();
 
                         let blobs = remote.objects().get_blobs_ids_cloned(ids).collect();
                         let trees = remote.objects().get_trees_ids_cloned(ids).collect();
-                        RemotePackageSource::new(*id, drv.clone(), base, blobs, trees)
+                        let links = remote.objects().get_links_ids_cloned(ids).collect();
+                        RemotePackageSource::new(*id, drv.clone(), base, blobs, trees, links)
+                            .with_timeout(timeout)
                     })
             })
             .flatten()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Locator;
+    use crate::{
+        config::CacheConfig,
+        recipe::Derivation,
+        store::{id::PackageId, LocalStore},
+        support::*,
+    };
+    use std::{fs, os::unix::fs::MetadataExt, path::Path};
+    use temp_dir::TempDir;
+
+    /// Inserts the package `one` into a new store at the path, which is used as cache.
+    fn cache_with_one(path: &Path, temp_dir: &TempDir) -> (PackageId, Derivation) {
+        let mut store = LocalStore::init(path).unwrap();
+        let package_path = temp_dir.child("one");
+        let package = pkg("one", &package_path);
+        fs::write(package_path.join("lib/one.so"), "one").unwrap();
+        let _ = store.insert(package).unwrap();
+        let (id, drv) = store.packages().find_by_name("one").unwrap();
+        let (id, drv) = (*id, drv.clone());
+        store.flush().unwrap();
+        (id, drv)
+    }
+
+    #[test]
+    fn locator_links_owned_files_of_directory_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let (one_id, one) = cache_with_one(&path, &temp_dir);
+        let file = one.relative_path(&one_id).join("lib/one.so");
+        let cached = fs::metadata(file.to_path(&path)).unwrap();
+
+        let url = CacheConfig::parse_url(path.to_str().unwrap()).unwrap();
+        assert_eq!(url.scheme(), "file");
+        let locator = Locator::new([CacheConfig::new(url)]).with_freshness(None);
+        let source = locator
+            .search(&one)
+            .next()
+            .unwrap()
+            .with_owners(Some(vec![cached.uid()]));
+
+        let other_path = temp_dir.child("other");
+        let mut other = LocalStore::init(&other_path).unwrap();
+        let _ = other.insert_remote(source).unwrap();
+        assert!(other.verify_package(&one_id).unwrap().is_ok());
+
+        let linked = fs::metadata(file.to_path(&other_path)).unwrap();
+        assert_eq!(cached.ino(), linked.ino());
+    }
+
+    #[test]
+    fn locator_copies_foreign_files_of_directory_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let (one_id, one) = cache_with_one(&path, &temp_dir);
+        let file = one.relative_path(&one_id).join("lib/one.so");
+
+        // Files of untrusted owners could be changed after they were verified
+        let url = CacheConfig::parse_url(path.to_str().unwrap()).unwrap();
+        let locator = Locator::new([CacheConfig::new(url)]).with_freshness(None);
+        let source = locator
+            .search(&one)
+            .next()
+            .unwrap()
+            .with_owners(Some(Vec::new()));
+
+        let other_path = temp_dir.child("other");
+        let mut other = LocalStore::init(&other_path).unwrap();
+        let _ = other.insert_remote(source).unwrap();
+        assert!(other.verify_package(&one_id).unwrap().is_ok());
 
-    pub fn get_url(&self, index: usize) -> Option<&Url> {
-        self.remotes.get(index).map(|store| store.url())
+        let cached = fs::metadata(file.to_path(&path)).unwrap();
+        let copied = fs::metadata(file.to_path(&other_path)).unwrap();
+        assert_ne!(cached.ino(), copied.ino());
     }
 }
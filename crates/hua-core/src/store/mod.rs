@@ -1,21 +1,29 @@
-use crate::recipe::Derivation;
+use crate::{dependency::Requirement, recipe::Derivation};
 
 use self::{
     id::{ObjectId, PackageId},
-    object::{Object, ObjectKind},
+    object::{Blob, Object, ObjectKind},
     package::LocalPackageSource,
+    report::PackageReport,
 };
 use snafu::prelude::*;
 use std::path::PathBuf;
 
 pub use store::*;
 
+pub mod archive;
 pub mod backend;
+pub mod gc;
+pub mod gcroots;
 // pub mod derivation;
 pub mod id;
 pub mod locator;
 pub mod object;
 pub mod package;
+pub mod report;
+pub mod server;
+pub mod signature;
+pub mod stats;
 mod store;
 
 #[derive(Debug, Snafu)]
@@ -32,8 +40,22 @@ pub enum StoreError {
     RustbreakLoadDataError { source: rustbreak::RustbreakError },
     #[snafu(display("Could not save data: {source}"))]
     RustbreakSaveDataError { source: rustbreak::RustbreakError },
+    #[snafu(display("Sqlite error: {source}"))]
+    SqliteError { source: rusqlite::Error },
+    #[snafu(display("Unknown store backend {name}, expected rustbreak or sqlite"))]
+    UnknownBackend { name: String },
+    #[snafu(display("The cache {url} is not a local directory"))]
+    InvalidCachePath { url: url::Url },
+    #[snafu(display("Invalid duration {value:?}, expected a number with a unit like 7d"))]
+    InvalidAge { value: String },
+    #[snafu(display("Invalid size {value:?}, expected a number with a unit like 10G"))]
+    InvalidSize { value: String },
     #[snafu(display("Store does not exists at {path:#?}"))]
     NotExisting { path: PathBuf },
+    #[snafu(display("The store is busy, {path:#?} is locked by another process"))]
+    Busy { path: PathBuf },
+    #[snafu(display("The store was opened read-only and cannot be written to {path:#?}"))]
+    ReadOnly { path: PathBuf },
     #[snafu(display("VerifyIoError: {source}"))]
     VerifyIoError { source: std::io::Error },
     #[snafu(display("IoError: {source}"))]
@@ -61,8 +83,33 @@ pub enum StoreError {
     FsExtraError { source: fs_extra::error::Error },
     #[snafu(display("Waldir Error: {source}"))]
     WalkDirError { source: walkdir::Error },
+    #[snafu(display("Could not (de)serialize the packages database: {source}"))]
+    PotError { source: pot::Error },
+    #[snafu(display("Invalid key or signature: {key}"))]
+    InvalidKey { key: String },
+    #[snafu(display("Could not generate a new key"))]
+    KeyGeneration,
+    #[snafu(display("Cache {url} is not signed but has trusted keys"))]
+    UnsignedCache { url: url::Url },
+    #[snafu(display("Cache {url} is signed by {name} which does not match a trusted key"))]
+    SignatureMismatch { url: url::Url, name: String },
+    #[snafu(display("Refusing to insert setuid or setgid file: {blob}"))]
+    SetuidBlob { blob: Blob },
+    #[snafu(display("Invalid package name {name:?} or version {version:?} in the archive"))]
+    InvalidPackagePath { name: String, version: String },
     #[snafu(display("Package could not be verified: {drv}"))]
     PackageNotVerified { drv: Derivation },
+    #[snafu(display("Package is still corrupted after the repair: {report}"))]
+    PackageNotRepaired { report: PackageReport },
+    #[snafu(display("Could not resolve the dependencies: {source}"))]
+    DependencyError {
+        source: crate::dependency::DependencyError,
+    },
+    #[snafu(display("The dependencies of {id} are not in the store: {requirements:#?}"))]
+    UnresolvedDependencies {
+        id: PackageId,
+        requirements: Vec<Requirement>,
+    },
     #[snafu(display("Packge could not be found for {id}"))]
     PackageNotFoundById { id: PackageId },
     #[snafu(display("Object vould not be found for {id}"))]
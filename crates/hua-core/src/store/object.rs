@@ -3,19 +3,50 @@ use relative_path::{RelativePath, RelativePathBuf};
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt,
+    hash::{Hash, Hasher},
+    mem,
     path::{Path, PathBuf},
 };
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A regular file inside a package.
+///
+/// Blobs are identified by their path only, so that requirements and conflicts
+/// do not depend on the mode. The mode is covered by the [ObjectId] of the blob.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Blob {
     pub path: RelativePathBuf,
+    /// The normalized permission bits of the file.
+    #[serde(default = "Blob::default_mode")]
+    pub mode: u32,
 }
 
 impl Blob {
+    /// Read-only for everyone, used when no mode is given.
+    pub const DEFAULT_MODE: u32 = 0o444;
+
     pub fn new(path: RelativePathBuf) -> Self {
-        Self { path }
+        Self::with_mode(path, Self::DEFAULT_MODE)
+    }
+
+    pub fn with_mode(path: RelativePathBuf, mode: u32) -> Self {
+        Self { path, mode }
+    }
+
+    fn default_mode() -> u32 {
+        Self::DEFAULT_MODE
+    }
+
+    /// Keeps only the permission bits and strips all write bits,
+    /// as files in the store must never be modified.
+    pub fn normalize_mode(mode: u32) -> u32 {
+        mode & 0o7777 & !0o222
+    }
+
+    /// Returns true if the setuid or setgid bit is set.
+    pub fn is_setuid(&self) -> bool {
+        self.mode & 0o6000 != 0
     }
 
     pub fn to_path<P: AsRef<Path>>(&self, base: P) -> PathBuf {
@@ -23,9 +54,35 @@ impl Blob {
     }
 }
 
+impl PartialEq for Blob {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for Blob {}
+
+impl Ord for Blob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.path.cmp(&other.path)
+    }
+}
+
+impl PartialOrd for Blob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for Blob {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
+}
+
 impl fmt::Display for Blob {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Blob: {}", &self.path)
+        write!(f, "Blob: {} ({:o})", &self.path, self.mode)
     }
 }
 
@@ -51,15 +108,18 @@ impl fmt::Display for Tree {
     }
 }
 
+/// A symbolic link inside a package.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Link {
+    /// The path of the link itself.
     pub link: RelativePathBuf,
-    pub source: ObjectId,
+    /// The target of the link exactly as it was read from the file system.
+    pub target: PathBuf,
 }
 
 impl Link {
-    pub fn new(link: RelativePathBuf, source: ObjectId) -> Self {
-        Self { link, source }
+    pub fn new(link: RelativePathBuf, target: PathBuf) -> Self {
+        Self { link, target }
     }
 
     pub fn to_path<P: AsRef<Path>>(&self, base: P) -> PathBuf {
@@ -69,7 +129,7 @@ impl Link {
 
 impl fmt::Display for Link {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Link: {} <- {}", &self.link, &self.source)
+        write!(f, "Link: {} -> {:?}", &self.link, &self.target)
     }
 }
 
@@ -98,7 +158,7 @@ impl Object {
 
     pub fn replace_path(&mut self, path: RelativePathBuf) -> RelativePathBuf {
         match self {
-            Self::Link(_) => todo!(),
+            Self::Link(link) => std::mem::replace(&mut link.link, path),
             Self::Tree(tree) => std::mem::replace(&mut tree.path, path),
             Self::Blob(blob) => std::mem::replace(&mut blob.path, path),
         }
@@ -236,6 +296,9 @@ impl From<Link> for Object {
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Objects {
     nodes: HashMap<ObjectId, Object>,
+    /// The objects inserted, removed or borrowed mutably since the last [Objects::take_changed]
+    #[serde(skip)]
+    changed: HashSet<ObjectId>,
 }
 
 impl Objects {
@@ -252,6 +315,7 @@ impl Objects {
     }
 
     pub fn get_mut(&mut self, id: &ObjectId) -> Option<&mut Object> {
+        self.changed.insert(*id);
         self.nodes.get_mut(id)
     }
 
@@ -278,7 +342,7 @@ impl Objects {
         ids: impl IntoIterator<Item = &'a ObjectId> + 'a,
     ) -> impl Iterator<Item = Option<(ObjectId, Object)>> + 'a {
         ids.into_iter()
-            .map(|id| self.nodes.remove(id).map(|object| (*id, object)))
+            .map(|id| self.remove(id).map(|object| (*id, object)))
     }
 
     pub unsafe fn remove_objects_unchecked<'a>(
@@ -286,7 +350,7 @@ impl Objects {
         ids: impl IntoIterator<Item = &'a ObjectId> + 'a,
     ) -> impl Iterator<Item = (ObjectId, Object)> + 'a {
         ids.into_iter().map(|id| {
-            let object = self.nodes.remove(id);
+            let object = self.remove(id);
             (*id, object.unwrap_unchecked())
         })
     }
@@ -319,9 +383,24 @@ impl Objects {
     }
 
     pub fn insert(&mut self, object_id: ObjectId, object: Object) -> Option<Object> {
+        self.changed.insert(object_id);
         self.nodes.insert(object_id, object)
     }
 
+    pub fn remove(&mut self, id: &ObjectId) -> Option<Object> {
+        self.changed.insert(*id);
+        self.nodes.remove(id)
+    }
+
+    /// Returns the ids of the objects changed since the last call, which may no longer exist.
+    pub fn take_changed(&mut self) -> HashSet<ObjectId> {
+        mem::take(&mut self.changed)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ObjectId, &Object)> {
+        self.nodes.iter()
+    }
+
     pub fn get_blobs<'a>(
         &'a self,
         ids: impl IntoIterator<Item = &'a ObjectId>,
@@ -386,4 +465,17 @@ impl Objects {
             }
         })
     }
+
+    pub fn get_links_ids_cloned<'a>(
+        &'a self,
+        ids: impl IntoIterator<Item = &'a ObjectId> + 'a,
+    ) -> impl Iterator<Item = (Link, ObjectId)> + 'a {
+        ids.into_iter().filter_map(move |id| {
+            if let Some(object) = self.nodes.get(id) {
+                object.clone().into_link().map(|link| (link, *id))
+            } else {
+                None
+            }
+        })
+    }
 }
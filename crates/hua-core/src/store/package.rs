@@ -1,16 +1,19 @@
-use crate::recipe::Derivation;
+use crate::{extra::hash::HASH_VERSION, recipe::Derivation};
 
 use super::{
-    object::{Blob, Tree},
+    object::{Blob, Link, Tree},
     ObjectId, PackageId,
 };
 use console::style;
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::{self},
+    hash::Hash,
+    mem,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use url::Url;
 
@@ -51,6 +54,12 @@ pub struct RemotePackageSource {
     pub base: Url,
     pub blobs: BTreeMap<Blob, ObjectId>,
     pub trees: BTreeMap<Tree, ObjectId>,
+    pub links: BTreeMap<Link, ObjectId>,
+    /// Downloads of the blobs are aborted after the timeout of the cache.
+    pub timeout: Option<Duration>,
+    /// The users whose files of a local cache are linked instead of copied,
+    /// the store user and root if none are given.
+    pub owners: Option<Vec<u32>>,
 }
 
 impl ToString for RemotePackageSource {
@@ -70,6 +79,7 @@ impl RemotePackageSource {
         base: Url,
         blobs: BTreeMap<Blob, ObjectId>,
         trees: BTreeMap<Tree, ObjectId>,
+        links: BTreeMap<Link, ObjectId>,
     ) -> Self {
         Self {
             id,
@@ -77,14 +87,89 @@ impl RemotePackageSource {
             base,
             blobs,
             trees,
+            links,
+            timeout: None,
+            owners: None,
         }
     }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_owners(mut self, owners: Option<Vec<u32>>) -> Self {
+        self.owners = owners;
+        self
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "PackagesData")]
 pub struct Packages {
     nodes: HashMap<PackageId, Derivation>,
     children: HashMap<PackageId, HashSet<ObjectId>>,
+    /// The [HASH_VERSION] the ids were calculated with.
+    /// Missing in stores created before the version was recorded.
+    hash_version: u8,
+    /// The unix time in seconds each package was inserted or last found in use
+    /// by the garbage collection.
+    used: HashMap<PackageId, u64>,
+    /// The packages which contain each object
+    #[serde(skip)]
+    owners: HashMap<ObjectId, BTreeSet<PackageId>>,
+    /// The packages built from each derivation
+    #[serde(skip)]
+    derivations: HashMap<Derivation, BTreeSet<PackageId>>,
+    /// The packages whose derivation, objects or usage changed since the last
+    /// [Packages::take_changed]
+    #[serde(skip)]
+    changed: HashSet<PackageId>,
+}
+
+/// The serialized fields of [Packages], from which the indices are built again.
+#[derive(Deserialize)]
+struct PackagesData {
+    nodes: HashMap<PackageId, Derivation>,
+    children: HashMap<PackageId, HashSet<ObjectId>>,
+    #[serde(default)]
+    hash_version: u8,
+    #[serde(default)]
+    used: HashMap<PackageId, u64>,
+}
+
+impl From<PackagesData> for Packages {
+    fn from(data: PackagesData) -> Self {
+        let mut packages = Self {
+            hash_version: data.hash_version,
+            ..Self::default()
+        };
+        let mut children = data.children;
+
+        for (id, drv) in data.nodes {
+            let objects = children.remove(&id).unwrap_or_default();
+            packages.insert(id, drv, objects);
+        }
+        for (id, time) in data.used {
+            packages.set_last_used(&id, time);
+        }
+
+        packages
+    }
+}
+
+impl Default for Packages {
+    fn default() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            children: HashMap::new(),
+            hash_version: HASH_VERSION,
+            used: HashMap::new(),
+            owners: HashMap::new(),
+            derivations: HashMap::new(),
+            changed: HashSet::new(),
+        }
+    }
 }
 
 impl Packages {
@@ -92,10 +177,18 @@ impl Packages {
         Self::default()
     }
 
+    pub fn hash_version(&self) -> u8 {
+        self.hash_version
+    }
+
+    pub fn set_hash_version(&mut self, version: u8) {
+        self.hash_version = version;
+    }
+
     pub fn contains_drv(&self, drv: &Derivation) -> Option<PackageId> {
-        self.nodes
-            .iter()
-            .find_map(|(id, other)| if drv == other { Some(*id) } else { None })
+        self.derivations
+            .get(drv)
+            .and_then(|ids| ids.iter().next().copied())
     }
 
     pub fn contains(&self, id: &PackageId) -> bool {
@@ -108,18 +201,39 @@ impl Packages {
         drv: Derivation,
         objects: HashSet<ObjectId>,
     ) -> Option<Derivation> {
+        let old = self.remove(&id).map(|(drv, _objects)| drv);
+
+        for object in &objects {
+            self.owners.entry(*object).or_default().insert(id);
+        }
+        self.derivations.entry(drv.clone()).or_default().insert(id);
+
+        self.changed.insert(id);
         self.children.insert(id, objects);
-        self.nodes.insert(id, drv)
+        self.nodes.insert(id, drv);
+        old
     }
 
     pub fn insert_child(&mut self, id: &PackageId, child: ObjectId) -> Option<bool> {
         if let Some(children) = self.children.get_mut(id) {
+            self.owners.entry(child).or_default().insert(*id);
+            self.changed.insert(*id);
             Some(children.insert(child))
         } else {
             None
         }
     }
 
+    pub fn remove_child(&mut self, id: &PackageId, child: &ObjectId) -> Option<bool> {
+        if let Some(children) = self.children.get_mut(id) {
+            remove_from_index(&mut self.owners, child, id);
+            self.changed.insert(*id);
+            Some(children.remove(child))
+        } else {
+            None
+        }
+    }
+
     pub fn get(&self, id: &PackageId) -> Option<&Derivation> {
         self.nodes.get(id)
     }
@@ -140,11 +254,45 @@ impl Packages {
         }
     }
 
+    /// Returns the unix time in seconds the package was inserted or last found in use,
+    /// or `None` if it was never recorded.
+    pub fn last_used(&self, id: &PackageId) -> Option<u64> {
+        self.used.get(id).copied()
+    }
+
+    /// Records the unix time in seconds the package was last used.
+    /// Returns false if the package is not in the store.
+    pub fn set_last_used(&mut self, id: &PackageId, time: u64) -> bool {
+        if self.nodes.contains_key(id) {
+            self.used.insert(*id, time);
+            self.changed.insert(*id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the ids of the packages changed since the last call, which may no longer exist.
+    pub fn take_changed(&mut self) -> HashSet<PackageId> {
+        mem::take(&mut self.changed)
+    }
+
     pub fn remove(&mut self, id: &PackageId) -> Option<(Derivation, HashSet<ObjectId>)> {
         let desc = self.nodes.remove(id);
         let children = self.children.remove(id);
+        self.used.remove(id);
+        self.changed.insert(*id);
 
-        if let Some(drv) = desc && let Some(children) = children {
+        for object in children.iter().flatten() {
+            remove_from_index(&mut self.owners, object, id);
+        }
+        if let Some(drv) = &desc {
+            remove_from_index(&mut self.derivations, drv, id);
+        }
+
+        if let Some(drv) = desc
+            && let Some(children) = children
+        {
             Some((drv, children))
         } else {
             None
@@ -173,6 +321,10 @@ impl Packages {
         }
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&PackageId, &Derivation, &HashSet<ObjectId>)> {
+        self.filter(|_id, _drv, _objects| true)
+    }
+
     pub fn filter<P>(
         &self,
         predicate: P,
@@ -227,12 +379,76 @@ impl Packages {
     }
 
     pub fn find_package_id(&self, object_id: &ObjectId) -> Option<&PackageId> {
-        self.children.iter().find_map(|(package_id, set)| {
-            if set.contains(object_id) {
-                Some(package_id)
-            } else {
-                None
-            }
-        })
+        self.owners(object_id).next()
+    }
+
+    /// Returns all packages which contain the object.
+    pub fn owners(&self, object_id: &ObjectId) -> impl Iterator<Item = &PackageId> {
+        self.owners.get(object_id).into_iter().flatten()
+    }
+
+    /// Returns the number of packages which contain the object.
+    /// An object may only be removed from the store once it is not referenced anymore.
+    pub fn reference_count(&self, object_id: &ObjectId) -> usize {
+        self.owners.get(object_id).map_or(0, BTreeSet::len)
+    }
+}
+
+/// Removes the package from the entry of the key and drops the entry once it is empty.
+fn remove_from_index<K: Eq + Hash>(
+    index: &mut HashMap<K, BTreeSet<PackageId>>,
+    key: &K,
+    id: &PackageId,
+) {
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Packages;
+    use crate::{
+        store::id::{ObjectId, PackageId},
+        support::*,
+    };
+    use std::collections::HashSet;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn packages_indices() {
+        let temp_dir = TempDir::new().unwrap();
+        let one = pkg("one", temp_dir.child("one")).drv;
+        let two = pkg("two", temp_dir.child("two")).drv;
+        let (one_id, two_id) = (PackageId::from([1; 32]), PackageId::from([2; 32]));
+        let (shared, own) = (ObjectId::from([1; 32]), ObjectId::from([2; 32]));
+
+        let mut packages = Packages::new();
+        packages.insert(one_id, one.clone(), HashSet::from([shared, own]));
+        packages.insert(two_id, two.clone(), HashSet::from([shared]));
+
+        assert_eq!(packages.owners(&shared).count(), 2);
+        assert_eq!(packages.find_package_id(&own), Some(&one_id));
+        assert_eq!(packages.contains_drv(&one), Some(one_id));
+        assert_eq!(packages.contains_drv(&two), Some(two_id));
+
+        packages.remove(&one_id);
+        assert_eq!(packages.owners(&shared).collect::<Vec<_>>(), [&two_id]);
+        assert_eq!(packages.find_package_id(&own), None);
+        assert_eq!(packages.contains_drv(&one), None);
+
+        assert_eq!(packages.insert_child(&two_id, own), Some(true));
+        assert_eq!(packages.find_package_id(&own), Some(&two_id));
+        assert_eq!(packages.remove_child(&two_id, &shared), Some(true));
+        assert_eq!(packages.find_package_id(&shared), None);
+
+        // The indices are not serialized but built again
+        let bytes = pot::to_vec(&packages).unwrap();
+        let packages = pot::from_slice::<Packages>(&bytes).unwrap();
+        assert_eq!(packages.find_package_id(&own), Some(&two_id));
+        assert_eq!(packages.contains_drv(&two), Some(two_id));
     }
 }
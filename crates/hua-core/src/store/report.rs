@@ -0,0 +1,166 @@
+use super::{
+    id::{ObjectId, PackageId},
+    object::{Blob, Link},
+};
+use console::style;
+use relative_path::RelativePathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::PathBuf,
+};
+
+/// The result of verifying a package in the store against its [PackageId]
+/// and the [ObjectId] of every [Blob] it contains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageReport {
+    /// The [PackageId] under which the package is registered.
+    pub id: PackageId,
+    /// The name of the package.
+    pub name: String,
+    /// The [PackageId] recalculated from the package directory,
+    /// or `None` if the directory is missing.
+    pub actual: Option<PackageId>,
+    /// Registered blobs that are missing in the package directory.
+    pub missing: BTreeMap<Blob, ObjectId>,
+    /// Registered blobs whose contents do not match their [ObjectId].
+    pub modified: BTreeMap<Blob, ObjectId>,
+    /// Registered blobs whose mode differs, together with the actual mode.
+    pub modes: BTreeMap<Blob, u32>,
+    /// Registered links that are missing or point to another target.
+    pub links: BTreeMap<Link, ObjectId>,
+    /// Files in the package directory that are not registered.
+    pub extra: BTreeSet<RelativePathBuf>,
+}
+
+impl PackageReport {
+    pub fn new(id: PackageId, name: String) -> Self {
+        Self {
+            id,
+            name,
+            actual: None,
+            missing: BTreeMap::new(),
+            modified: BTreeMap::new(),
+            modes: BTreeMap::new(),
+            links: BTreeMap::new(),
+            extra: BTreeSet::new(),
+        }
+    }
+
+    /// Returns true if the package is intact.
+    pub fn is_ok(&self) -> bool {
+        self.actual == Some(self.id)
+            && self.missing.is_empty()
+            && self.modified.is_empty()
+            && self.modes.is_empty()
+            && self.links.is_empty()
+            && self.extra.is_empty()
+    }
+
+    /// Returns all blobs which have to be restored to repair the package.
+    pub fn broken_blobs(&self) -> impl Iterator<Item = (&Blob, &ObjectId)> {
+        self.missing.iter().chain(self.modified.iter())
+    }
+}
+
+impl fmt::Display for PackageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(
+                f,
+                "{} {} {}",
+                style("Ok").green(),
+                style(&self.name).blue(),
+                self.id
+            );
+        }
+
+        write!(
+            f,
+            "{} {} {}",
+            style("Corrupted").red(),
+            style(&self.name).blue(),
+            self.id
+        )?;
+        match self.actual {
+            None => write!(f, "\n\tpackage directory missing")?,
+            Some(actual) if actual != self.id => write!(f, "\n\tcalculated id {actual}")?,
+            _ => (),
+        }
+        for blob in self.missing.keys() {
+            write!(f, "\n\tmissing {}", style(&blob.path).yellow())?;
+        }
+        for blob in self.modified.keys() {
+            write!(f, "\n\tmodified {}", style(&blob.path).yellow())?;
+        }
+        for (blob, mode) in &self.modes {
+            write!(
+                f,
+                "\n\tmode of {} changed from {:o} to {mode:o}",
+                style(&blob.path).yellow(),
+                blob.mode
+            )?;
+        }
+        for link in self.links.keys() {
+            write!(f, "\n\tbroken link {}", style(&link.link).yellow())?;
+        }
+        for path in &self.extra {
+            write!(f, "\n\textra {}", style(path).yellow())?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of checking the directories of the store against its database.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreReport {
+    /// Directories in the store that no package is registered for
+    /// and files in the objects that no blob is registered for.
+    pub orphans: BTreeSet<PathBuf>,
+    /// Registered packages whose directory is missing.
+    pub dangling_packages: BTreeSet<PackageId>,
+    /// Objects referenced by packages but missing in the object table.
+    pub dangling_objects: BTreeMap<PackageId, BTreeSet<ObjectId>>,
+    /// Objects in the object table that are not referenced by any package.
+    pub unreferenced_objects: BTreeSet<ObjectId>,
+}
+
+impl StoreReport {
+    /// Returns true if the store is consistent with its database.
+    pub fn is_ok(&self) -> bool {
+        self.orphans.is_empty()
+            && self.dangling_packages.is_empty()
+            && self.dangling_objects.is_empty()
+            && self.unreferenced_objects.is_empty()
+    }
+}
+
+impl fmt::Display for StoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "{} store is consistent", style("Ok").green());
+        }
+
+        write!(f, "{} store is inconsistent", style("Inconsistent").red())?;
+        for path in &self.orphans {
+            write!(f, "\n\torphan directory {}", style(path.display()).yellow())?;
+        }
+        for id in &self.dangling_packages {
+            write!(f, "\n\tdangling package {}", style(id).yellow())?;
+        }
+        for (package_id, objects) in &self.dangling_objects {
+            for id in objects {
+                write!(
+                    f,
+                    "\n\tdangling object {} of package {}",
+                    style(id).yellow(),
+                    package_id
+                )?;
+            }
+        }
+        for id in &self.unreferenced_objects {
+            write!(f, "\n\tunreferenced object {}", style(id).yellow())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,746 @@
+//! Serves a local store over HTTP as a binary cache.
+//!
+//! The layout is the one expected by [RemoteStore](super::RemoteStore): the
+//! [PACKAGES_DB] of the published packages at the root and their contents under
+//! `name-version-id/`. If the server trusts keys for uploads, archives created by
+//! [Store::export](super::Store::export) can be uploaded with a `PUT` request. The
+//! [archive::digest] has to be signed by one of the keys, the signature is sent in the
+//! [SIGNATURE_HEADER]. Uploads without a signature of a trusted key or larger than the
+//! maximum upload size are refused before the archive is read, signed archives are
+//! verified and imported into the store.
+//! If the server has a [SecretKey], the signature of the served database is published
+//! as [PACKAGES_DB_SIG].
+//!
+//! Every connection is handled on its own thread.
+
+use super::{
+    archive,
+    backend::BackendKind,
+    signature::{PublicKey, SecretKey, Signature, PACKAGES_DB_SIG},
+    IoSnafu, LocalStore, PotSnafu, StoreResult, PACKAGES_DB,
+};
+use crate::extra::lock::LockMode;
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use relative_path::{Component, RelativePath};
+use snafu::ResultExt;
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, SystemTime},
+};
+use temp_dir::TempDir;
+
+/// The maximum size of the request line and headers.
+const MAX_HEAD: u64 = 16 * 1024;
+/// Connections are dropped if the client does not send anything for this long.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// The header carrying the signature of an uploaded archive.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// The default maximum size of an uploaded archive in bytes.
+pub const MAX_UPLOAD: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    content_length: u64,
+    signature: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Published {
+    /// The last served database together with the modification time of the store database.
+    database: Option<(SystemTime, Vec<u8>)>,
+    /// The directory names of the packages in the last served database.
+    packages: BTreeSet<String>,
+}
+
+#[derive(Debug)]
+pub struct StoreServer {
+    path: PathBuf,
+    filter: Vec<String>,
+    /// The keys whose signed archives are imported, without keys uploads are refused.
+    uploaders: Vec<PublicKey>,
+    /// Larger uploads are refused.
+    max_upload: u64,
+    key: Option<SecretKey>,
+    published: Mutex<Published>,
+}
+
+impl StoreServer {
+    /// Creates a read-only server for the store at the path which publishes all packages.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            filter: Vec::new(),
+            uploaders: Vec::new(),
+            max_upload: MAX_UPLOAD,
+            key: None,
+            published: Mutex::default(),
+        }
+    }
+
+    /// Only publishes packages whose name contains one of the given names.
+    pub fn filter<N: Into<String>>(mut self, names: impl IntoIterator<Item = N>) -> Self {
+        self.filter.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Imports uploaded archives which are signed by one of the keys.
+    /// Without any key the server is read-only, which is the default.
+    pub fn allow_upload(mut self, keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        self.uploaders.extend(keys);
+        self
+    }
+
+    /// Refuses uploads larger than the size in bytes, by default [MAX_UPLOAD].
+    pub fn max_upload(mut self, bytes: u64) -> Self {
+        self.max_upload = bytes;
+        self
+    }
+
+    /// Signs the served database with the key.
+    pub fn sign_with(mut self, key: SecretKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Binds to the address and serves requests.
+    pub fn bind<A: ToSocketAddrs>(self, addr: A) -> StoreResult<()> {
+        let listener = TcpListener::bind(addr).context(IoSnafu)?;
+        self.serve(listener)
+    }
+
+    /// Serves the requests of the listener, each connection on its own thread.
+    /// Failing requests are logged and do not stop the server.
+    pub fn serve(self, listener: TcpListener) -> StoreResult<()> {
+        // Fail early if the store cannot be opened
+        let _database = self.publish()?;
+
+        let server = Arc::new(self);
+        for stream in listener.incoming() {
+            let mut stream = stream.context(IoSnafu)?;
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                if let Err(e) = server.handle(&mut stream) {
+                    warn!("Request failed: {e}");
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the serialized database of all published packages.
+    /// The store is opened again whenever its database changed so that new packages
+    /// are published.
+    fn publish(&self) -> StoreResult<Vec<u8>> {
+        let mut published = self
+            .published
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let database = self.path.join(PACKAGES_DB);
+        let database = database.with_file_name(BackendKind::detect(&database).file_name());
+        let modified = fs::metadata(database)
+            .and_then(|metadata| metadata.modified())
+            .context(IoSnafu)?;
+        if let Some((time, database)) = &published.database
+            && *time == modified
+        {
+            return Ok(database.clone());
+        }
+
+        let store = LocalStore::open_with(&self.path, LockMode::Shared, Some(TIMEOUT))?;
+        let package_ids = store
+            .packages()
+            .filter(|_id, drv, _objects| {
+                self.filter.is_empty() || self.filter.iter().any(|name| drv.name.contains(name))
+            })
+            .map(|(id, _drv, _objects)| id)
+            .collect::<Vec<_>>();
+
+        let database = store.subset(package_ids.iter().copied())?;
+        published.packages = database
+            .1
+            .iter()
+            .map(|(id, drv, _objects)| drv.relative_path(id).into_string())
+            .collect();
+
+        let database = pot::to_vec(&database).context(PotSnafu)?;
+        published.database = Some((modified, database.clone()));
+        Ok(database)
+    }
+
+    fn handle(&self, stream: &mut TcpStream) -> StoreResult<()> {
+        stream.set_read_timeout(Some(TIMEOUT)).context(IoSnafu)?;
+        let mut reader = BufReader::new(stream.try_clone().context(IoSnafu)?);
+
+        let request = match read_request(&mut reader).context(IoSnafu)? {
+            Some(request) => request,
+            None => return respond(stream, "400 Bad Request", &[]).context(IoSnafu),
+        };
+        info!("{} {}", request.method, request.path);
+
+        match request.method.as_str() {
+            "GET" | "HEAD" => self.get(stream, &request),
+            "PUT" if self.uploaders.is_empty() => {
+                respond(stream, "403 Forbidden", b"The cache is read-only\n").context(IoSnafu)
+            }
+            "PUT" => self.put(stream, &mut reader, &request),
+            _ => respond(stream, "405 Method Not Allowed", &[]).context(IoSnafu),
+        }
+    }
+
+    fn get(&self, stream: &mut TcpStream, request: &Request) -> StoreResult<()> {
+        let head = request.method == "HEAD";
+
+        if request.path == format!("/{PACKAGES_DB}") {
+            let database = self.publish()?;
+            return send(stream, &database, head).context(IoSnafu);
+        }
+
+        if request.path == format!("/{PACKAGES_DB_SIG}") {
+            let database = self.publish()?;
+            let signature = match &self.key {
+                Some(key) => format!("{}\n", key.sign(&database)),
+                None => return respond(stream, "404 Not Found", &[]).context(IoSnafu),
+            };
+            return send(stream, signature.as_bytes(), head).context(IoSnafu);
+        }
+
+        let path = match self.resolve(&request.path) {
+            Some(path) => path,
+            None => return respond(stream, "404 Not Found", &[]).context(IoSnafu),
+        };
+
+        let mut file = File::open(&path).context(IoSnafu)?;
+        let metadata = file.metadata().context(IoSnafu)?;
+        let etag = format!("{:x}-{:x}", metadata.mtime(), metadata.size());
+
+        write_head(stream, "200 OK", metadata.size(), Some(&etag)).context(IoSnafu)?;
+        if !head {
+            io::copy(&mut file, stream).context(IoSnafu)?;
+        }
+        stream.flush().context(IoSnafu)
+    }
+
+    fn put(
+        &self,
+        stream: &mut TcpStream,
+        reader: &mut impl Read,
+        request: &Request,
+    ) -> StoreResult<()> {
+        // Nothing is read before the upload is known to be signed by a trusted key
+        let signature = match request.signature.as_deref().map(str::parse::<Signature>) {
+            Some(Ok(signature)) => signature,
+            _ => {
+                let body =
+                    format!("The upload has to be signed in the {SIGNATURE_HEADER} header\n");
+                return respond(stream, "401 Unauthorized", body.as_bytes()).context(IoSnafu);
+            }
+        };
+        let keys = self
+            .uploaders
+            .iter()
+            .filter(|key| key.name() == signature.name())
+            .collect::<Vec<_>>();
+        if keys.is_empty() {
+            warn!("Refused upload signed by unknown key {}", signature.name());
+            return respond(
+                stream,
+                "403 Forbidden",
+                b"The upload is not signed by a trusted key\n",
+            )
+            .context(IoSnafu);
+        }
+        if request.content_length > self.max_upload {
+            let body = format!("The upload is larger than {} bytes\n", self.max_upload);
+            return respond(stream, "413 Payload Too Large", body.as_bytes()).context(IoSnafu);
+        }
+
+        let temp_dir = TempDir::new().context(IoSnafu)?;
+        let archive = temp_dir.child("archive");
+
+        let mut file = File::create(&archive).context(IoSnafu)?;
+        let read =
+            io::copy(&mut reader.take(request.content_length), &mut file).context(IoSnafu)?;
+        if read != request.content_length {
+            return respond(stream, "400 Bad Request", &[]).context(IoSnafu);
+        }
+
+        let digest = archive::digest(&archive).context(IoSnafu)?;
+        if !keys
+            .iter()
+            .any(|key| key.verify(digest.as_bytes(), &signature))
+        {
+            warn!(
+                "Refused upload signed by untrusted key {}",
+                signature.name()
+            );
+            return respond(
+                stream,
+                "403 Forbidden",
+                b"The upload is not signed by a trusted key\n",
+            )
+            .context(IoSnafu);
+        }
+
+        let mut store = LocalStore::open_with(&self.path, LockMode::Exclusive, Some(TIMEOUT))?;
+        let imported = match store.import(&archive) {
+            Ok(imported) => imported,
+            Err(e) => {
+                let body = format!("{e}\n");
+                return respond(stream, "422 Unprocessable Entity", body.as_bytes())
+                    .context(IoSnafu);
+            }
+        };
+        store.flush()?;
+
+        let body = imported
+            .iter()
+            .map(|id| format!("{id}\n"))
+            .collect::<String>();
+        respond(stream, "200 OK", body.as_bytes()).context(IoSnafu)
+    }
+
+    /// Returns the path of the requested file if it is a regular file inside
+    /// a published package.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let path = path.strip_prefix('/')?;
+        let path = percent_decode_str(path).decode_utf8().ok()?;
+        let path = RelativePath::new(path.as_ref());
+
+        let mut components = path.components();
+        let published = self
+            .published
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match components.next() {
+            Some(Component::Normal(dir)) if published.packages.contains(dir) => (),
+            _ => return None,
+        }
+        if !components.all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+
+        // Links inside the package must not lead out of it
+        let full = path.to_path(&self.path);
+        let package = RelativePath::new(path.components().next()?.as_str()).to_path(&self.path);
+        let canonical = fs::canonicalize(&full).ok()?;
+        if !canonical.starts_with(fs::canonicalize(package).ok()?) {
+            return None;
+        }
+
+        let metadata = fs::symlink_metadata(&full).ok()?;
+        metadata.is_file().then_some(full)
+    }
+}
+
+/// Reads the request line and headers.
+/// Returns `None` if the request is malformed.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut head = reader.take(MAX_HEAD);
+
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target),
+        _ => return Ok(None),
+    };
+    // The query is not used
+    let path = target.split('?').next().unwrap_or_default().to_owned();
+
+    let mut content_length = 0;
+    let mut signature = None;
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = match value.trim().parse() {
+                Ok(length) => length,
+                Err(_) => return Ok(None),
+            };
+        } else if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case(SIGNATURE_HEADER)
+        {
+            signature = Some(value.trim().to_owned());
+        }
+    }
+
+    Ok(Some(Request {
+        method,
+        path,
+        content_length,
+        signature,
+    }))
+}
+
+fn write_head(
+    stream: &mut TcpStream,
+    status: &str,
+    content_length: u64,
+    etag: Option<&str>,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Length: {content_length}\r\n"
+    )?;
+    if let Some(etag) = etag {
+        write!(stream, "ETag: \"{etag}\"\r\n")?;
+    }
+    write!(stream, "Connection: close\r\n\r\n")
+}
+
+/// Sends generated data, tagged with its hash so that clients notice changes.
+fn send(stream: &mut TcpStream, data: &[u8], head: bool) -> io::Result<()> {
+    let etag = blake3::hash(data).to_hex().to_string();
+    write_head(stream, "200 OK", data.len() as u64, Some(&etag))?;
+    if !head {
+        stream.write_all(data)?;
+    }
+    stream.flush()
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    write_head(stream, status, body.len() as u64, None)?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StoreServer, SIGNATURE_HEADER};
+    use crate::{
+        config::CacheConfig,
+        extra::hash,
+        store::{
+            archive,
+            locator::{CacheStatus, Locator},
+            signature::{SecretKey, Signature},
+            LocalStore, RemoteStore, StoreError,
+        },
+        support::*,
+    };
+    use std::assert_matches::assert_matches;
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        path::Path,
+        thread,
+        time::Duration,
+    };
+    use temp_dir::TempDir;
+    use url::Url;
+
+    fn spawn(server: StoreServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener).unwrap());
+        addr
+    }
+
+    fn request(addr: SocketAddr, head: &str, body: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn store_with(path: &Path, names: &[&str], temp_dir: &TempDir) -> LocalStore {
+        let mut store = LocalStore::init(path).unwrap();
+        for name in names {
+            let package_path = temp_dir.child(name);
+            let package = pkg(name, &package_path);
+            fs::write(package_path.join(format!("lib/{name}.so")), name).unwrap();
+            let _ = store.insert(package).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn server_serves_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let store = store_with(&path, &["one", "two"], &temp_dir);
+        let (one_id, one) = store.packages().find_by_name("one").unwrap();
+        let (one_id, one) = (*one_id, one.clone());
+        let two = store.packages().find_by_name("two").unwrap().1.clone();
+        store.flush().unwrap();
+
+        let addr = spawn(StoreServer::new(&path).filter(["one"]));
+        let url = Url::parse(&format!("http://{addr}/")).unwrap();
+        let locator = Locator::new([CacheConfig::new(url)]).with_freshness(None);
+
+        assert_eq!(locator.search(&two).count(), 0);
+        let source = locator.search(&one).next().unwrap();
+        assert_eq!(source.id, one_id);
+
+        let other_path = temp_dir.child("other");
+        let mut other = LocalStore::init(&other_path).unwrap();
+        let _ = other.insert_remote(source).unwrap();
+        assert!(other.verify_package(&one_id).unwrap().is_ok());
+
+        let one_dir = one.relative_path(&one_id);
+        let response = request(
+            addr,
+            &format!("GET /{one_dir}/lib/one.so HTTP/1.1\r\n\r\n"),
+            &[],
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\none"));
+
+        for target in [
+            format!("/{one_dir}/../packages.db"),
+            format!("/{one_dir}/%2E%2E/packages.db"),
+            format!("/{one_dir}/lib"),
+            "/two".to_owned(),
+        ] {
+            let response = request(addr, &format!("GET {target} HTTP/1.1\r\n\r\n"), &[]);
+            assert!(response.starts_with("HTTP/1.1 404"), "{target}: {response}");
+        }
+
+        let response = request(addr, "PUT / HTTP/1.1\r\nContent-Length: 0\r\n\r\n", &[]);
+        assert!(response.starts_with("HTTP/1.1 403"));
+    }
+
+    #[test]
+    fn server_serves_while_a_client_is_idle() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        store_with(&path, &["one"], &temp_dir).flush().unwrap();
+
+        let addr = spawn(StoreServer::new(&path));
+        // Sends nothing until the others are served
+        let _idle = TcpStream::connect(addr).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"HEAD /packages.db HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    #[test]
+    fn server_copy_from_remote() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        store_with(&path, &["one", "two"], &temp_dir)
+            .flush()
+            .unwrap();
+        let one_id = hash::root_hash(&temp_dir.child("one"), "one").unwrap();
+
+        let addr = spawn(StoreServer::new(&path));
+        let url = Url::parse(&format!("http://{addr}/")).unwrap();
+        let remote = RemoteStore::open(url).unwrap();
+
+        let other_path = temp_dir.child("other");
+        let mut other = LocalStore::init(&other_path).unwrap();
+        assert_eq!(
+            other.copy_from_remote(&remote, &one_id, None).unwrap(),
+            [one_id]
+        );
+        assert!(other
+            .copy_from_remote(&remote, &one_id, None)
+            .unwrap()
+            .is_empty());
+        assert!(other.verify_package(&one_id).unwrap().is_ok());
+        assert_eq!(other.packages().iter().count(), 1);
+    }
+
+    #[test]
+    fn server_imports_upload() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        store_with(&path, &[], &temp_dir).flush().unwrap();
+
+        let other_path = temp_dir.child("other");
+        let other = store_with(&other_path, &["three"], &temp_dir);
+        let three_id = hash::root_hash(&temp_dir.child("three"), "three").unwrap();
+        let archive = temp_dir.child("archive");
+        other.export([&three_id], &archive, true).unwrap();
+        let body = fs::read(&archive).unwrap();
+
+        let key = SecretKey::generate("uploader").unwrap();
+        let untrusted = SecretKey::generate("uploader").unwrap();
+        let digest = archive::digest(&archive).unwrap();
+
+        let read_only = spawn(StoreServer::new(&path));
+        let addr = spawn(StoreServer::new(&path).allow_upload([key.public_key()]));
+        let upload = |addr, signature: Option<Signature>| {
+            let header = signature
+                .map(|signature| format!("{SIGNATURE_HEADER}: {signature}\r\n"))
+                .unwrap_or_default();
+            request(
+                addr,
+                &format!(
+                    "PUT /archive HTTP/1.1\r\nContent-Length: {}\r\n{header}\r\n",
+                    body.len()
+                ),
+                &body,
+            )
+        };
+
+        let response = upload(read_only, Some(key.sign(digest.as_bytes())));
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+        let response = upload(addr, None);
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+        let response = upload(addr, Some(untrusted.sign(digest.as_bytes())));
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+        let response = upload(addr, Some(key.sign(b"another archive")));
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+
+        // Refused before the body is read, which is never sent
+        let head = |signature: Signature, length: u64| {
+            format!(
+                "PUT /archive HTTP/1.1\r\nContent-Length: {length}\r\n{SIGNATURE_HEADER}: {signature}\r\n\r\n"
+            )
+        };
+        let stranger = SecretKey::generate("stranger").unwrap();
+        let response = request(addr, &head(stranger.sign(digest.as_bytes()), 1 << 40), &[]);
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+        let response = request(addr, &head(key.sign(digest.as_bytes()), 1 << 40), &[]);
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+        let limited = spawn(
+            StoreServer::new(&path)
+                .allow_upload([key.public_key()])
+                .max_upload(body.len() as u64 - 1),
+        );
+        let response = upload(limited, Some(key.sign(digest.as_bytes())));
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+        assert!(!LocalStore::open(&path)
+            .unwrap()
+            .packages()
+            .contains(&three_id));
+
+        let response = upload(addr, Some(key.sign(digest.as_bytes())));
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with(&format!("{three_id}\n")));
+
+        let store = LocalStore::open(&path).unwrap();
+        assert!(store.verify_package(&three_id).unwrap().is_ok());
+    }
+
+    #[test]
+    fn server_signed_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        store_with(&path, &["one"], &temp_dir).flush().unwrap();
+
+        let key = SecretKey::generate("cache-1").unwrap();
+        let other = SecretKey::generate("cache-1").unwrap();
+
+        let signed = spawn(StoreServer::new(&path).sign_with(key.clone()));
+        let signed = Url::parse(&format!("http://{signed}/")).unwrap();
+        let unsigned = spawn(StoreServer::new(&path));
+        let unsigned = Url::parse(&format!("http://{unsigned}/")).unwrap();
+
+        let locator = Locator::new([
+            CacheConfig::new(signed.clone()).with_trusted_keys([key.public_key()]),
+            CacheConfig::new(signed.clone())
+                .with_trusted_keys([other.public_key(), key.public_key()]),
+            CacheConfig::new(unsigned.clone()),
+            CacheConfig::new(signed).with_trusted_keys([other.public_key()]),
+            CacheConfig::new(unsigned).with_trusted_keys([key.public_key()]),
+        ])
+        .with_freshness(None);
+        locator.open_all();
+
+        let status = locator
+            .status()
+            .map(|(_cache, status)| status)
+            .collect::<Vec<_>>();
+        for status in &status[..3] {
+            assert_matches!(status, CacheStatus::Available { packages: 1 });
+        }
+        assert_matches!(
+            status[3],
+            CacheStatus::Failed(StoreError::SignatureMismatch { url: _, name: _ })
+        );
+        assert_matches!(
+            status[4],
+            CacheStatus::Failed(StoreError::UnsignedCache { url: _ })
+        );
+    }
+
+    #[test]
+    fn server_locator_skips_failing_caches() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let store = store_with(&path, &["one"], &temp_dir);
+        let (one_id, one) = store.packages().find_by_name("one").unwrap();
+        let (one_id, one) = (*one_id, one.clone());
+        store.flush().unwrap();
+
+        let addr = spawn(StoreServer::new(&path));
+        let url = Url::parse(&format!("http://{addr}/")).unwrap();
+        let unreachable = Url::parse("http://127.0.0.1:1/").unwrap();
+
+        let locator = Locator::new([
+            CacheConfig::new(url.clone()).with_priority(1),
+            CacheConfig::new(unreachable).with_priority(9),
+            CacheConfig::new(url.clone()),
+            CacheConfig {
+                enabled: false,
+                ..CacheConfig::new(url).with_priority(10)
+            },
+        ])
+        .with_freshness(None);
+        assert_eq!(
+            locator
+                .status()
+                .map(|(cache, _status)| cache.priority)
+                .collect::<Vec<_>>(),
+            [10, 9, 5, 1]
+        );
+        assert_matches!(locator.status().next(), Some((_, CacheStatus::Disabled)));
+        assert!(locator
+            .status()
+            .skip(1)
+            .all(|(_cache, status)| matches!(status, CacheStatus::Unopened)));
+
+        // The search stops before the last cache is needed
+        let source = locator.search(&one).next().unwrap();
+        assert_eq!(source.id, one_id);
+
+        let status = locator
+            .status()
+            .map(|(_cache, status)| status)
+            .collect::<Vec<_>>();
+        assert_matches!(status[0], CacheStatus::Disabled);
+        assert_matches!(status[1], CacheStatus::Failed(_));
+        assert_matches!(status[2], CacheStatus::Available { packages: 1 });
+        assert_matches!(status[3], CacheStatus::Unopened);
+
+        assert_eq!(locator.search(&one).count(), 2);
+    }
+}
@@ -0,0 +1,295 @@
+//! Ed25519 signatures over the packages database of a cache.
+//!
+//! Keys and signatures are written as `name:base64`. The name identifies the key
+//! which created a signature, so that a cache can rotate its keys. The signature
+//! of the [PACKAGES_DB](super::PACKAGES_DB) is published next to it as
+//! [PACKAGES_DB_SIG].
+
+use super::{StoreError, StoreResult};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::warn;
+use ring::{
+    rand::SystemRandom,
+    signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use url::Url;
+
+/// The filename of the signature of the packages database
+pub const PACKAGES_DB_SIG: &str = "packages.db.sig";
+
+/// Splits `name:base64` into the name and the decoded bytes.
+fn decode(s: &str) -> StoreResult<(String, Vec<u8>)> {
+    // Only the name is reported so that secret keys do not end up in logs
+    let (name, data) = s.trim().split_once(':').unwrap_or_default();
+    let invalid = || StoreError::InvalidKey {
+        key: name.to_owned(),
+    };
+
+    if name.is_empty() {
+        return Err(invalid());
+    }
+    let bytes = STANDARD.decode(data).map_err(|_| invalid())?;
+
+    Ok((name.to_owned(), bytes))
+}
+
+/// The secret key used to sign a cache.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretKey {
+    name: String,
+    pkcs8: Vec<u8>,
+}
+
+impl SecretKey {
+    /// Generates a new random key with the given name.
+    pub fn generate(name: &str) -> StoreResult<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| StoreError::KeyGeneration)?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            pkcs8: pkcs8.as_ref().to_vec(),
+        })
+    }
+
+    fn key_pair(&self) -> Ed25519KeyPair {
+        // The key was already validated when it was parsed or generated
+        Ed25519KeyPair::from_pkcs8(&self.pkcs8).expect("Secret key should be valid")
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key_pair().public_key().as_ref().to_vec(),
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        Signature {
+            name: self.name.clone(),
+            bytes: self.key_pair().sign(data).as_ref().to_vec(),
+        }
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> StoreResult<Self> {
+        let (name, pkcs8) = decode(s)?;
+        if Ed25519KeyPair::from_pkcs8(&pkcs8).is_err() {
+            return Err(StoreError::InvalidKey { key: name });
+        }
+
+        Ok(Self { name, pkcs8 })
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, STANDARD.encode(&self.pkcs8))
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The public key used to verify the signature of a cache.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PublicKey {
+    name: String,
+    key: Vec<u8>,
+}
+
+impl PublicKey {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true if the signature was created by the secret key of this key over the data.
+    pub fn verify(&self, data: &[u8], signature: &Signature) -> bool {
+        self.name == signature.name
+            && UnparsedPublicKey::new(&signature::ED25519, &self.key)
+                .verify(data, &signature.bytes)
+                .is_ok()
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> StoreResult<Self> {
+        let (name, key) = decode(s)?;
+        if key.len() != 32 {
+            return Err(StoreError::InvalidKey { key: name });
+        }
+
+        Ok(Self { name, key })
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = StoreError;
+
+    fn try_from(s: String) -> StoreResult<Self> {
+        s.parse()
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(key: PublicKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, STANDARD.encode(&self.key))
+    }
+}
+
+/// A detached signature together with the name of the key which created it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    name: String,
+    bytes: Vec<u8>,
+}
+
+impl Signature {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl FromStr for Signature {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> StoreResult<Self> {
+        let (name, bytes) = decode(s)?;
+        Ok(Self { name, bytes })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, STANDARD.encode(&self.bytes))
+    }
+}
+
+/// Checks the signature of the packages database of a cache.
+///
+/// If keys are trusted for the cache, the database has to be signed by one of them.
+/// Caches without trusted keys are accepted with a warning.
+pub fn check(
+    url: &Url,
+    data: &[u8],
+    signature: Option<&Signature>,
+    trusted: &[PublicKey],
+) -> StoreResult<()> {
+    match signature {
+        _ if trusted.is_empty() => {
+            warn!("No trusted keys for cache {url}, its packages are not verified");
+            Ok(())
+        }
+        None => Err(StoreError::UnsignedCache { url: url.clone() }),
+        Some(signature) if trusted.iter().any(|key| key.verify(data, signature)) => Ok(()),
+        Some(signature) => Err(StoreError::SignatureMismatch {
+            url: url.clone(),
+            name: signature.name.clone(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, PublicKey, SecretKey, Signature};
+    use crate::store::StoreError;
+    use std::assert_matches::assert_matches;
+    use url::Url;
+
+    #[test]
+    fn signature_sign_verify() {
+        let key = SecretKey::generate("cache-1").unwrap();
+        let public = key.public_key();
+        let signature = key.sign(b"packages");
+
+        assert!(public.verify(b"packages", &signature));
+        assert!(!public.verify(b"modified", &signature));
+
+        let other = SecretKey::generate("cache-1").unwrap().public_key();
+        assert!(!other.verify(b"packages", &signature));
+
+        let renamed = format!("cache-2:{}", public.to_string().split_once(':').unwrap().1);
+        let renamed = renamed.parse::<PublicKey>().unwrap();
+        assert!(!renamed.verify(b"packages", &signature));
+    }
+
+    #[test]
+    fn signature_parse() {
+        let key = SecretKey::generate("cache-1").unwrap();
+        let public = key.public_key();
+        let signature = key.sign(b"packages");
+
+        assert_eq!(key.to_string().parse::<SecretKey>().unwrap(), key);
+        assert_eq!(public.to_string().parse::<PublicKey>().unwrap(), public);
+        assert_eq!(
+            signature.to_string().parse::<Signature>().unwrap(),
+            signature
+        );
+
+        for invalid in ["", "cache-1", ":AAAA", "cache-1:AAAA", "cache-1:not base64"] {
+            assert_matches!(
+                invalid.parse::<PublicKey>(),
+                Err(StoreError::InvalidKey { key: _ })
+            );
+        }
+        assert_matches!(
+            public.to_string().parse::<SecretKey>(),
+            Err(StoreError::InvalidKey { key: _ })
+        );
+    }
+
+    #[test]
+    fn signature_check() {
+        let url = Url::parse("http://localhost/").unwrap();
+        let key = SecretKey::generate("cache-1").unwrap();
+        let other = SecretKey::generate("cache-2").unwrap();
+        let signature = key.sign(b"packages");
+
+        assert!(check(&url, b"packages", None, &[]).is_ok());
+        assert!(check(&url, b"packages", Some(&signature), &[]).is_ok());
+        assert!(check(&url, b"packages", Some(&signature), &[key.public_key()]).is_ok());
+        assert!(check(
+            &url,
+            b"packages",
+            Some(&signature),
+            &[other.public_key(), key.public_key()]
+        )
+        .is_ok());
+
+        assert_matches!(
+            check(&url, b"packages", None, &[key.public_key()]),
+            Err(StoreError::UnsignedCache { url: _ })
+        );
+        assert_matches!(
+            check(&url, b"modified", Some(&signature), &[key.public_key()]),
+            Err(StoreError::SignatureMismatch { url: _, name: _ })
+        );
+        assert_matches!(
+            check(&url, b"packages", Some(&signature), &[other.public_key()]),
+            Err(StoreError::SignatureMismatch { url: _, name: _ })
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use super::id::PackageId;
+use console::style;
+use indicatif::HumanBytes;
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+/// Serializes an id as its hex string instead of its bytes.
+fn serialize_id<S: Serializer>(id: &PackageId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+/// The disk usage of a package in the store.
+/// All sizes are in bytes and only count blobs, every blob of a package counted once.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageStats {
+    #[serde(serialize_with = "serialize_id")]
+    pub id: PackageId,
+    pub name: String,
+    pub version: String,
+    /// The size of all blobs of the package.
+    pub apparent_size: u64,
+    /// The size of the blobs no other package contains.
+    pub unique_size: u64,
+    /// The size of the blobs shared with other packages.
+    pub shared_size: u64,
+    pub trees: usize,
+    pub blobs: usize,
+    pub links: usize,
+    /// The number of packages in the closure, including the package itself.
+    pub closure_packages: usize,
+    /// The size of the blobs of the package and all its dependencies,
+    /// or `None` if the dependencies could not be resolved in the store.
+    pub closure_size: Option<u64>,
+}
+
+impl fmt::Display for PackageStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}\n\tsize {} unique {} shared {}\n\t{} trees {} blobs {} links",
+            style(&self.name).blue(),
+            self.version,
+            self.id,
+            HumanBytes(self.apparent_size),
+            HumanBytes(self.unique_size),
+            HumanBytes(self.shared_size),
+            self.trees,
+            self.blobs,
+            self.links,
+        )?;
+        match self.closure_size {
+            Some(size) => write!(
+                f,
+                "\n\tclosure {} in {} packages",
+                HumanBytes(size),
+                self.closure_packages
+            ),
+            None => write!(f, "\n\tclosure {}", style("unresolved").yellow()),
+        }
+    }
+}
+
+/// The disk usage of the whole store.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TotalStats {
+    pub packages: usize,
+    pub objects: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    pub links: usize,
+    /// The size of all packages as if none of their blobs were shared.
+    pub apparent_size: u64,
+    /// The size of all blobs in the store, which is the space actually used.
+    pub disk_size: u64,
+    /// The size of the blobs which are contained in more than one package.
+    pub shared_size: u64,
+}
+
+impl TotalStats {
+    /// Returns the bytes saved by storing shared blobs only once.
+    pub fn saved_size(&self) -> u64 {
+        self.apparent_size.saturating_sub(self.disk_size)
+    }
+}
+
+impl fmt::Display for TotalStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} packages {} objects ({} trees {} blobs {} links)\n\tsize {} on disk {} shared {} saved {}",
+            style("Total").green(),
+            self.packages,
+            self.objects,
+            self.trees,
+            self.blobs,
+            self.links,
+            HumanBytes(self.apparent_size),
+            HumanBytes(self.disk_size),
+            HumanBytes(self.shared_size),
+            HumanBytes(self.saved_size()),
+        )
+    }
+}
+
+/// The disk usage of every package in the store and in total.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    pub packages: Vec<PackageStats>,
+    pub total: TotalStats,
+}
+
+impl StoreStats {
+    pub fn get(&self, id: &PackageId) -> Option<&PackageStats> {
+        self.packages.iter().find(|stats| &stats.id == id)
+    }
+}
+
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stats in &self.packages {
+            writeln!(f, "{stats}")?;
+        }
+        write!(f, "{}", self.total)
+    }
+}
@@ -1,13 +1,23 @@
 use super::{
-    backend::{LocalBackend, MemoryBackend, ReadBackend, RemoteBackend, WriteBackend},
-    object::{Blob, Objects, Tree},
+    archive,
+    backend::{
+        BackendKind, LocalBackend, MemoryBackend, ReadBackend, RemoteBackend, WriteBackend,
+        PACKAGES_SQLITE,
+    },
+    gc::{self, GcPackage, GcPolicy, GcReport},
+    gcroots::{GcRoots, GC_ROOTS_DIR},
+    object::{Blob, Link, Objects, Tree},
     package::{Packages, RemotePackageSource},
+    report::{PackageReport, StoreReport},
+    signature::PublicKey,
+    stats::{PackageStats, StoreStats},
     *,
 };
 use crate::{
-    dependency::Requirement,
+    dependency::{DependencyGraph, Requirement},
     extra::{
-        hash::{self, PackageHash},
+        hash::{self, PackageHash, HASH_VERSION},
+        lock::LockMode,
         path::ComponentPathBuf,
         style::ProgressBar,
     },
@@ -16,17 +26,29 @@ use crate::{
     GID, UID,
 };
 use cached_path::{Cache, CacheBuilder};
-use log::{info, warn};
+use log::{debug, info, warn};
+use relative_path::RelativePath;
 use std::{
-    collections::{BTreeMap, HashSet},
-    fs::{self},
-    os::unix::{self},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, Permissions},
+    os::unix::{
+        self,
+        fs::{MetadataExt, PermissionsExt},
+    },
     path::{Path, PathBuf},
+    time::Duration,
 };
+use temp_dir::TempDir;
 use url::Url;
 
 /// The filename of the packages database of the store
-const PACKAGES_DB: &str = "packages.db";
+pub const PACKAGES_DB: &str = "packages.db";
+/// The directory inside the store in which packages are assembled before insertion
+pub const STAGING_DIR: &str = ".staging";
+/// The directory inside the store which holds every blob exactly once, named by its [ObjectId].
+/// The blobs of the packages are hard links to these files.
+pub const OBJECTS_DIR: &str = ".objects";
 pub const STORE_PATH: &str = "/hua/store/";
 
 pub type LocalStore = Store<PathBuf, LocalBackend>;
@@ -69,10 +91,81 @@ impl<B: ReadBackend<Source = Url>> Store<Url, B> {
     }
 }
 
-impl<B> Store<PathBuf, B> {
+impl RemoteStore {
+    /// Opens a cache and checks the signature of its packages database against the
+    /// trusted keys, see [check](super::signature::check).
+    pub fn open_trusted(url: Url, trusted: &[PublicKey]) -> StoreResult<Self> {
+        let cache = CacheBuilder::default().build().context(CacheSnafu)?;
+        Self::open_cached(url, trusted, &cache)
+    }
+
+    /// Opens a cache like [RemoteStore::open_trusted], but downloads the packages database
+    /// through the given download cache, so that its freshness lifetime and timeout apply.
+    pub fn open_cached(url: Url, trusted: &[PublicKey], cache: &Cache) -> StoreResult<Self> {
+        let packages_db_url = url.join(PACKAGES_DB).context(UrlParseSnafu)?;
+        let backend = RemoteBackend::open_cached(packages_db_url, trusted, cache)?;
+
+        Ok(Self {
+            source: url,
+            backend,
+        })
+    }
+}
+
+impl<B, const BAR: bool> Store<PathBuf, B, BAR> {
     pub fn path(&self) -> &Path {
         &self.source
     }
+
+    /// Returns the path of the blob with the id inside the [OBJECTS_DIR].
+    pub fn object_file(&self, id: &ObjectId) -> PathBuf {
+        self.source.join(OBJECTS_DIR).join(id.to_string())
+    }
+
+    /// Returns the garbage collection roots of the store,
+    /// which are kept in the [GC_ROOTS_DIR] next to the store directory.
+    pub fn gc_roots(&self) -> GcRoots {
+        GcRoots::new(self.source.with_file_name(GC_ROOTS_DIR))
+    }
+}
+
+impl LocalStore {
+    /// Opens a store under the specified path with a lock of the given mode.
+    /// A store opened with a [LockMode::Shared] lock cannot be flushed.
+    /// Returns [StoreError::Busy] if another process holds a conflicting lock
+    /// after the timeout elapsed.
+    pub fn open_with<P: AsRef<Path>>(
+        path: P,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> StoreResult<Self> {
+        Self::open_backend(path.as_ref().to_owned(), |path| {
+            LocalBackend::open_with(path, mode, timeout)
+        })
+    }
+
+    /// Creates a new store directory under the given path with a database of the given kind.
+    /// Will return an Error if the directory already exists
+    pub fn init_with<P: AsRef<Path>>(path: P, kind: BackendKind) -> StoreResult<Self> {
+        Self::init_backend(path.as_ref().to_owned(), |path| {
+            LocalBackend::init_with(path, kind)
+        })
+    }
+
+    pub fn backend_kind(&self) -> BackendKind {
+        self.backend.kind()
+    }
+
+    /// Moves the contents of the store into a database of the given kind.
+    /// The store must have been opened with a [LockMode::Exclusive] lock.
+    pub fn convert(self, kind: BackendKind) -> StoreResult<Self> {
+        let backend = self.backend.convert(self.source.join(PACKAGES_DB), kind)?;
+
+        Ok(Self {
+            source: self.source,
+            backend,
+        })
+    }
 }
 
 impl<B: ReadBackend<Source = PathBuf>> Store<PathBuf, B> {
@@ -80,13 +173,26 @@ impl<B: ReadBackend<Source = PathBuf>> Store<PathBuf, B> {
     /// Returns an error if the path does not exists or
     /// does not contain the necessary files
     pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
-        let path = path.as_ref().to_owned();
+        Self::open_backend(path.as_ref().to_owned(), B::open)
+    }
 
+    fn open_backend(
+        path: PathBuf,
+        open: impl FnOnce(PathBuf) -> StoreResult<B>,
+    ) -> StoreResult<Self> {
         if !path.exists() {
             return Err(StoreError::NotExisting { path });
         }
 
-        let backend = B::open(path.join(PACKAGES_DB))?;
+        let backend = open(path.join(PACKAGES_DB))?;
+
+        let version = backend.packages().hash_version();
+        if version != HASH_VERSION {
+            warn!("Store {path:?} uses hash version {version} instead of {HASH_VERSION}, it has to be migrated");
+        }
+        if !path.join(OBJECTS_DIR).exists() {
+            warn!("Store {path:?} has no {OBJECTS_DIR} directory, it has to be migrated");
+        }
 
         Ok(Self {
             source: path,
@@ -158,15 +264,330 @@ impl<B: ReadBackend<Source = PathBuf>> Store<PathBuf, B> {
     }
 }
 
+impl<B: ReadBackend<Source = PathBuf>, const BAR: bool> Store<PathBuf, B, BAR> {
+    /// Verifies the contents of a package against its [PackageId]
+    /// and the [ObjectId] of every blob registered for it.
+    pub fn verify_package(&self, package_id: &PackageId) -> StoreResult<PackageReport> {
+        let (drv, object_ids) = self
+            .packages()
+            .get_full(package_id)
+            .ok_or(StoreError::PackageNotFoundById { id: *package_id })?;
+        let root = drv.path_in_store(&self.source, package_id);
+
+        let mut report = PackageReport::new(*package_id, drv.name.clone());
+        let expected = self.objects().get_blobs_ids_cloned(object_ids);
+        let expected_links = self.objects().get_links_ids_cloned(object_ids);
+
+        if !root.exists() {
+            report.missing.extend(expected);
+            report.links.extend(expected_links);
+            return Ok(report);
+        }
+
+        let PackageHash {
+            root: actual,
+            mut blobs,
+            mut links,
+            ..
+        } = PackageHash::from_path(&root, &drv.name).context(VerifyIoSnafu)?;
+        report.actual = Some(actual);
+
+        for (blob, id) in expected {
+            match blobs.remove_entry(&blob) {
+                Some((_actual, other)) if other == id => (),
+                Some((actual, _other)) if actual.mode != blob.mode => {
+                    report.modes.insert(blob, actual.mode);
+                }
+                Some(_) => {
+                    report.modified.insert(blob, id);
+                }
+                None => {
+                    report.missing.insert(blob, id);
+                }
+            }
+        }
+        for (link, id) in expected_links {
+            if links.remove(&link).is_none() {
+                report.links.insert(link, id);
+            }
+        }
+        report.extra.extend(blobs.into_keys().map(|blob| blob.path));
+        report.extra.extend(links.into_keys().map(|link| link.link));
+
+        Ok(report)
+    }
+
+    /// Verifies all packages in the store.
+    pub fn verify(&self) -> StoreResult<Vec<PackageReport>> {
+        self.packages()
+            .iter()
+            .map(|(id, _drv, _objects)| self.verify_package(id))
+            .collect()
+    }
+
+    /// Checks the store directory against the database of the store.
+    /// Reports directories without a package, packages without a directory
+    /// and objects which are missing or not referenced by any package.
+    pub fn check(&self) -> StoreResult<StoreReport> {
+        let mut report = StoreReport::default();
+
+        let known = self
+            .packages()
+            .iter()
+            .map(|(id, drv, _objects)| drv.path_in_store(&self.source, id))
+            .collect::<HashSet<_>>();
+
+        for entry in fs::read_dir(&self.source).context(IoSnafu)? {
+            let path = entry.context(IoSnafu)?.path();
+
+            // The databases with their journals and hidden entries are not packages
+            let hidden = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(true, |name| {
+                    name == PACKAGES_DB
+                        || name.starts_with(PACKAGES_SQLITE)
+                        || name.starts_with('.')
+                });
+
+            if !hidden && !known.contains(&path) {
+                report.orphans.insert(path);
+            }
+        }
+
+        // Packages left over by interrupted insertions
+        let staging = self.source.join(STAGING_DIR);
+        if staging.exists() {
+            for entry in fs::read_dir(&staging).context(IoSnafu)? {
+                report.orphans.insert(entry.context(IoSnafu)?.path());
+            }
+        }
+
+        // Blobs in the objects area which are not in the database
+        let objects = self.source.join(OBJECTS_DIR);
+        if objects.exists() {
+            let blobs = self
+                .objects()
+                .iter()
+                .filter(|(_id, object)| object.is_blob())
+                .map(|(id, _object)| id.to_string())
+                .collect::<HashSet<_>>();
+
+            for entry in fs::read_dir(&objects).context(IoSnafu)? {
+                let entry = entry.context(IoSnafu)?;
+                if !blobs.contains(entry.file_name().to_str().unwrap_or_default()) {
+                    report.orphans.insert(entry.path());
+                }
+            }
+        }
+
+        let mut referenced: HashSet<&ObjectId> = HashSet::new();
+
+        for (id, drv, objects) in self.packages().iter() {
+            if !drv.path_in_store(&self.source, id).exists() {
+                report.dangling_packages.insert(*id);
+            }
+
+            let missing = objects
+                .iter()
+                .filter(|object_id| !self.objects().contains(object_id))
+                .copied()
+                .collect::<BTreeSet<_>>();
+            if !missing.is_empty() {
+                report.dangling_objects.insert(*id, missing);
+            }
+
+            referenced.extend(objects.iter());
+        }
+
+        report.unreferenced_objects.extend(
+            self.objects()
+                .iter()
+                .map(|(id, _object)| id)
+                .filter(|id| !referenced.contains(id)),
+        );
+
+        Ok(report)
+    }
+}
+
+impl<B: ReadBackend<Source = PathBuf>, const BAR: bool> Store<PathBuf, B, BAR> {
+    /// Exports the packages together with their objects and contents into an archive,
+    /// compressed with zstd if `compress` is set.
+    pub fn export<'a>(
+        &self,
+        package_ids: impl IntoIterator<Item = &'a PackageId>,
+        path: &Path,
+        compress: bool,
+    ) -> StoreResult<()> {
+        let package_ids = package_ids.into_iter().collect::<Vec<_>>();
+        let database = self.subset(package_ids.iter().copied())?;
+
+        let dirs = database
+            .1
+            .iter()
+            .map(|(id, drv, _objects)| {
+                (
+                    drv.relative_path(id).into_string(),
+                    drv.path_in_store(&self.source, id),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let database = pot::to_vec(&database).context(PotSnafu)?;
+        archive::write(
+            path,
+            compress,
+            &database,
+            dirs.iter().map(|(name, dir)| (name.clone(), dir.as_path())),
+        )
+        .context(IoSnafu)
+    }
+}
+
+impl<B: ReadBackend<Source = PathBuf>, const BAR: bool> Store<PathBuf, B, BAR> {
+    /// Returns the size of the blob, read from the [OBJECTS_DIR] or the first package
+    /// containing it. Blobs missing on disk count as empty, they are reported by [Store::check].
+    fn blob_size(&self, id: &ObjectId, blob: &Blob) -> u64 {
+        let object = self.object_file(id);
+        let file = if object.exists() {
+            object
+        } else {
+            match self
+                .packages()
+                .find_package_id(id)
+                .and_then(|package_id| self.packages().path_in_store(package_id, &self.source))
+            {
+                Some(path) => blob.to_path(path),
+                None => return 0,
+            }
+        };
+
+        fs::symlink_metadata(file)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    }
+
+    /// Returns the size of the packages, every blob counted once even if it is
+    /// contained in more than one of them.
+    pub fn size_of<'a>(&self, package_ids: impl IntoIterator<Item = &'a PackageId>) -> u64 {
+        package_ids
+            .into_iter()
+            .filter_map(|id| self.packages().get_children(id))
+            .flatten()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter_map(|id| {
+                self.objects()
+                    .get(id)
+                    .and_then(Object::as_blob)
+                    .map(|blob| (id, blob))
+            })
+            .map(|(id, blob)| self.blob_size(id, blob))
+            .sum()
+    }
+
+    /// Calculates the disk usage of every package and of the whole store.
+    /// The closures of all packages are resolved from their requirements in one pass.
+    pub fn stats(&self) -> StoreResult<StoreStats> {
+        let sizes = self
+            .objects()
+            .iter()
+            .filter_map(|(id, object)| object.as_blob().map(|blob| (*id, self.blob_size(id, blob))))
+            .collect::<HashMap<_, _>>();
+        let size_of = |id: &ObjectId| sizes.get(id).copied().unwrap_or(0);
+
+        let closures = self.closures();
+        let mut stats = StoreStats::default();
+
+        for (id, drv, objects) in self.packages().iter() {
+            let (shared, unique): (Vec<_>, Vec<_>) = objects
+                .iter()
+                .partition(|object| self.packages().reference_count(object) > 1);
+            let kinds = objects
+                .iter()
+                .filter_map(|object| self.objects().get(object))
+                .map(Object::kind)
+                .collect::<Vec<_>>();
+
+            let closure = closures.get(id).and_then(Option::as_ref);
+            let closure_objects = closure.map(|closure| {
+                closure
+                    .iter()
+                    .filter_map(|package_id| self.packages().get_children(package_id))
+                    .flatten()
+                    .collect::<HashSet<_>>()
+            });
+
+            let unique_size = unique.into_iter().map(size_of).sum::<u64>();
+            let shared_size = shared.into_iter().map(size_of).sum::<u64>();
+
+            stats.packages.push(PackageStats {
+                id: *id,
+                name: drv.name.clone(),
+                version: drv.version.to_string(),
+                apparent_size: unique_size + shared_size,
+                unique_size,
+                shared_size,
+                trees: kinds
+                    .iter()
+                    .filter(|kind| **kind == ObjectKind::Tree)
+                    .count(),
+                blobs: kinds
+                    .iter()
+                    .filter(|kind| **kind == ObjectKind::Blob)
+                    .count(),
+                links: kinds
+                    .iter()
+                    .filter(|kind| **kind == ObjectKind::Link)
+                    .count(),
+                closure_packages: closure.map_or(1, BTreeSet::len),
+                closure_size: closure_objects.map(|objects| objects.into_iter().map(size_of).sum()),
+            });
+        }
+        stats.packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let total = &mut stats.total;
+        total.packages = stats.packages.len();
+        total.apparent_size = stats.packages.iter().map(|stats| stats.apparent_size).sum();
+        for (_id, object) in self.objects().iter() {
+            total.objects += 1;
+            match object.kind() {
+                ObjectKind::Tree => total.trees += 1,
+                ObjectKind::Blob => total.blobs += 1,
+                ObjectKind::Link => total.links += 1,
+            }
+        }
+        total.disk_size = sizes.values().sum();
+        total.shared_size = sizes
+            .iter()
+            .filter(|(id, _size)| self.packages().reference_count(id) > 1)
+            .map(|(_id, size)| size)
+            .sum();
+
+        Ok(stats)
+    }
+}
+
 impl<B: WriteBackend<Source = PathBuf>> Store<PathBuf, B> {
     /// Creates a new store directory under the given path.
     /// Will return an Error if the directory already exists
     pub fn init<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
-        let path = path.as_ref().to_owned();
+        Self::init_backend(path.as_ref().to_owned(), B::init)
+    }
+
+    fn init_backend(
+        path: PathBuf,
+        init: impl FnOnce(PathBuf) -> StoreResult<B>,
+    ) -> StoreResult<Self> {
         fs::create_dir(&path).context(IoSnafu)?;
         unix::fs::chown(&path, UID, GID).context(IoSnafu)?;
 
-        let backend = B::init(path.join(PACKAGES_DB))?;
+        let objects = path.join(OBJECTS_DIR);
+        fs::create_dir(&objects).context(IoSnafu)?;
+        unix::fs::chown(&objects, UID, GID).context(IoSnafu)?;
+
+        let backend = init(path.join(PACKAGES_DB))?;
 
         Ok(Self {
             source: path,
@@ -188,6 +609,35 @@ impl<S, B: ReadBackend, const BAR: bool> Store<S, B, BAR> {
     //     self.backend.derivations()
     // }
 
+    /// Returns the objects and packages of the database restricted to the given packages.
+    /// Fails if an object of the packages is missing in the database.
+    pub fn subset<'a>(
+        &self,
+        package_ids: impl IntoIterator<Item = &'a PackageId>,
+    ) -> StoreResult<(Objects, Packages)> {
+        let mut objects = Objects::new();
+        let mut packages = Packages::new();
+        packages.set_hash_version(self.packages().hash_version());
+
+        for id in package_ids {
+            let (drv, children) = self
+                .packages()
+                .get_full(id)
+                .ok_or(StoreError::PackageNotFoundById { id: *id })?;
+
+            for object_id in children {
+                let object = self
+                    .objects()
+                    .get(object_id)
+                    .ok_or(StoreError::ObjectNotFoundById { id: *object_id })?;
+                objects.insert(*object_id, object.clone());
+            }
+            packages.insert(*id, drv.clone(), children.clone());
+        }
+
+        Ok((objects, packages))
+    }
+
     pub fn matches<'a>(
         &'a self,
         requirement: &'a Requirement,
@@ -248,6 +698,185 @@ impl<S, B: ReadBackend, const BAR: bool> Store<S, B, BAR> {
     }
 }
 
+impl<S, B: ReadBackend, const BAR: bool> Store<S, B, BAR> {
+    /// Resolves the requirements of the package against the store and returns the ids
+    /// of the package and of all packages it depends on, directly or transitively.
+    pub fn closure(&self, package_id: &PackageId) -> StoreResult<BTreeSet<PackageId>> {
+        let drv = self
+            .packages()
+            .get(package_id)
+            .context(PackageNotFoundByIdSnafu { id: *package_id })?;
+
+        let mut graph = DependencyGraph::new();
+        graph
+            .resolve(&drv.requires, self)
+            .context(DependencySnafu)?;
+        if !graph.is_resolved() {
+            return Err(StoreError::UnresolvedDependencies {
+                id: *package_id,
+                requirements: graph.unresolved_requirements().cloned().collect(),
+            });
+        }
+
+        let mut closure = graph.resolved_packages().collect::<BTreeSet<_>>();
+        closure.insert(*package_id);
+        Ok(closure)
+    }
+
+    /// Returns the closure of every package like [Store::closure], or `None` if it cannot be
+    /// resolved. The closures of the dependencies are reused, only packages with a requirement
+    /// which several packages match are resolved on their own.
+    fn closures(&self) -> HashMap<PackageId, Option<BTreeSet<PackageId>>> {
+        let mut closures = HashMap::new();
+        for (id, _drv, _objects) in self.packages().iter() {
+            self.closure_memoized(id, &mut closures, &mut HashSet::new());
+        }
+        closures
+    }
+
+    fn closure_memoized(
+        &self,
+        package_id: &PackageId,
+        closures: &mut HashMap<PackageId, Option<BTreeSet<PackageId>>>,
+        visiting: &mut HashSet<PackageId>,
+    ) -> Option<BTreeSet<PackageId>> {
+        if let Some(closure) = closures.get(package_id) {
+            return closure.clone();
+        }
+        // Packages which depend on themselves are a cycle
+        if !visiting.insert(*package_id) {
+            return None;
+        }
+        let closure = self.closure_of_dependencies(package_id, closures, visiting);
+        visiting.remove(package_id);
+        closures.insert(*package_id, closure.clone());
+        closure
+    }
+
+    fn closure_of_dependencies(
+        &self,
+        package_id: &PackageId,
+        closures: &mut HashMap<PackageId, Option<BTreeSet<PackageId>>>,
+        visiting: &mut HashSet<PackageId>,
+    ) -> Option<BTreeSet<PackageId>> {
+        let drv = self.packages().get(package_id)?;
+        let mut closure = BTreeSet::from([*package_id]);
+        for req in &drv.requires {
+            let matches = self
+                .matches(req)
+                .map(|(id, _drv, _blobs)| *id)
+                .collect::<Vec<_>>();
+            match matches.as_slice() {
+                [] => return None,
+                [id] => closure.extend(self.closure_memoized(id, closures, visiting)?),
+                // The choice depends on the other packages of the resolution
+                _ => return self.closure(package_id).ok(),
+            }
+        }
+
+        // Like in the resolution the dependencies must not share a name or a blob
+        let mut names = HashSet::new();
+        let mut blobs = HashSet::new();
+        for id in closure.iter().filter(|id| *id != package_id) {
+            let name = &self.packages().get(id)?.name;
+            if !names.insert(name)
+                || !self
+                    .get_blobs_of_package(id)?
+                    .all(|blob| blobs.insert(blob))
+            {
+                return None;
+            }
+        }
+        Some(closure)
+    }
+
+    /// Resolves the requirements against the store once and returns every path through the
+    /// dependencies which leads to one of the target packages, see [DependencyGraph::paths].
+    pub fn dependency_paths<'a>(
+        &'a self,
+        requirements: impl IntoIterator<Item = &'a Requirement>,
+        targets: &[PackageId],
+    ) -> StoreResult<Vec<Vec<(&'a Requirement, PackageId)>>> {
+        let mut graph = DependencyGraph::new();
+        graph.resolve(requirements, self).context(DependencySnafu)?;
+
+        Ok(targets
+            .iter()
+            .flat_map(|target| graph.paths(target))
+            .collect())
+    }
+
+    /// Returns every path from the requirements to one of the target packages which only
+    /// goes through the given packages, like the packages of a generation.
+    /// Requirements are matched against the given packages instead of the whole store.
+    pub fn dependency_paths_within<'a>(
+        &'a self,
+        requirements: impl IntoIterator<Item = &'a Requirement>,
+        packages: &BTreeSet<PackageId>,
+        targets: &[PackageId],
+    ) -> Vec<Vec<(&'a Requirement, PackageId)>> {
+        let mut paths = Vec::new();
+        for req in requirements {
+            if let Some(id) = packages.iter().find(|id| self.is_matching(id, req)) {
+                self.collect_paths_within(req, id, packages, targets, &mut Vec::new(), &mut paths);
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    fn collect_paths_within<'a>(
+        &'a self,
+        req: &'a Requirement,
+        id: &PackageId,
+        packages: &BTreeSet<PackageId>,
+        targets: &[PackageId],
+        path: &mut Vec<(&'a Requirement, PackageId)>,
+        paths: &mut Vec<Vec<(&'a Requirement, PackageId)>>,
+    ) {
+        // Packages of a generation are not checked for cycles when they are added
+        if path.iter().any(|(_req, visited)| visited == id) {
+            return;
+        }
+
+        path.push((req, *id));
+        if targets.contains(id) {
+            paths.push(path.clone());
+        } else {
+            let requires = self
+                .packages()
+                .get(id)
+                .into_iter()
+                .flat_map(|drv| &drv.requires);
+            for req in requires {
+                if let Some(dependency) = packages.iter().find(|id| self.is_matching(id, req)) {
+                    self.collect_paths_within(req, dependency, packages, targets, path, paths);
+                }
+            }
+        }
+        path.pop();
+    }
+
+    /// Returns the packages of the closure which fulfill the requirements of the package,
+    /// which are its direct dependencies.
+    pub fn dependencies<'a>(
+        &'a self,
+        package_id: &PackageId,
+        closure: &'a BTreeSet<PackageId>,
+    ) -> impl Iterator<Item = (&'a Requirement, &'a PackageId)> + 'a {
+        self.packages()
+            .get(package_id)
+            .into_iter()
+            .flat_map(|drv| drv.requires.iter())
+            .filter_map(move |req| {
+                closure
+                    .iter()
+                    .find(|id| self.is_matching(id, req))
+                    .map(|id| (req, id))
+            })
+    }
+}
+
 impl<S, B: WriteBackend, const BAR: bool> Store<S, B, BAR> {
     pub fn objects_mut(&mut self) -> &mut Objects {
         self.backend.objects_mut()
@@ -264,6 +893,9 @@ impl<S, B: WriteBackend, const BAR: bool> Store<S, B, BAR> {
 
 enum Source {
     Local(PathBuf),
+    /// A package directory in a local cache, whose blobs are linked instead of copied
+    /// where possible if they are owned by one of the users
+    Directory(PathBuf, Vec<u32>),
     Remote(Url, Cache),
 }
 
@@ -274,331 +906,2342 @@ impl Source {
             _ => false,
         }
     }
+
+    /// Returns the source of the package under `relative` in the cache at the url.
+    /// The packages of `file://` caches are read from their directory directly and files
+    /// of the owners are linked, the store user and root by default.
+    /// Downloads from other caches are aborted after the timeout.
+    fn of_cache(
+        base: Url,
+        relative: &RelativePath,
+        timeout: Option<Duration>,
+        owners: Option<Vec<u32>>,
+    ) -> StoreResult<Self> {
+        if base.scheme() == "file" {
+            let root = base
+                .to_file_path()
+                .map_err(|_| StoreError::InvalidCachePath { url: base.clone() })?;
+            let owners =
+                owners.unwrap_or_else(|| vec![UID.unwrap_or_else(users::get_current_uid), 0]);
+            Ok(Self::Directory(relative.to_path(root), owners))
+        } else {
+            let mut builder = CacheBuilder::default();
+            if let Some(timeout) = timeout {
+                builder = builder.timeout(timeout);
+            }
+            let cache = builder.build().context(CacheSnafu)?;
+            Ok(Self::Remote(base, cache))
+        }
+    }
+
+    /// Returns the local path of a blob of the package under `relative`.
+    /// Remote blobs are downloaded into the cache first.
+    fn blob_path(&self, relative: &RelativePath, blob: &Blob) -> StoreResult<PathBuf> {
+        match self {
+            Self::Local(path) | Self::Directory(path, _) => Ok(blob.to_path(path)),
+            Self::Remote(base, cache) => {
+                let url = base.join(relative.join(&blob.path).as_str()).unwrap();
+                cache.cached_path(url.as_str()).context(CacheSnafu)
+            }
+        }
+    }
+}
+
+/// Copies a blob into the store and applies its mode.
+fn copy_blob(src: PathBuf, dest: &Path, blob: &Blob) -> StoreResult<()> {
+    fs::copy(&src, dest).context(CopyObjectSnafu {
+        kind: ObjectKind::Blob,
+        src,
+        dest,
+    })?;
+    fs::set_permissions(dest, Permissions::from_mode(blob.mode)).context(IoSnafu)
+}
+
+/// Hard links a blob of a local cache into the store if the file already has the mode
+/// of the blob and cannot be changed by anyone but the owners afterwards.
+/// Otherwise or if it is on another filesystem the blob is copied, the copy is verified
+/// together with its package.
+fn link_blob(src: PathBuf, dest: &Path, blob: &Blob, owners: &[u32]) -> StoreResult<()> {
+    let linkable = fs::metadata(&src).map_or(false, |metadata| {
+        owners.contains(&metadata.uid())
+            && metadata.mode() & 0o022 == 0
+            && metadata.mode() & 0o7777 == blob.mode
+    });
+    if linkable {
+        match fs::hard_link(&src, dest) {
+            Ok(()) => return Ok(()),
+            Err(e) => debug!("Copying {src:?} as it cannot be linked: {e}"),
+        }
+    }
+    copy_blob(src, dest, blob)
+}
+
+/// Returns true if both paths are hard links to the same file.
+fn same_file(a: &Path, b: &Path) -> StoreResult<bool> {
+    let a = fs::metadata(a).context(IoSnafu)?;
+    let b = fs::metadata(b).context(IoSnafu)?;
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// Replaces the file at `link` by a hard link to `original`.
+/// The link is created next to it first, so that the file is never missing.
+fn replace_with_link(original: &Path, link: &Path, kind: ObjectKind) -> StoreResult<()> {
+    let mut name = OsString::from(".");
+    name.push(link.file_name().unwrap_or_default());
+    name.push(".link");
+    let temp = link.with_file_name(name);
+
+    if temp.exists() {
+        fs::remove_file(&temp).context(IoSnafu)?;
+    }
+    fs::hard_link(original, &temp).context(LinkObjectsSnafu {
+        kind,
+        original,
+        link: temp.clone(),
+    })?;
+    fs::rename(&temp, link).context(IoSnafu)
+}
+
+/// Recreates a symbolic link of a package under the given root.
+fn create_link(link: &Link, root: &Path) -> StoreResult<()> {
+    let dest = link.to_path(root);
+    unix::fs::symlink(&link.target, &dest).context(LinkObjectsSnafu {
+        kind: ObjectKind::Link,
+        original: link.target.clone(),
+        link: dest,
+    })
 }
 
 impl<B: WriteBackend<Source = PathBuf> + ReadBackend<Source = PathBuf>, const BAR: bool>
     Store<PathBuf, B, BAR>
 {
-    fn get_full_object_path(&self, object_id: &ObjectId) -> Option<PathBuf> {
-        if let Some(obj) = self.objects().get(&object_id) && let Some(package_id) = self.packages().find_package_id(object_id) {
-            let package_path = self.packages().path_in_store(&package_id, &self.source);
+    /// Copies the blob of the package under `relative` from the source into the
+    /// [OBJECTS_DIR] under its id, blobs of local caches are linked where possible.
+    /// The blob is copied next to its final path first, so that an interrupted copy
+    /// never leaves a broken object behind.
+    fn store_blob(
+        &self,
+        source: &Source,
+        relative: &RelativePath,
+        id: &ObjectId,
+        blob: &Blob,
+    ) -> StoreResult<PathBuf> {
+        let object = self.object_file(id);
+        let temp = object.with_file_name(format!(".{id}"));
+        let src = source.blob_path(relative, blob)?;
 
-            package_path.map(|path| obj.to_path(path))
+        if let Source::Directory(_, owners) = source {
+            if temp.symlink_metadata().is_ok() {
+                fs::remove_file(&temp).context(IoSnafu)?;
+            }
+            link_blob(src, &temp, blob, owners)?;
         } else {
-            None
+            copy_blob(src, &temp, blob)?;
+        }
+        fs::rename(&temp, &object).context(IoSnafu)?;
+
+        Ok(object)
+    }
+
+    /// Makes the file of a package a view of the blob in the [OBJECTS_DIR].
+    /// The file is linked into the objects if the blob is not stored yet,
+    /// otherwise it is replaced by a hard link to the stored blob.
+    /// Returns true if the file was changed or stored.
+    fn share_blob(&self, id: &ObjectId, file: &Path) -> StoreResult<bool> {
+        let object = self.object_file(id);
+
+        if !object.exists() {
+            fs::hard_link(file, &object).context(LinkObjectsSnafu {
+                kind: ObjectKind::Blob,
+                original: file,
+                link: object.clone(),
+            })?;
+            Ok(true)
+        } else if !same_file(file, &object)? {
+            replace_with_link(&object, file, ObjectKind::Blob)?;
+            Ok(true)
+        } else {
+            Ok(false)
         }
     }
 
+    /// Inserts a package by populating a staging directory inside the store, which is
+    /// renamed to its final path only after it was verified. If anything fails, the
+    /// staging directory is removed and the objects added to the database are removed
+    /// again, so that the store is left untouched.
     fn insert_source(
         &mut self,
         package_id: PackageId,
         drv: Derivation,
         blobs: BTreeMap<Blob, ObjectId>,
         trees: BTreeMap<Tree, ObjectId>,
+        links: BTreeMap<Link, ObjectId>,
         absolute: PathBuf,
         source: Source,
     ) -> StoreResult<PathBuf> {
-        fs::create_dir(&absolute).context(IoSnafu)?;
-        unix::fs::chown(&absolute, UID, GID).context(IoSnafu)?;
-
-        info!("Created {absolute:?}");
+        if let Some(blob) = blobs.keys().find(|blob| blob.is_setuid()) {
+            return Err(StoreError::SetuidBlob { blob: blob.clone() });
+        }
 
         if self.packages().contains(&package_id) {
-            Ok(absolute)
-        } else {
-            let mut object_ids: HashSet<ObjectId> = HashSet::new();
-
-            // should be ordered (by BTreeMap and Object::cmp) so that trees with lower depth come first
-            for (tree, id) in trees {
-                let dest = tree.to_path(&absolute);
-                fs::create_dir(&dest).context(CreateTreeSnafu { path: dest.clone() })?;
-                // unix::fs::chown(&dest, UID, GID).context(IoSnafu)?;
-                object_ids.insert(id);
-
-                if !self.objects().contains(&id) {
-                    self.objects_mut().insert(id, tree.into());
-                }
-            }
-
-            info!("Package directories created");
-
-            let relative = drv.relative_path(&package_id);
+            return Ok(absolute);
+        }
 
-            for (blob, id) in blobs {
-                let dest = blob.to_path(&absolute);
+        let staging_root = self.source.join(STAGING_DIR);
+        let staging = drv.path_in_store(&staging_root, &package_id);
 
-                if self.objects().contains(&id) {
-                    // Object is saved under other package
-                    if let Some(original) = self.get_full_object_path(&id) {
-                        fs::hard_link(&original, &dest).context(LinkObjectsSnafu {
-                            kind: ObjectKind::Blob,
-                            original,
-                            link: dest,
-                        })?;
-                    } else {
-                        // Object is saved under current package
-                        let object = unsafe { self.objects().get_unchecked(&id) };
-                        let original = object.to_path(&absolute);
-                        if original.exists() {
-                            fs::hard_link(&original, &dest).context(LinkObjectsSnafu {
-                                kind: ObjectKind::Blob,
-                                original,
-                                link: dest,
-                            })?;
-                        } else {
-                            return Err(StoreError::ObjectNotRetrievable {
-                                object: object.clone(),
-                            });
-                        }
-                    }
-                    object_ids.insert(id);
-                } else {
-                    let source = match source {
-                        Source::Local(ref path) => blob.to_path(&path),
-                        Source::Remote(ref base, ref cache) => {
-                            let url = base.join(relative.join(&blob.path).as_str()).unwrap();
-                            let from = cache.cached_path(url.as_str()).context(CacheSnafu)?;
-                            from
-                        }
-                    };
-                    fs::copy(&source, &dest).context(CopyObjectSnafu {
-                        kind: ObjectKind::Blob,
-                        src: source,
-                        dest: dest.clone(),
-                    })?;
-                    // unix::fs::chown(&dest, UID, GID).context(IoSnafu)?;
-
-                    let old = self.objects_mut().insert(id, blob.into());
-                    assert!(old.is_none());
-                    object_ids.insert(id);
-                }
-            }
+        fs::create_dir_all(&staging_root).context(IoSnafu)?;
+        if staging.exists() {
+            // Left over by an insertion which was interrupted
+            fs::remove_dir_all(&staging).context(IoSnafu)?;
+        }
 
-            info!("Blobs copied or linked");
+        let objects_dir = self.source.join(OBJECTS_DIR);
+        if !objects_dir.exists() {
+            fs::create_dir(&objects_dir).context(IoSnafu)?;
+            unix::fs::chown(&objects_dir, UID, GID).context(IoSnafu)?;
+        }
 
-            // TODO check
-            if true || hash::verify(package_id, &absolute, &drv.name).context(IoSnafu)? {
+        let mut inserted = Vec::new();
+        let result = self
+            .populate(
+                &package_id,
+                &drv,
+                blobs,
+                trees,
+                links,
+                &staging,
+                source,
+                &mut inserted,
+            )
+            .and_then(|object_ids| {
+                fs::rename(&staging, &absolute).context(IoSnafu)?;
+                Ok(object_ids)
+            });
+
+        match result {
+            Ok(object_ids) => {
                 info!("Verified {drv}");
 
                 assert!(self
                     .packages_mut()
                     .insert(package_id, drv, object_ids)
                     .is_none());
+                self.packages_mut().set_last_used(&package_id, gc::now());
                 Ok(absolute)
-            } else {
-                fs::remove_dir_all(absolute).context(IoSnafu)?;
-                Err(StoreError::PackageNotVerified { drv })
+            }
+            Err(e) => {
+                // Failures of the cleanup are logged, the error of the insertion is returned
+                for id in &inserted {
+                    self.objects_mut().remove(id);
+                    let object = self.object_file(id);
+                    if object.exists()
+                        && let Err(e) = fs::remove_file(&object)
+                    {
+                        warn!("Could not remove the object {object:?}: {e}");
+                    }
+                }
+                if staging.exists()
+                    && let Err(e) = fs::remove_dir_all(&staging)
+                {
+                    warn!("Could not remove the staging directory {staging:?}: {e}");
+                }
+                Err(e)
             }
         }
     }
 
-    pub fn insert_remote(&mut self, source: RemotePackageSource) -> StoreResult<PathBuf> {
-        let RemotePackageSource {
-            id: package_id,
-            drv,
-            base,
-            blobs,
-            trees,
-        } = source;
+    /// Creates the package in the staging directory and verifies it.
+    /// Objects new to the store are added to the database and to `inserted`.
+    fn populate(
+        &mut self,
+        package_id: &PackageId,
+        drv: &Derivation,
+        blobs: BTreeMap<Blob, ObjectId>,
+        trees: BTreeMap<Tree, ObjectId>,
+        links: BTreeMap<Link, ObjectId>,
+        staging: &Path,
+        source: Source,
+        inserted: &mut Vec<ObjectId>,
+    ) -> StoreResult<HashSet<ObjectId>> {
+        fs::create_dir(staging).context(IoSnafu)?;
+        unix::fs::chown(staging, UID, GID).context(IoSnafu)?;
 
-        let absolute = drv.path_in_store(&self.source, &package_id);
-        let cache = CacheBuilder::default().build().context(CacheSnafu)?;
+        info!("Created {staging:?}");
 
-        self.insert_source(
-            package_id,
-            drv,
-            blobs,
-            trees,
-            absolute,
-            Source::Remote(base, cache),
-        )
-    }
+        let mut object_ids: HashSet<ObjectId> = HashSet::new();
 
-    /// Inserts a package into the store and returns true if the package was not present and was inserted
-    /// and false if it was already present.
-    pub fn insert(&mut self, source: LocalPackageSource) -> StoreResult<PathBuf> {
-        let LocalPackageSource { drv, path } = source;
+        // should be ordered (by BTreeMap and Object::cmp) so that trees with lower depth come first
+        for (tree, id) in trees {
+            let dest = tree.to_path(staging);
+            fs::create_dir(&dest).context(CreateTreeSnafu { path: dest.clone() })?;
+            // unix::fs::chown(&dest, UID, GID).context(IoSnafu)?;
+            object_ids.insert(id);
 
-        let PackageHash {
-            root: package_id,
-            trees,
-            blobs,
-        } = PackageHash::from_path(&path, &drv.name).context(IoSnafu)?;
+            if !self.objects().contains(&id) {
+                self.objects_mut().insert(id, tree.into());
+                inserted.push(id);
+            }
+        }
 
-        let absolute = drv.path_in_store(&self.source, &package_id);
+        info!("Package directories created");
 
-        self.insert_source(package_id, drv, blobs, trees, absolute, Source::Local(path))
-    }
+        let relative = drv.relative_path(package_id);
 
-    pub fn extend<'a>(
-        &'a mut self,
-        packages: impl IntoIterator<Item = LocalPackageSource> + 'a,
+        for (blob, id) in blobs {
+            let dest = blob.to_path(staging);
+
+            // Every blob is stored once and linked into the packages which contain it
+            let mut object = self.object_file(&id);
+            if !object.exists() {
+                object = self.store_blob(&source, &relative, &id, &blob)?;
+            }
+            fs::hard_link(&object, &dest).context(LinkObjectsSnafu {
+                kind: ObjectKind::Blob,
+                original: object,
+                link: dest,
+            })?;
+
+            if !self.objects().contains(&id) {
+                self.objects_mut().insert(id, blob.into());
+                inserted.push(id);
+            }
+            object_ids.insert(id);
+        }
+
+        info!("Blobs copied or linked");
+
+        for (link, id) in links {
+            create_link(&link, staging)?;
+            object_ids.insert(id);
+
+            if !self.objects().contains(&id) {
+                self.objects_mut().insert(id, link.into());
+                inserted.push(id);
+            }
+        }
+
+        if hash::verify(*package_id, staging, &drv.name).context(VerifyIoSnafu)? {
+            Ok(object_ids)
+        } else {
+            Err(StoreError::PackageNotVerified { drv: drv.clone() })
+        }
+    }
+
+    /// Links the blob in the [OBJECTS_DIR] into every package which contains it again.
+    /// The path is part of the [ObjectId], so the blob is at the same path in every package.
+    fn relink_object(&self, object_id: &ObjectId) -> StoreResult<()> {
+        let object = self
+            .objects()
+            .get(object_id)
+            .ok_or(StoreError::ObjectNotFoundById { id: *object_id })?;
+        let original = self.object_file(object_id);
+
+        for package_id in self.packages().owners(object_id) {
+            let drv = unsafe { self.packages().get_unchecked(package_id) };
+            let link = object.to_path(drv.path_in_store(&self.source, package_id));
+
+            if link.exists() {
+                replace_with_link(&original, &link, object.kind())?;
+            } else if link.parent().map_or(false, Path::exists) {
+                fs::hard_link(&original, &link).context(LinkObjectsSnafu {
+                    kind: object.kind(),
+                    original: &original,
+                    link,
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn repair_source(
+        &mut self,
+        package_id: &PackageId,
+        source: Source,
+    ) -> StoreResult<PackageReport> {
+        let mut report = self.verify_package(package_id)?;
+        if report.is_ok() {
+            return Ok(report);
+        }
+
+        if !report.modes.is_empty() {
+            let root = unsafe {
+                self.packages()
+                    .path_in_store(package_id, &self.source)
+                    .unwrap_unchecked()
+            };
+            for blob in report.modes.keys() {
+                fs::set_permissions(blob.to_path(&root), Permissions::from_mode(blob.mode))
+                    .context(IoSnafu)?;
+            }
+
+            // The contents of a blob might have changed as well
+            report = self.verify_package(package_id)?;
+            if report.is_ok() {
+                return Ok(report);
+            }
+        }
+
+        let drv = unsafe { self.packages().get_unchecked(package_id) };
+        let root = drv.path_in_store(&self.source, package_id);
+        let relative = drv.relative_path(package_id);
+
+        if !root.exists() {
+            fs::create_dir(&root).context(IoSnafu)?;
+            unix::fs::chown(&root, UID, GID).context(IoSnafu)?;
+        }
+
+        let object_ids = unsafe { self.packages().get_children(package_id).unwrap_unchecked() };
+        let mut trees = self
+            .objects()
+            .get_trees_cloned(object_ids)
+            .collect::<Vec<_>>();
+        trees.sort();
+
+        for tree in trees {
+            let dest = tree.to_path(&root);
+            if !dest.exists() {
+                fs::create_dir(&dest).context(CreateTreeSnafu { path: dest.clone() })?;
+            }
+        }
+
+        for path in &report.extra {
+            // A file whose object is missing in the database still belongs to the package
+            match hash::hash_path(&root, path) {
+                Ok(id) if object_ids.contains(&id) => {
+                    warn!("Keeping {path}, it is part of the package but not in the objects");
+                }
+                _ => fs::remove_file(path.to_path(&root)).context(IoSnafu)?,
+            }
+        }
+
+        for link in report.links.keys() {
+            let dest = link.to_path(&root);
+            if dest.is_symlink() {
+                fs::remove_file(&dest).context(IoSnafu)?;
+            }
+            create_link(link, &root)?;
+        }
+
+        for (blob, id) in report.broken_blobs() {
+            // A modified blob is modified in the objects and every package sharing it,
+            // so it is stored again and linked into all of them
+            self.store_blob(&source, &relative, id, blob)?;
+            self.relink_object(id)?;
+        }
+
+        let report = self.verify_package(package_id)?;
+        if !report.is_ok() {
+            return Err(StoreError::PackageNotRepaired { report });
+        }
+        info!("Repaired {root:?}");
+
+        Ok(report)
+    }
+
+    /// Repairs a package by restoring its missing and modified blobs
+    /// from the package files under the given path.
+    /// Returns the [PackageReport] of the repaired package,
+    /// fails with [StoreError::PackageNotRepaired] if it is still corrupted afterwards.
+    pub fn repair(&mut self, package_id: &PackageId, path: PathBuf) -> StoreResult<PackageReport> {
+        self.repair_source(package_id, Source::Local(path))
+    }
+
+    /// Repairs a package by downloading its missing and modified blobs from a remote store.
+    /// Returns the [PackageReport] of the repaired package,
+    /// fails with [StoreError::PackageNotRepaired] if it is still corrupted afterwards.
+    pub fn repair_remote(&mut self, source: RemotePackageSource) -> StoreResult<PackageReport> {
+        let relative = source.drv.relative_path(&source.id);
+
+        let from = Source::of_cache(source.base, &relative, source.timeout, source.owners)?;
+        self.repair_source(&source.id, from)
+    }
+
+    pub fn insert_remote(&mut self, source: RemotePackageSource) -> StoreResult<PathBuf> {
+        let RemotePackageSource {
+            id: package_id,
+            drv,
+            base,
+            blobs,
+            trees,
+            links,
+            timeout,
+            owners,
+        } = source;
+
+        let absolute = drv.path_in_store(&self.source, &package_id);
+        let source = Source::of_cache(base, &drv.relative_path(&package_id), timeout, owners)?;
+
+        self.insert_source(package_id, drv, blobs, trees, links, absolute, source)
+    }
+
+    /// Inserts a package into the store and returns true if the package was not present and was inserted
+    /// and false if it was already present.
+    pub fn insert(&mut self, source: LocalPackageSource) -> StoreResult<PathBuf> {
+        let LocalPackageSource { drv, path } = source;
+
+        let PackageHash {
+            root: package_id,
+            trees,
+            blobs,
+            links,
+            ..
+        } = PackageHash::from_path(&path, &drv.name).context(IoSnafu)?;
+
+        let absolute = drv.path_in_store(&self.source, &package_id);
+
+        self.insert_source(
+            package_id,
+            drv,
+            blobs,
+            trees,
+            links,
+            absolute,
+            Source::Local(path),
+        )
+    }
+
+    /// Registers a package directory which is already inside the store,
+    /// e.g. an orphan reported by [Store::check], without copying its files.
+    /// The directory has to be named after the derivation and its calculated [PackageId].
+    pub fn adopt(&mut self, path: &Path, drv: Derivation) -> StoreResult<PackageId> {
+        let PackageHash {
+            root: package_id,
+            trees,
+            blobs,
+            links,
+            ..
+        } = PackageHash::from_path(path, &drv.name).context(IoSnafu)?;
+
+        if drv.path_in_store(&self.source, &package_id) != path {
+            return Err(StoreError::PackageNotVerified { drv });
+        }
+
+        if self.packages().contains(&package_id) {
+            return Ok(package_id);
+        }
+
+        let mut object_ids = HashSet::new();
+
+        for (tree, id) in trees {
+            if !self.objects().contains(&id) {
+                self.objects_mut().insert(id, tree.into());
+            }
+            object_ids.insert(id);
+        }
+
+        for (blob, id) in blobs {
+            self.share_blob(&id, &blob.to_path(path))?;
+            if !self.objects().contains(&id) {
+                self.objects_mut().insert(id, blob.into());
+            }
+            object_ids.insert(id);
+        }
+
+        for (link, id) in links {
+            if !self.objects().contains(&id) {
+                self.objects_mut().insert(id, link.into());
+            }
+            object_ids.insert(id);
+        }
+
+        info!("Adopted {path:?}");
+
+        self.packages_mut().insert(package_id, drv, object_ids);
+        self.packages_mut().set_last_used(&package_id, gc::now());
+        Ok(package_id)
+    }
+
+    /// Reconciles the database of the store with the store directory.
+    /// Removes orphan directories and packages whose directory is missing,
+    /// restores dangling objects by hashing the package directory again and
+    /// drops objects not referenced by any package.
+    /// Returns the [StoreReport] of the inconsistencies found before fixing them.
+    pub fn fix(&mut self) -> StoreResult<StoreReport> {
+        let report = self.check()?;
+
+        for path in &report.orphans {
+            if path.is_dir() {
+                fs::remove_dir_all(path).context(IoSnafu)?;
+            } else {
+                fs::remove_file(path).context(IoSnafu)?;
+            }
+            info!("Removed orphan {path:?}");
+        }
+
+        for id in &report.dangling_packages {
+            self.packages_mut().remove(id);
+            info!("Removed dangling package {id}");
+        }
+
+        for (package_id, missing) in &report.dangling_objects {
+            if report.dangling_packages.contains(package_id) {
+                continue;
+            }
+
+            let drv = unsafe { self.packages().get_unchecked(package_id) };
+            let PackageHash {
+                trees,
+                blobs,
+                links,
+                ..
+            } = PackageHash::from_path(drv.path_in_store(&self.source, package_id), &drv.name)
+                .context(IoSnafu)?;
+
+            let mut found = trees
+                .into_iter()
+                .map(|(tree, id)| (id, tree.into()))
+                .chain(blobs.into_iter().map(|(blob, id)| (id, blob.into())))
+                .chain(links.into_iter().map(|(link, id)| (id, link.into())))
+                .filter(|(id, _object)| missing.contains(id))
+                .collect::<BTreeMap<ObjectId, Object>>();
+
+            for id in missing {
+                if let Some(object) = found.remove(id) {
+                    self.objects_mut().insert(*id, object);
+                } else {
+                    self.packages_mut().remove_child(package_id, id);
+                    warn!("Dropped object {id} of package {package_id}");
+                }
+            }
+        }
+
+        // Removing dangling packages may leave further objects unreferenced
+        let unreferenced = self
+            .objects()
+            .iter()
+            .map(|(id, _object)| *id)
+            .filter(|id| self.packages().reference_count(id) == 0)
+            .collect::<Vec<_>>();
+
+        for id in unreferenced {
+            self.remove_object(&id)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Imports all packages of an archive created by [Store::export].
+    /// The contents of every package are hashed and checked against its [PackageId]
+    /// before the first package is inserted. Packages already present are skipped,
+    /// archives with a name or version which is not a single path component are rejected.
+    /// Returns the ids of the imported packages.
+    pub fn import(&mut self, path: &Path) -> StoreResult<Vec<PackageId>> {
+        let temp_dir = TempDir::new().context(IoSnafu)?;
+        archive::unpack(path, temp_dir.path()).context(IoSnafu)?;
+
+        let database = fs::read(temp_dir.child(PACKAGES_DB)).context(IoSnafu)?;
+        let (_objects, packages): (Objects, Packages) =
+            pot::from_slice(&database).context(PotSnafu)?;
+
+        // Names and versions become directories of the store and must not escape it
+        if let Some((_id, drv, _objects)) =
+            packages.iter().find(|(_, drv, _)| !drv.has_valid_path())
+        {
+            return InvalidPackagePathSnafu {
+                name: drv.name.clone(),
+                version: drv.version.to_string(),
+            }
+            .fail();
+        }
+
+        // The whole archive is verified before anything is inserted
+        let mut verified = Vec::new();
+        for (id, drv, _objects) in packages.iter() {
+            if self.packages().contains(id) {
+                info!("{drv} already present");
+                continue;
+            }
+
+            let src = drv.path_in_store(temp_dir.path(), id);
+            let hash = PackageHash::from_path(&src, &drv.name).context(VerifyIoSnafu)?;
+            if hash.root != *id {
+                return Err(StoreError::PackageNotVerified { drv: drv.clone() });
+            }
+            verified.push((drv, src, hash));
+        }
+
+        let mut imported = Vec::new();
+
+        for (drv, src, hash) in verified {
+            let PackageHash {
+                root: package_id,
+                trees,
+                blobs,
+                links,
+                ..
+            } = hash;
+
+            let absolute = drv.path_in_store(&self.source, &package_id);
+            if let Err(e) = self.insert_source(
+                package_id,
+                drv.clone(),
+                blobs,
+                trees,
+                links,
+                absolute,
+                Source::Local(src),
+            ) {
+                self.roll_back(&imported);
+                return Err(e);
+            }
+            imported.push(package_id);
+        }
+
+        Ok(imported)
+    }
+
+    /// Removes the packages inserted before a later package of the same batch failed,
+    /// so that a batch is either inserted completely or not at all.
+    fn roll_back(&mut self, inserted: &[PackageId]) {
+        for id in inserted.iter().rev() {
+            if let Err(e) = self.remove_package(id) {
+                warn!("Could not remove the partially inserted package {id}: {e}");
+            }
+        }
+    }
+
+    /// Copies the packages from another store, packages already present are skipped.
+    /// The objects of every package are taken from the source returned for it.
+    /// If a package fails the packages copied before it are removed again.
+    /// Returns the ids of the copied packages.
+    fn copy_packages<'a, S, F: ReadBackend>(
+        &mut self,
+        from: &Store<S, F>,
+        package_ids: impl IntoIterator<Item = &'a PackageId>,
+        source: impl Fn(&PackageId, &Derivation) -> StoreResult<Source>,
+    ) -> StoreResult<Vec<PackageId>> {
+        let mut copied = Vec::new();
+
+        for id in package_ids {
+            let (drv, ids) = from
+                .packages()
+                .get_full(id)
+                .context(PackageNotFoundByIdSnafu { id: *id })?;
+            if self.packages().contains(id) {
+                info!("{drv} already present");
+                continue;
+            }
+
+            let blobs = from.objects().get_blobs_ids_cloned(ids).collect();
+            let trees = from.objects().get_trees_ids_cloned(ids).collect();
+            let links = from.objects().get_links_ids_cloned(ids).collect();
+
+            let absolute = drv.path_in_store(&self.source, id);
+            let result = source(id, drv).and_then(|source| {
+                self.insert_source(*id, drv.clone(), blobs, trees, links, absolute, source)
+            });
+            if let Err(e) = result {
+                self.roll_back(&copied);
+                return Err(e);
+            }
+            copied.push(*id);
+        }
+
+        Ok(copied)
+    }
+
+    /// Copies the package together with its closure from another local store.
+    /// Packages already present are skipped and blobs already in the objects are
+    /// linked instead of copied. Returns the ids of the copied packages.
+    pub fn copy_from<F: ReadBackend<Source = PathBuf>>(
+        &mut self,
+        from: &Store<PathBuf, F>,
+        package_id: &PackageId,
+    ) -> StoreResult<Vec<PackageId>> {
+        let closure = from.closure(package_id)?;
+        self.copy_packages(from, &closure, |id, drv| {
+            Ok(Source::Local(drv.path_in_store(from.path(), id)))
+        })
+    }
+
+    /// Copies the package together with its closure from a cache,
+    /// only blobs which are not in the objects yet are downloaded, see [Store::copy_from].
+    /// Downloads are aborted after the timeout.
+    pub fn copy_from_remote(
+        &mut self,
+        from: &RemoteStore,
+        package_id: &PackageId,
+        timeout: Option<Duration>,
+    ) -> StoreResult<Vec<PackageId>> {
+        let closure = from.closure(package_id)?;
+        self.copy_packages(from, &closure, |id, drv| {
+            Source::of_cache(from.url().clone(), &drv.relative_path(id), timeout, None)
+        })
+    }
+
+    /// Recalculates the ids of all packages if the store was created with an older
+    /// [HASH_VERSION] and renames the package directories accordingly.
+    /// All packages are hashed before the first directory is renamed and failed renames
+    /// are undone, missing packages keep their id.
+    /// Returns the new [PackageId] of every package whose id changed.
+    pub fn migrate(&mut self) -> StoreResult<BTreeMap<PackageId, PackageId>> {
+        let mut migrated = BTreeMap::new();
+
+        if self.packages().hash_version() == HASH_VERSION {
+            return Ok(migrated);
+        }
+
+        let packages = self
+            .packages()
+            .iter()
+            .map(|(id, drv, objects)| (*id, drv.clone(), objects.clone()))
+            .collect::<Vec<_>>();
+        let mut objects = Objects::new();
+        let mut rehashed = Vec::new();
+
+        // Every package is hashed before anything on disk is changed
+        for (old_id, drv, old_objects) in packages {
+            let old_path = drv.path_in_store(&self.source, &old_id);
+            if !old_path.exists() {
+                warn!("Package {old_path:?} is missing, its id is kept, run fsck to remove it");
+                for id in &old_objects {
+                    if let Some(object) = self.objects().get(id) {
+                        objects.insert(*id, object.clone());
+                    }
+                }
+                continue;
+            }
+
+            let PackageHash {
+                root: package_id,
+                trees,
+                blobs,
+                links,
+                ..
+            } = PackageHash::from_path(&old_path, &drv.name).context(IoSnafu)?;
+            rehashed.push((old_id, package_id, drv, trees, blobs, links));
+        }
+
+        let mut renamed = Vec::new();
+        for (old_id, package_id, drv, ..) in &rehashed {
+            if package_id == old_id {
+                continue;
+            }
+            let old_path = drv.path_in_store(&self.source, old_id);
+            let path = drv.path_in_store(&self.source, package_id);
+            if let Err(e) = fs::rename(&old_path, &path) {
+                for (path, old_path) in renamed.into_iter().rev() {
+                    if let Err(e) = fs::rename(&path, &old_path) {
+                        warn!("Could not move {path:?} back to {old_path:?}: {e}");
+                    }
+                }
+                return Err(e).context(IoSnafu);
+            }
+            renamed.push((path, old_path));
+        }
+        for (path, old_path) in renamed {
+            info!("Migrated {old_path:?} to {path:?}");
+        }
+
+        for (old_id, package_id, drv, trees, blobs, links) in rehashed {
+            let path = drv.path_in_store(&self.source, &package_id);
+            if package_id != old_id {
+                migrated.insert(old_id, package_id);
+            }
+
+            // Older stores did not normalize the modes
+            for blob in blobs.keys() {
+                fs::set_permissions(blob.to_path(&path), Permissions::from_mode(blob.mode))
+                    .context(IoSnafu)?;
+            }
+
+            let object_ids = trees
+                .into_iter()
+                .map(|(tree, id)| (id, tree.into()))
+                .chain(blobs.into_iter().map(|(blob, id)| (id, blob.into())))
+                .chain(links.into_iter().map(|(link, id)| (id, link.into())))
+                .map(|(id, object)| {
+                    objects.insert(id, object);
+                    id
+                })
+                .collect::<HashSet<_>>();
+
+            let last_used = self.packages().last_used(&old_id);
+            self.packages_mut().remove(&old_id);
+            self.packages_mut().insert(package_id, drv, object_ids);
+            if let Some(time) = last_used {
+                self.packages_mut().set_last_used(&package_id, time);
+            }
+        }
+
+        // Changed one by one, so that the backend only writes the changed objects
+        let obsolete = self
+            .objects()
+            .iter()
+            .filter(|(id, _object)| !objects.contains(id))
+            .map(|(id, _object)| *id)
+            .collect::<Vec<_>>();
+        for id in &obsolete {
+            self.objects_mut().remove(id);
+        }
+        for (id, object) in objects.iter() {
+            self.objects_mut().insert(*id, object.clone());
+        }
+        self.packages_mut().set_hash_version(HASH_VERSION);
+
+        Ok(migrated)
+    }
+
+    /// Moves the blobs of all packages into the [OBJECTS_DIR] for stores created before
+    /// it existed, so that every package becomes a view of the stored blobs.
+    /// Identical blobs of different packages are linked to the same stored blob.
+    /// Returns the number of files which were stored or linked again.
+    pub fn migrate_objects(&mut self) -> StoreResult<usize> {
+        let objects = self.source.join(OBJECTS_DIR);
+        if !objects.exists() {
+            fs::create_dir(&objects).context(IoSnafu)?;
+            unix::fs::chown(&objects, UID, GID).context(IoSnafu)?;
+        }
+
+        let mut changed = 0;
+
+        for (id, drv, object_ids) in self.packages().iter() {
+            let root = drv.path_in_store(&self.source, id);
+            if !root.exists() {
+                continue;
+            }
+
+            // Modified blobs are left to repair
+            let PackageHash { blobs, .. } =
+                PackageHash::from_path(&root, &drv.name).context(IoSnafu)?;
+            for (blob, object_id) in blobs.iter().filter(|(_blob, id)| object_ids.contains(id)) {
+                if self.share_blob(object_id, &blob.to_path(&root))? {
+                    changed += 1;
+                }
+            }
+        }
+
+        if changed > 0 {
+            info!("Moved {changed} blobs into {objects:?}");
+        }
+
+        Ok(changed)
+    }
+
+    pub fn extend<'a>(
+        &'a mut self,
+        packages: impl IntoIterator<Item = LocalPackageSource> + 'a,
     ) -> impl Iterator<Item = StoreResult<PathBuf>> + 'a {
         packages.into_iter().map(|src| self.insert(src))
     }
 
-    /// Remove all packages that are currently unused in all generations.
-    pub fn remove_unused(&mut self, user_manager: &UserManager) -> StoreResult<Vec<PackageId>> {
-        let used_packages = user_manager.packages().collect::<HashSet<&PackageId>>();
+    /// Returns the packages used by any generation of all users and the packages kept alive
+    /// by a root of the [GcRoots] together with their closure.
+    fn live_packages(&self, user_manager: &UserManager) -> StoreResult<HashSet<PackageId>> {
+        let mut used_packages = user_manager.packages().copied().collect::<HashSet<_>>();
+
+        for root in self.gc_roots().list(self)? {
+            if let Some(package_id) = root.package {
+                match self.closure(&package_id) {
+                    Ok(closure) => used_packages.extend(closure),
+                    Err(e) => {
+                        warn!("Keeping only {package_id} of root {:?}: {e}", root.path);
+                        used_packages.insert(package_id);
+                    }
+                }
+            }
+        }
+
+        Ok(used_packages)
+    }
+
+    /// Remove all packages that are currently unused in all generations
+    /// and not kept alive by a root of the [GcRoots], stale roots are pruned first.
+    pub fn remove_unused(&mut self, user_manager: &UserManager) -> StoreResult<Vec<PackageId>> {
+        let report = self.collect_garbage(user_manager, GcPolicy::default(), false)?;
+        Ok(report.ids().copied().collect())
+    }
+
+    /// Returns the recorded time the package was last used, otherwise the modification time
+    /// of its directory or 0 if it is missing.
+    fn last_used_or_modified(&self, id: &PackageId, drv: &Derivation) -> u64 {
+        self.packages().last_used(id).unwrap_or_else(|| {
+            fs::metadata(drv.path_in_store(&self.source, id))
+                .map_or(0, |metadata| metadata.mtime().max(0) as u64)
+        })
+    }
+
+    /// Records the packages as used now. Packages leaving a generation are recorded, so that
+    /// their age is counted by [Store::collect_garbage] from the time they became unused.
+    pub fn mark_used<'a>(&mut self, package_ids: impl IntoIterator<Item = &'a PackageId>) {
+        let now = gc::now();
+        for id in package_ids {
+            self.packages_mut().set_last_used(id, now);
+        }
+    }
+
+    /// Removes the packages unused in all generations and not kept alive by a root of the
+    /// [GcRoots] as the policy allows, the least recently used first.
+    /// Packages unused for longer than [GcPolicy::older_than] are removed, afterwards further
+    /// packages until the store is not larger than [GcPolicy::max_size].
+    ///
+    /// The time of use of the live packages is recorded on every collection, packages
+    /// without a recorded time count as used when their directory was last modified.
+    /// With `dry_run` nothing is changed and the report lists what would be freed.
+    pub fn collect_garbage(
+        &mut self,
+        user_manager: &UserManager,
+        policy: GcPolicy,
+        dry_run: bool,
+    ) -> StoreResult<GcReport> {
+        if !dry_run {
+            for root in self.gc_roots().prune(self)? {
+                info!("Pruned stale root {:?}", root.path);
+            }
+        }
+        let live = self.live_packages(user_manager)?;
+        let now = gc::now();
+
+        let mut unused = self
+            .packages()
+            .filter(|id, _drv, _objects| !live.contains(id))
+            .map(|(id, drv, _objects)| (self.last_used_or_modified(id, drv), *id, drv))
+            .collect::<Vec<_>>();
+        unused.sort_by_key(|(last_used, id, _drv)| (*last_used, *id));
+
+        let size_before = self.size_of(self.packages().iter().map(|(id, _drv, _objects)| id));
+        let mut report = GcReport {
+            size_before,
+            size_after: size_before,
+            ..Default::default()
+        };
+        // The packages which still contain each object once the collected ones are removed
+        let mut references = HashMap::<ObjectId, usize>::new();
+
+        for (last_used, id, drv) in unused {
+            let expired = policy
+                .older_than
+                .map_or(false, |age| now.saturating_sub(last_used) > age.as_secs());
+            let too_large = policy
+                .max_size
+                .map_or(false, |size| report.size_after > size.bytes());
+            if !(policy.is_unlimited() || expired || too_large) {
+                continue;
+            }
+
+            let mut freed_size = 0;
+            for object in self.packages().get_children(&id).into_iter().flatten() {
+                let count = references
+                    .entry(*object)
+                    .or_insert_with(|| self.packages().reference_count(object));
+                *count -= 1;
+
+                if *count == 0
+                    && let Some(blob) = self.objects().get(object).and_then(Object::as_blob)
+                {
+                    freed_size += self.blob_size(object, blob);
+                }
+            }
+
+            report.size_after = report.size_after.saturating_sub(freed_size);
+            report.packages.push(GcPackage {
+                id,
+                name: drv.name.clone(),
+                version: drv.version.to_string(),
+                freed_size,
+                last_used,
+            });
+        }
+
+        if dry_run {
+            return Ok(report);
+        }
+
+        if BAR {
+            let mut bar = ProgressBar::new(report.packages.len() as u64);
+
+            for package_id in report.ids() {
+                self.remove_package(package_id)?;
+                bar.inc(1);
+            }
+
+            bar.finish("Unused packages removed");
+        } else {
+            for package_id in report.ids() {
+                self.remove_package(package_id)?;
+            }
+        }
+
+        let used = self
+            .packages()
+            .iter()
+            .map(|(id, _drv, _objects)| *id)
+            .filter(|id| live.contains(id) || self.packages().last_used(id).is_none())
+            .collect::<Vec<_>>();
+        for id in used {
+            self.packages_mut().set_last_used(&id, now);
+        }
+
+        Ok(report)
+    }
+
+    /// Removes the directory of the package and the package from the database.
+    /// Its objects are only removed once no other package references them.
+    fn remove_package(&mut self, package_id: &PackageId) -> StoreResult<()> {
+        let root = self
+            .packages()
+            .path_in_store(package_id, &self.source)
+            .ok_or(StoreError::PackageNotFoundById { id: *package_id })?;
+        fs::remove_dir_all(root).context(IoSnafu)?;
+
+        let (_drv, objects) = unsafe { self.packages_mut().remove(package_id).unwrap_unchecked() };
+        for id in objects {
+            if self.packages().reference_count(&id) == 0 {
+                self.remove_object(&id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the object from the database and its file from the [OBJECTS_DIR].
+    fn remove_object(&mut self, id: &ObjectId) -> StoreResult<()> {
+        let object = self.object_file(id);
+        if object.exists() {
+            fs::remove_file(object).context(IoSnafu)?;
+        }
+        self.objects_mut().remove(id);
+
+        Ok(())
+    }
+
+    /// Flushes all data to the backend
+    pub fn flush(self) -> StoreResult<()> {
+        self.backend.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        archive, BackendKind, Blob, LocalStore, Packages, Source, StoreError, OBJECTS_DIR,
+        PACKAGES_DB, PACKAGES_SQLITE, STAGING_DIR,
+    };
+    use crate::{
+        extra::{
+            hash::{self, PackageHash, HASH_VERSION},
+            lock::LockMode,
+            path::ComponentPathBuf,
+        },
+        store::{
+            gc::{self, GcPolicy},
+            id::{ObjectId, PackageId},
+        },
+        support::*,
+        user::UserManager,
+    };
+    use relative_path::RelativePathBuf;
+    use std::assert_matches::assert_matches;
+    use std::{
+        collections::BTreeSet,
+        fs::{self, File, Permissions},
+        os::unix::{
+            self,
+            fs::{MetadataExt, PermissionsExt},
+        },
+        path::Path,
+        thread,
+        time::{Duration, SystemTime},
+    };
+    use temp_dir::TempDir;
+
+    fn store_create_at_path(path: &Path) -> LocalStore {
+        let store = LocalStore::init(&path).unwrap();
+
+        let store_db = path.join(PACKAGES_DB);
+
+        assert!(store_db.exists());
+        assert!(store_db.is_file());
+
+        store
+    }
+
+    #[test]
+    fn store_create_at_path_ok() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let _store = store_create_at_path(&path);
+    }
+
+    #[test]
+    fn store_create_at_path_err() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        fs::create_dir(&path).unwrap();
+
+        let res = LocalStore::init(&path);
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn store_open_ok() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        store_create_at_path(&path).flush().unwrap();
+
+        let _store = LocalStore::open(path).unwrap();
+    }
+
+    #[test]
+    fn store_open_err() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+
+        let res = LocalStore::open(path);
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn store_insert() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+        let package_store_path = package.path_in_store(store.path(), &package_id);
+
+        let _ = store.insert(package).unwrap();
+
+        assert!(package_store_path.exists());
+        assert!(package_store_path.is_dir());
+
+        let package_store_file = package_store_path.join("lib/package.so");
+
+        assert!(package_store_file.exists());
+        assert!(package_store_file.is_file());
+    }
+
+    #[test]
+    fn store_remove_unused() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let one_path = temp_dir.child("one");
+        let two_path = temp_dir.child("two");
+        let user_manager_path = temp_dir.child("user");
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", &one_path);
+        let two = pkg("two", &two_path);
+
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+        let one_req = req("one", ">0.0.0");
+
+        store.insert(one).unwrap();
+        store.insert(two).unwrap();
+
+        let global_temp = temp_dir.child("global");
+        fs::create_dir(&global_temp).unwrap();
+        let global_paths = ComponentPathBuf::from_path(&global_temp);
+        global_paths.create_dirs(false).unwrap();
+
+        let mut user_manager = UserManager::init(&user_manager_path).unwrap();
+        assert!(user_manager
+            .insert_requirement(one_req, &mut store, &global_paths)
+            .unwrap());
+
+        let removed = store.remove_unused(&mut user_manager).unwrap();
+
+        assert_eq!(removed.len(), 1);
+        assert!(removed.contains(&two_id));
+    }
+
+    #[test]
+    fn store_remove_unused_gc_roots() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let user_manager = UserManager::init(&temp_dir.child("user")).unwrap();
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", temp_dir.child("one"));
+        let two = pkg_req("two", temp_dir.child("two"), [req("one", ">0.0.0")]);
+        let three = pkg("three", temp_dir.child("three"));
+        let four = pkg("four", temp_dir.child("four"));
+        let ids = [&one, &two, &three, &four]
+            .map(|source| hash::root_hash(&source.path, source.name()).unwrap());
+        let [one_id, two_id, three_id, four_id] = ids;
+        let [_one, two, three, four] =
+            [one, two, three, four].map(|source| store.insert(source).unwrap());
+
+        let roots = store.gc_roots();
+        assert_eq!(roots.path(), temp_dir.child("gcroots"));
+
+        // Two and its dependency are kept by a result link, three by a running process
+        let result = temp_dir.child("result");
+        unix::fs::symlink(&two, &result).unwrap();
+        roots.add_link(&result).unwrap();
+        roots.add_process(std::process::id(), &three).unwrap();
+
+        // The roots of four are stale
+        let removed_link = temp_dir.child("removed");
+        unix::fs::symlink(&four, &removed_link).unwrap();
+        roots.add_link(&removed_link).unwrap();
+        fs::remove_file(&removed_link).unwrap();
+        roots.add_process(u32::MAX, &four).unwrap();
+        // Entries which are no links are skipped
+        fs::write(roots.path().join("not-a-link"), "").unwrap();
+
+        let list = roots.list(&store).unwrap();
+        assert_eq!(list.len(), 4);
+        assert_eq!(list.iter().filter(|root| root.is_stale()).count(), 2);
+
+        assert_eq!(store.remove_unused(&user_manager).unwrap(), [four_id]);
+        let list = roots.list(&store).unwrap();
+        assert_eq!(
+            list.iter()
+                .map(|root| root.package)
+                .collect::<BTreeSet<_>>(),
+            BTreeSet::from([Some(two_id), Some(three_id)])
+        );
+
+        fs::remove_file(&result).unwrap();
+        roots.remove_process(std::process::id()).unwrap();
+        let removed = store.remove_unused(&user_manager).unwrap();
+        assert_eq!(
+            removed.into_iter().collect::<BTreeSet<_>>(),
+            BTreeSet::from([one_id, two_id, three_id])
+        );
+        assert!(roots.list(&store).unwrap().is_empty());
+        assert!(roots.path().join("not-a-link").exists());
+    }
+
+    #[test]
+    fn store_collect_garbage() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let user_manager = UserManager::init(&temp_dir.child("user")).unwrap();
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", temp_dir.child("one"));
+        fs::write(one.path.join("lib/one.so"), "123456").unwrap();
+        fs::write(one.path.join("lib/shared.so"), "abcd").unwrap();
+        let two = pkg("two", temp_dir.child("two"));
+        fs::write(two.path.join("lib/two.so"), "12").unwrap();
+        fs::write(two.path.join("lib/shared.so"), "abcd").unwrap();
+        let three = pkg("three", temp_dir.child("three"));
+        fs::write(three.path.join("lib/three.so"), "xyz").unwrap();
+        let ids = [&one, &two, &three]
+            .map(|source| hash::root_hash(&source.path, source.name()).unwrap());
+        let [one_id, two_id, three_id] = ids;
+        for source in [one, two, three] {
+            store.insert(source).unwrap();
+        }
+
+        let now = gc::now();
+        assert!(store.packages().last_used(&three_id).unwrap() >= now - 1);
+        let day = 24 * 60 * 60;
+        store.packages_mut().set_last_used(&one_id, now - 10 * day);
+        store.packages_mut().set_last_used(&two_id, now - 3 * day);
+
+        let policy = |older_than: Option<&str>, max_size: Option<&str>| GcPolicy {
+            older_than: older_than.map(|age| age.parse().unwrap()),
+            max_size: max_size.map(|size| size.parse().unwrap()),
+        };
+
+        // The shared blob is only freed together with the second package
+        let report = store
+            .collect_garbage(&user_manager, policy(Some("7d"), None), true)
+            .unwrap();
+        assert_eq!(report.ids().collect::<Vec<_>>(), [&one_id]);
+        assert_eq!(report.packages[0].freed_size, 6);
+        assert_eq!((report.size_before, report.size_after), (15, 9));
+
+        let report = store
+            .collect_garbage(&user_manager, policy(None, Some("8")), true)
+            .unwrap();
+        assert_eq!(report.ids().collect::<Vec<_>>(), [&one_id, &two_id]);
+        assert_eq!(report.packages[1].freed_size, 6);
+        assert_eq!(report.size_after, 3);
+
+        let report = store
+            .collect_garbage(&user_manager, policy(Some("7d"), Some("12")), true)
+            .unwrap();
+        assert_eq!(report.ids().collect::<Vec<_>>(), [&one_id]);
+
+        let report = store
+            .collect_garbage(&user_manager, policy(None, None), true)
+            .unwrap();
+        assert_eq!(report.packages.len(), 3);
+        assert_eq!(report.size_after, 0);
+        assert!(ids.iter().all(|id| store.packages().contains(id)));
+
+        let report = store
+            .collect_garbage(&user_manager, policy(Some("2d"), None), false)
+            .unwrap();
+        assert_eq!(report.ids().collect::<Vec<_>>(), [&one_id, &two_id]);
+        assert!(!store.packages().contains(&one_id));
+        assert!(!store.packages().contains(&two_id));
+        assert!(store.packages().contains(&three_id));
+        assert_eq!(store.size_of(&[three_id]), 3);
+    }
+
+    #[test]
+    fn store_collect_garbage_unrecorded() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let user_manager = UserManager::init(&temp_dir.child("user")).unwrap();
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", temp_dir.child("one"));
+        let two = pkg("two", temp_dir.child("two"));
+        let three = pkg("three", temp_dir.child("three"));
+        let ids = [&one, &two, &three]
+            .map(|source| hash::root_hash(&source.path, source.name()).unwrap());
+        let [one_id, two_id, three_id] = ids;
+        for source in [one, two, three] {
+            store.insert(source).unwrap();
+        }
+
+        // Stores of older versions did not record the time of use
+        for id in &ids {
+            let (drv, objects) = store.packages_mut().remove(id).unwrap();
+            store.packages_mut().insert(*id, drv, objects);
+            assert!(store.packages().last_used(id).is_none());
+        }
+        let day = Duration::from_secs(24 * 60 * 60);
+        let one_path = store.packages().path_in_store(&one_id, &path).unwrap();
+        File::open(&one_path)
+            .unwrap()
+            .set_modified(SystemTime::now() - 10 * day)
+            .unwrap();
+        let three_path = store.packages().path_in_store(&three_id, &path).unwrap();
+        fs::remove_dir_all(&three_path).unwrap();
+
+        let policy = GcPolicy {
+            older_than: Some("7d".parse().unwrap()),
+            max_size: None,
+        };
+        let report = store.collect_garbage(&user_manager, policy, true).unwrap();
+        assert_eq!(report.ids().collect::<Vec<_>>(), [&three_id, &one_id]);
+        assert_eq!(report.packages[0].last_used, 0);
+        assert!(!report.ids().any(|id| id == &two_id));
+    }
+
+    #[test]
+    fn store_mark_used() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let user_manager = UserManager::init(&temp_dir.child("user")).unwrap();
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", temp_dir.child("one"));
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        store.insert(one).unwrap();
+
+        // The package was installed long ago and just left its last generation
+        store.packages_mut().set_last_used(&one_id, 0);
+        store.mark_used([&one_id]);
+        assert!(store.packages().last_used(&one_id).unwrap() >= gc::now() - 1);
+
+        let policy = GcPolicy {
+            older_than: Some("1d".parse().unwrap()),
+            max_size: None,
+        };
+        let report = store.collect_garbage(&user_manager, policy, false).unwrap();
+        assert!(report.packages.is_empty());
+        assert!(store.packages().contains(&one_id));
+    }
+
+    #[test]
+    fn store_remove_unused_empty() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let user_manager_path = temp_dir.child("user");
+
+        let mut store = store_create_at_path(&path);
+        let mut user_manager = UserManager::init(&user_manager_path).unwrap();
+
+        let removed = store.remove_unused(&mut user_manager).unwrap();
+
+        assert_eq!(removed.len(), 0);
+    }
+
+    #[test]
+    fn store_link_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+        let _package_store_path = package.path_in_store(store.path(), &package_id);
+
+        let _ = store.insert(package).unwrap();
+
+        let global_temp_path = temp_dir.child("global");
+        fs::create_dir(&global_temp_path).unwrap();
+        let global_paths = ComponentPathBuf::from_path(&global_temp_path);
+        global_paths.create_dirs(true).unwrap();
+
+        store.link_package(&package_id, &global_paths).unwrap();
+
+        let package_link = global_paths.library.join("package.so");
+        assert!(package_link.exists());
+        assert!(package_link.is_symlink());
+    }
+
+    #[test]
+    fn store_verify_ok() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+
+        let _ = store.insert(package).unwrap();
+
+        let report = store.verify_package(&package_id).unwrap();
+        assert!(report.is_ok());
+
+        let reports = store.verify().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].is_ok());
+    }
+
+    #[test]
+    fn store_verify_modified_and_extra() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+        let package_store_path = package.path_in_store(store.path(), &package_id);
+
+        let _ = store.insert(package).unwrap();
+
+        fs::write(package_store_path.join("lib/package.so"), "modified").unwrap();
+        fs::write(package_store_path.join("lib/extra.so"), "extra").unwrap();
+
+        let report = store.verify_package(&package_id).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.missing.is_empty());
+        assert_eq!(report.modified.len(), 1);
+        assert!(report
+            .extra
+            .contains(&RelativePathBuf::from("lib/extra.so")));
+    }
+
+    #[test]
+    fn store_repair() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let one_path = temp_dir.child("one");
+        let two_path = temp_dir.child("two");
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", &one_path);
+        // provides the same blob as one, which is hard linked in the store
+        let two = pkg_prov("two", &two_path, "one");
+
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+        let one_store_path = one.path_in_store(store.path(), &one_id);
+
+        store.insert(one).unwrap();
+        store.insert(two).unwrap();
+
+        fs::write(one_store_path.join("lib/one.so"), "modified").unwrap();
+        fs::write(one_store_path.join("lib/extra.so"), "extra").unwrap();
+
+        assert!(!store.verify_package(&one_id).unwrap().is_ok());
+        assert!(!store.verify_package(&two_id).unwrap().is_ok());
+
+        let report = store.repair(&one_id, one_path).unwrap();
+
+        assert!(report.is_ok());
+        assert!(!one_store_path.join("lib/extra.so").exists());
+        assert!(store.verify_package(&two_id).unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_repair_relinks_at_the_path_of_each_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let one_path = temp_dir.child("one");
+        let two_path = temp_dir.child("two");
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", &one_path);
+        let two = pkg("two", &two_path);
+        // The same file in another directory of each package
+        for (package_path, dir) in [(&one_path, "share/a"), (&two_path, "share/b")] {
+            fs::create_dir_all(package_path.join(dir)).unwrap();
+            fs::write(package_path.join(dir).join("data"), "data").unwrap();
+        }
+
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+        let one_store_path = one.path_in_store(store.path(), &one_id);
+        let two_store_path = two.path_in_store(store.path(), &two_id);
+
+        store.insert(one).unwrap();
+        store.insert(two).unwrap();
+
+        fs::write(two_store_path.join("share/b/data"), "modified").unwrap();
+
+        let report = store.repair(&two_id, two_path).unwrap();
+        assert!(report.is_ok());
+        assert!(store.verify_package(&one_id).unwrap().is_ok());
+        assert!(!one_store_path.join("share/b").exists());
+        assert!(!two_store_path.join("share/a").exists());
+    }
+
+    #[test]
+    fn store_repair_keeps_files_of_the_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let PackageHash {
+            root: package_id,
+            blobs,
+            ..
+        } = PackageHash::from_path(&package.path, package.name()).unwrap();
+        let package_store_path = package.path_in_store(store.path(), &package_id);
+        let _ = store.insert(package).unwrap();
+
+        // The file is reported as extra once its object is missing in the database
+        let (blob, object_id) = blobs.into_iter().next().unwrap();
+        store.objects_mut().remove(&object_id);
+        let report = store.verify_package(&package_id).unwrap();
+        assert!(report.extra.contains(&blob.path));
+
+        let err = store.repair(&package_id, package_path).unwrap_err();
+        assert_matches!(err, StoreError::PackageNotRepaired { .. });
+        assert!(blob.to_path(&package_store_path).exists());
+    }
+
+    #[test]
+    fn store_verify_missing() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+        let package_store_path = package.path_in_store(store.path(), &package_id);
+
+        let _ = store.insert(package).unwrap();
+
+        fs::remove_file(package_store_path.join("lib/package.so")).unwrap();
+
+        let report = store.verify_package(&package_id).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.missing.len(), 1);
+        assert!(report.modified.is_empty());
+    }
+
+    #[test]
+    fn store_verify_duplicate_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        // Identical files with the same name in different directories
+        for dir in ["share/a", "share/b"] {
+            fs::create_dir_all(package_path.join(dir)).unwrap();
+            fs::write(package_path.join(dir).join("__init__.py"), "").unwrap();
+        }
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+
+        let _ = store.insert(package).unwrap();
+
+        let report = store.verify_package(&package_id).unwrap();
+        assert!(report.is_ok(), "{report:?}");
+    }
+
+    #[test]
+    fn store_check_and_fix() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let one_path = temp_dir.child("one");
+        let two_path = temp_dir.child("two");
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", &one_path);
+        let two = pkg("two", &two_path);
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+        let two_store_path = two.path_in_store(store.path(), &two_id);
+
+        let _ = store.insert(one).unwrap();
+        let _ = store.insert(two).unwrap();
+        assert!(store.check().unwrap().is_ok());
+
+        let orphan = path.join("orphan");
+        fs::create_dir(&orphan).unwrap();
+        fs::remove_dir_all(&two_store_path).unwrap();
+
+        let report = store.check().unwrap();
+        assert!(report.orphans.contains(&orphan));
+        assert_eq!(report.orphans.len(), 1);
+        assert!(report.dangling_packages.contains(&two_id));
+        assert!(report.dangling_objects.is_empty());
+
+        let fixed = store.fix().unwrap();
+        assert_eq!(fixed, report);
+        assert!(!orphan.exists());
+        assert!(!store.packages().contains(&two_id));
+        assert!(store.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_adopt() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let drv = package.drv.clone();
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+
+        let package_store_path = store.insert(package).unwrap();
+        let _ = store.packages_mut().remove(&package_id).unwrap();
+
+        let report = store.check().unwrap();
+        assert!(report.orphans.contains(&package_store_path));
+        assert!(!report.unreferenced_objects.is_empty());
+
+        let adopted = store.adopt(&package_store_path, drv).unwrap();
+        assert_eq!(adopted, package_id);
+        assert!(store.check().unwrap().is_ok());
+        assert!(store.verify_package(&package_id).unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_insert_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
 
-        let to_remove = self
-            .packages()
-            .filter(|id, _desc, _objects| !used_packages.contains(id))
-            .map(|(id, _desc, _objects)| *id)
-            .collect::<Vec<PackageId>>();
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        unix::fs::symlink("package.so", package_path.join("lib/libpackage.so")).unwrap();
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
 
-        if BAR {
-            let mut bar = ProgressBar::new(to_remove.len() as u64);
-
-            for package_id in &to_remove {
-                let root = unsafe {
-                    self.packages()
-                        .path_in_store(&package_id, &self.source)
-                        .unwrap_unchecked()
-                };
-
-                fs::remove_dir_all(root).context(IoSnafu)?;
-                let (_desc, objects) =
-                    unsafe { self.packages_mut().remove(&package_id).unwrap_unchecked() };
-                assert!(self
-                    .objects_mut()
-                    .remove_objects(objects.iter())
-                    .collect::<Option<Vec<_>>>()
-                    .is_some());
+        let package_store_path = store.insert(package).unwrap();
 
-                bar.inc(1);
-            }
+        let link = package_store_path.join("lib/libpackage.so");
+        assert!(link.is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("package.so"));
+        assert!(store.verify_package(&package_id).unwrap().is_ok());
 
-            bar.finish("Unused packages removed");
-        } else {
-            for package_id in &to_remove {
-                let root = unsafe {
-                    self.packages()
-                        .path_in_store(&package_id, &self.source)
-                        .unwrap_unchecked()
-                };
-
-                fs::remove_dir_all(root).context(IoSnafu)?;
-                let (_desc, objects) =
-                    unsafe { self.packages_mut().remove(&package_id).unwrap_unchecked() };
-                assert!(self
-                    .objects_mut()
-                    .remove_objects(objects.iter())
-                    .collect::<Option<Vec<_>>>()
-                    .is_some());
-            }
-        }
+        let global_temp_path = temp_dir.child("global");
+        fs::create_dir(&global_temp_path).unwrap();
+        let global_paths = ComponentPathBuf::from_path(&global_temp_path);
+        global_paths.create_dirs(true).unwrap();
 
-        Ok(to_remove)
-    }
+        store.link_package(&package_id, &global_paths).unwrap();
 
-    /// Flushes all data to the backend
-    pub fn flush(self) -> StoreResult<()> {
-        self.backend.flush()?;
+        let package_link = global_paths.library.join("libpackage.so");
+        assert!(package_link.is_symlink());
+        assert!(package_link.exists());
 
-        Ok(())
+        fs::remove_file(&link).unwrap();
+        let report = store.verify_package(&package_id).unwrap();
+        assert_eq!(report.links.len(), 1);
+
+        let report = store.repair(&package_id, package_path).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("package.so"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{LocalStore, PACKAGES_DB};
-    use crate::{
-        extra::{hash, path::ComponentPathBuf},
-        support::*,
-        user::UserManager,
-    };
-    use std::{fs, path::Path};
-    use temp_dir::TempDir;
+    #[test]
+    fn store_insert_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+        let setuid_path = temp_dir.child("setuid");
 
-    fn store_create_at_path(path: &Path) -> LocalStore {
-        let store = LocalStore::init(&path).unwrap();
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let lib_path = package_path.join("lib/package.so");
+        fs::set_permissions(&lib_path, Permissions::from_mode(0o755)).unwrap();
 
-        let store_db = path.join(PACKAGES_DB);
+        let package_store_path = store.insert(package).unwrap();
 
-        assert!(store_db.exists());
-        assert!(store_db.is_file());
+        let mode = fs::metadata(package_store_path.join("lib/package.so"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o555);
 
-        store
+        let setuid = pkg("setuid", &setuid_path);
+        let lib_path = setuid_path.join("lib/setuid.so");
+        fs::set_permissions(&lib_path, Permissions::from_mode(0o4755)).unwrap();
+
+        let err = store.insert(setuid).unwrap_err();
+        assert_matches!(err, StoreError::SetuidBlob { blob: _ });
     }
 
     #[test]
-    fn store_create_at_path_ok() {
+    fn store_verify_mode() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
-        let _store = store_create_at_path(&path);
+        let package_path = temp_dir.child("package");
+
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+
+        let package_store_path = store.insert(package).unwrap();
+        let lib_path = package_store_path.join("lib/package.so");
+        fs::set_permissions(&lib_path, Permissions::from_mode(0o755)).unwrap();
+
+        let report = store.verify_package(&package_id).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.modes.len(), 1);
+        assert!(report.modified.is_empty());
+
+        let report = store.repair(&package_id, package_path).unwrap();
+        assert!(report.is_ok());
+        let mode = fs::metadata(&lib_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o444);
     }
 
     #[test]
-    fn store_create_at_path_err() {
+    fn store_migrate() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
-        fs::create_dir(&path).unwrap();
+        let package_path = temp_dir.child("package");
 
-        let res = LocalStore::init(&path);
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
 
-        assert!(res.is_err());
+        let package_store_path = store.insert(package).unwrap();
+        assert!(store.migrate().unwrap().is_empty());
+
+        // Pretend the package was inserted with an older hash version
+        let old_id = PackageId::from([1; 32]);
+        let (drv, objects) = store.packages_mut().remove(&package_id).unwrap();
+        let old_path = drv.path_in_store(store.path(), &old_id);
+        fs::rename(&package_store_path, &old_path).unwrap();
+        store.packages_mut().insert(old_id, drv.clone(), objects);
+        // A package whose directory is missing does not stop the migration
+        let dangling_id = PackageId::from([2; 32]);
+        store
+            .packages_mut()
+            .insert(dangling_id, drv, Default::default());
+        store.packages_mut().set_hash_version(0);
+
+        let migrated = store.migrate().unwrap();
+        assert_eq!(migrated.get(&old_id), Some(&package_id));
+        assert_eq!(migrated.len(), 1);
+        assert!(store.packages().contains(&dangling_id));
+        assert_eq!(store.packages().hash_version(), HASH_VERSION);
+        assert!(!old_path.exists());
+        assert!(store.packages_mut().remove(&dangling_id).is_some());
+        assert!(store.verify_package(&package_id).unwrap().is_ok());
+        assert!(store.check().unwrap().is_ok());
     }
 
     #[test]
-    fn store_open_ok() {
+    fn store_export_import() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
-        let _store = store_create_at_path(&path);
+        let other_path = temp_dir.child("other");
+        let one_path = temp_dir.child("one");
+        let two_path = temp_dir.child("two");
 
-        let _store = LocalStore::open(path).unwrap();
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", &one_path);
+        let two = pkg("two", &two_path);
+        unix::fs::symlink("two.so", two_path.join("lib/libtwo.so")).unwrap();
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+
+        let _ = store.insert(one).unwrap();
+        let _ = store.insert(two).unwrap();
+
+        for compress in [false, true] {
+            let archive = temp_dir.child(format!("archive-{compress}"));
+            store
+                .export([&one_id, &two_id], &archive, compress)
+                .unwrap();
+
+            let mut other = store_create_at_path(&other_path);
+            let mut imported = other.import(&archive).unwrap();
+            imported.sort();
+            let mut expected = vec![one_id, two_id];
+            expected.sort();
+            assert_eq!(imported, expected);
+
+            assert!(other.verify_package(&one_id).unwrap().is_ok());
+            assert!(other.verify_package(&two_id).unwrap().is_ok());
+            assert!(other.import(&archive).unwrap().is_empty());
+
+            fs::remove_dir_all(&other_path).unwrap();
+        }
     }
 
     #[test]
-    fn store_open_err() {
+    fn store_subset() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
 
-        let res = LocalStore::open(path);
-
-        assert!(res.is_err());
+        let mut store = store_create_at_path(&path);
+        let one = pkg("one", temp_dir.child("one"));
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        let _ = store.insert(one).unwrap();
+        let _ = store.insert(pkg("two", temp_dir.child("two"))).unwrap();
+
+        let (objects, packages) = store.subset([&one_id]).unwrap();
+        assert_eq!(packages.iter().count(), 1);
+        let (_drv, children) = store.packages().get_full(&one_id).unwrap();
+        assert_eq!(objects.iter().count(), children.len());
+
+        // A dangling object is reported instead of read
+        let object_id = *children.iter().next().unwrap();
+        store.objects_mut().remove(&object_id);
+        assert_matches!(
+            store.subset([&one_id]),
+            Err(StoreError::ObjectNotFoundById { id }) if id == object_id
+        );
     }
 
     #[test]
-    fn store_insert() {
+    fn store_import_modified() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
+        let other_path = temp_dir.child("other");
         let package_path = temp_dir.child("package");
 
         let mut store = store_create_at_path(&path);
         let package = pkg("package", &package_path);
+        let drv = package.drv.clone();
         let package_id = hash::root_hash(&package.path, package.name()).unwrap();
-        let package_store_path = package.path_in_store(store.path(), &package_id);
+        let _ = store.insert(package).unwrap();
+        let valid_path = temp_dir.child("valid");
+        let valid = pkg("valid", &valid_path);
+        let valid_drv = valid.drv.clone();
+        let valid_id = hash::root_hash(&valid.path, valid.name()).unwrap();
+        let _ = store.insert(valid).unwrap();
+
+        // Archive the original database with modified contents
+        let database = pot::to_vec(&(store.objects(), store.packages())).unwrap();
+        fs::write(package_path.join("lib/package.so"), b"modified").unwrap();
+        let archive = temp_dir.child("archive");
+        archive::write(
+            &archive,
+            false,
+            &database,
+            [
+                (
+                    drv.relative_path(&package_id).into_string(),
+                    package_path.as_path(),
+                ),
+                (
+                    valid_drv.relative_path(&valid_id).into_string(),
+                    valid_path.as_path(),
+                ),
+            ],
+        )
+        .unwrap();
+
+        let mut other = store_create_at_path(&other_path);
+        let err = other.import(&archive).unwrap_err();
+        assert_matches!(err, StoreError::PackageNotVerified { drv: _ });
+        assert!(!other.packages().contains(&package_id));
+        // Nothing of the archive is inserted
+        assert!(!other.packages().contains(&valid_id));
+        assert!(!valid_drv.path_in_store(&other_path, &valid_id).exists());
+        assert!(other.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_import_malicious_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let other_path = temp_dir.child("other");
+        let package_path = temp_dir.child("package");
 
+        let mut store = store_create_at_path(&path);
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
         let _ = store.insert(package).unwrap();
 
-        assert!(package_store_path.exists());
-        assert!(package_store_path.is_dir());
+        // The database of the archive points a package outside of the store
+        let (drv, children) = store.packages().get_full(&package_id).unwrap();
+        let mut drv = drv.clone();
+        drv.name = "../../evil".to_owned();
+        let mut packages = Packages::new();
+        packages.insert(package_id, drv.clone(), children.clone());
+        let database = pot::to_vec(&(store.objects(), &packages)).unwrap();
+        let archive = temp_dir.child("archive");
+        archive::write(
+            &archive,
+            false,
+            &database,
+            [("package".to_owned(), package_path.as_path())],
+        )
+        .unwrap();
+
+        let mut other = store_create_at_path(&other_path);
+        assert_matches!(
+            other.import(&archive),
+            Err(StoreError::InvalidPackagePath { name, version: _ }) if name == "../../evil"
+        );
+        assert!(!other.packages().contains(&package_id));
+        assert!(!drv.path_in_store(&other_path, &package_id).exists());
+        assert!(other.check().unwrap().is_ok());
+    }
 
-        let package_store_file = package_store_path.join("lib/package.so");
+    #[test]
+    fn store_insert_rollback() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+        let modified_path = temp_dir.child("modified");
 
-        assert!(package_store_file.exists());
-        assert!(package_store_file.is_file());
+        let mut store = store_create_at_path(&path);
+        let _ = store.insert(pkg("one", temp_dir.child("one"))).unwrap();
+        let object_ids = |store: &LocalStore| {
+            store
+                .objects()
+                .iter()
+                .map(|(id, _object)| *id)
+                .collect::<BTreeSet<_>>()
+        };
+        let objects = object_ids(&store);
+
+        let package = pkg("package", &package_path);
+        let _modified = pkg("package", &modified_path);
+        fs::write(modified_path.join("lib/package.so"), b"modified").unwrap();
+
+        let PackageHash {
+            root: package_id,
+            trees,
+            blobs,
+            links,
+            ..
+        } = PackageHash::from_path(&package_path, "package").unwrap();
+        let absolute = package.drv.path_in_store(&path, &package_id);
+
+        // The copied contents do not match the package id
+        let err = store
+            .insert_source(
+                package_id,
+                package.drv.clone(),
+                blobs.clone(),
+                trees.clone(),
+                links.clone(),
+                absolute.clone(),
+                Source::Local(modified_path.clone()),
+            )
+            .unwrap_err();
+        assert_matches!(err, StoreError::PackageNotVerified { drv: _ });
+
+        // A blob is missing in the source
+        fs::remove_file(package_path.join("lib/package.so")).unwrap();
+        let err = store
+            .insert_source(
+                package_id,
+                package.drv.clone(),
+                blobs.clone(),
+                trees.clone(),
+                links.clone(),
+                absolute.clone(),
+                Source::Local(package_path),
+            )
+            .unwrap_err();
+        assert_matches!(err, StoreError::CopyObjectError { .. });
+
+        assert!(!absolute.exists());
+        assert_eq!(object_ids(&store), objects);
+        assert!(!store.packages().contains(&package_id));
+        assert!(store.check().unwrap().is_ok());
+        assert_eq!(fs::read_dir(path.join(STAGING_DIR)).unwrap().count(), 0);
+
+        // A failed cleanup does not replace the error of the insertion
+        let tree_id = trees.values().next().unwrap();
+        let blocked = path.join(OBJECTS_DIR).join(tree_id.to_string());
+        fs::create_dir_all(blocked.join("blocked")).unwrap();
+        let err = store
+            .insert_source(
+                package_id,
+                package.drv.clone(),
+                blobs,
+                trees,
+                links,
+                absolute.clone(),
+                Source::Local(modified_path),
+            )
+            .unwrap_err();
+        assert_matches!(err, StoreError::PackageNotVerified { drv: _ });
+        assert!(blocked.exists());
+        assert_eq!(object_ids(&store), objects);
+        assert!(!store.packages().contains(&package_id));
     }
 
     #[test]
-    fn store_remove_unused() {
+    fn store_check_staging() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
-        let one_path = temp_dir.child("one");
-        let two_path = temp_dir.child("two");
-        let user_manager_path = temp_dir.child("user");
 
         let mut store = store_create_at_path(&path);
-        let one = pkg("one", &one_path);
-        let two = pkg("two", &two_path);
+        let leftover = path.join(STAGING_DIR).join("package-1.0.0-interrupted");
+        fs::create_dir_all(leftover.join("lib")).unwrap();
+
+        let report = store.check().unwrap();
+        assert!(report.orphans.contains(&leftover));
+
+        store.fix().unwrap();
+        assert!(!leftover.exists());
+        assert!(store.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_open_locked() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        store_create_at_path(&path).flush().unwrap();
+
+        let reader = LocalStore::open_with(&path, LockMode::Shared, None).unwrap();
+        let other = LocalStore::open_with(&path, LockMode::Shared, None).unwrap();
+        assert_matches!(LocalStore::open(&path), Err(StoreError::Busy { path: _ }));
+        assert_matches!(reader.flush(), Err(StoreError::ReadOnly { path: _ }));
+        drop(other);
+
+        let _writer = LocalStore::open(&path).unwrap();
+        let timeout = Some(Duration::from_millis(100));
+        assert_matches!(
+            LocalStore::open_with(&path, LockMode::Shared, timeout),
+            Err(StoreError::Busy { path: _ })
+        );
+    }
+
+    #[test]
+    fn store_concurrent_writers() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        store_create_at_path(&path).flush().unwrap();
+
+        let writers = ["one", "two"]
+            .into_iter()
+            .map(|name| {
+                let path = path.clone();
+                let package = pkg(name, temp_dir.child(name));
+
+                thread::spawn(move || {
+                    let timeout = Some(Duration::from_secs(10));
+                    let mut store =
+                        LocalStore::open_with(&path, LockMode::Exclusive, timeout).unwrap();
+                    let _ = store.insert(package).unwrap();
+                    // Give the other writer the chance to load the database in between
+                    thread::sleep(Duration::from_millis(100));
+                    store.flush().unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let store = LocalStore::open(&path).unwrap();
+        assert!(store.packages().find_by_name("one").is_some());
+        assert!(store.packages().find_by_name("two").is_some());
+        assert!(store.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_sqlite_backend() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+
+        let mut store = LocalStore::init_with(&path, BackendKind::Sqlite).unwrap();
+        assert!(path.join(PACKAGES_SQLITE).is_file());
+        assert!(!path.join(PACKAGES_DB).exists());
 
+        let one = pkg("one", temp_dir.child("one"));
+        let two = pkg("two", temp_dir.child("two"));
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+        store.insert(one).unwrap();
+        store.insert(two).unwrap();
+
+        let objects = store
+            .objects()
+            .iter()
+            .map(|(id, _object)| *id)
+            .collect::<BTreeSet<_>>();
+        store.flush().unwrap();
+
+        let mut store = LocalStore::open(&path).unwrap();
+        assert_eq!(store.backend_kind(), BackendKind::Sqlite);
+        assert!(store.check().unwrap().is_ok());
+        assert_eq!(
+            store
+                .objects()
+                .iter()
+                .map(|(id, _object)| *id)
+                .collect::<BTreeSet<_>>(),
+            objects
+        );
+
+        let (one_id, _drv) = store.packages().find_by_name("one").unwrap();
+        let one_id = *one_id;
+        assert!(store.packages().last_used(&one_id).is_some());
+        store.packages_mut().set_last_used(&one_id, 42);
+
+        let (_drv, removed) = store.packages_mut().remove(&two_id).unwrap();
+        let (_one_id, _drv, kept) = store.packages().iter().next().unwrap();
+        let removed = removed.difference(kept).copied().collect::<Vec<_>>();
+        for id in &removed {
+            store.objects_mut().remove(id);
+        }
+        store.flush().unwrap();
+
+        let mut store = LocalStore::open(&path).unwrap();
+        assert!(store.packages().find_by_name("one").is_some());
+        assert!(!store.packages().contains(&two_id));
+        assert!(removed.iter().all(|id| !store.objects().contains(id)));
+        assert_eq!(store.packages().hash_version(), HASH_VERSION);
+        assert_eq!(store.packages().last_used(&one_id), Some(42));
+
+        // A changed object keeps its id but is written again
+        let (_drv, children) = store.packages().get_full(&one_id).unwrap();
+        let object_id = *children.iter().next().unwrap();
+        store
+            .objects_mut()
+            .get_mut(&object_id)
+            .unwrap()
+            .replace_path(RelativePathBuf::from("changed"));
+        store.flush().unwrap();
+
+        let store = LocalStore::open(&path).unwrap();
+        assert_eq!(
+            store.objects().get(&object_id).unwrap().relative_path(),
+            RelativePathBuf::from("changed")
+        );
+    }
+
+    #[test]
+    fn store_sqlite_backend_migrate() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let package_path = temp_dir.child("package");
+
+        let mut store = LocalStore::init_with(&path, BackendKind::Sqlite).unwrap();
+        let package = pkg("package", &package_path);
+        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
+        let package_store_path = store.insert(package).unwrap();
+
+        // Pretend the package was inserted with an older hash version
+        let old_id = PackageId::from([1; 32]);
+        let (drv, objects) = store.packages_mut().remove(&package_id).unwrap();
+        fs::rename(
+            &package_store_path,
+            drv.path_in_store(store.path(), &old_id),
+        )
+        .unwrap();
+        store.packages_mut().insert(old_id, drv, objects);
+        let (_id, object) = store.objects().iter().next().unwrap();
+        let object = object.clone();
+        store.objects_mut().insert(ObjectId::from([3; 32]), object);
+        store.packages_mut().set_hash_version(0);
+        store.flush().unwrap();
+
+        let mut store = LocalStore::open(&path).unwrap();
+        assert_eq!(store.migrate().unwrap().get(&old_id), Some(&package_id));
+        let objects = store
+            .objects()
+            .iter()
+            .map(|(id, _object)| *id)
+            .collect::<BTreeSet<_>>();
+        store.flush().unwrap();
+
+        // The objects replaced by the migration are deleted from the database
+        let store = LocalStore::open(&path).unwrap();
+        assert!(!store.packages().contains(&old_id));
+        assert!(store.packages().contains(&package_id));
+        assert_eq!(
+            store
+                .objects()
+                .iter()
+                .map(|(id, _object)| *id)
+                .collect::<BTreeSet<_>>(),
+            objects
+        );
+        assert!(!objects.contains(&ObjectId::from([3; 32])));
+        assert!(store.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_convert() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+
+        let mut store = store_create_at_path(&path);
+        store.insert(pkg("one", temp_dir.child("one"))).unwrap();
+        store.flush().unwrap();
+
+        let reader = LocalStore::open_with(&path, LockMode::Shared, None).unwrap();
+        assert_matches!(
+            reader.convert(BackendKind::Sqlite),
+            Err(StoreError::ReadOnly { path: _ })
+        );
+
+        let store = LocalStore::open(&path).unwrap();
+        let store = store.convert(BackendKind::Sqlite).unwrap();
+        assert!(!path.join(PACKAGES_DB).exists());
+        store.flush().unwrap();
+
+        let store = LocalStore::open(&path).unwrap();
+        assert_eq!(store.backend_kind(), BackendKind::Sqlite);
+        assert!(store.packages().find_by_name("one").is_some());
+        assert!(store.check().unwrap().is_ok());
+
+        let store = store.convert(BackendKind::Rustbreak).unwrap();
+        assert!(!path.join(PACKAGES_SQLITE).exists());
+        store.flush().unwrap();
+
+        let store = LocalStore::open(&path).unwrap();
+        assert_eq!(store.backend_kind(), BackendKind::Rustbreak);
+        assert!(store.packages().find_by_name("one").is_some());
+    }
+
+    #[test]
+    fn store_remove_unused_shared_objects() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+        let user_manager_path = temp_dir.child("user");
+
+        let mut store = store_create_at_path(&path);
+        let one = pkg_prov("one", temp_dir.child("one"), "shared");
+        let two = pkg_prov("two", temp_dir.child("two"), "shared");
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
         let two_id = hash::root_hash(&two.path, two.name()).unwrap();
-        let one_req = req("one", ">0.0.0");
 
         store.insert(one).unwrap();
         store.insert(two).unwrap();
 
+        let shared = store
+            .objects()
+            .iter()
+            .filter(|(id, _object)| store.packages().reference_count(id) == 2)
+            .map(|(id, _object)| *id)
+            .collect::<Vec<_>>();
+        assert!(!shared.is_empty());
+
         let global_temp = temp_dir.child("global");
         fs::create_dir(&global_temp).unwrap();
         let global_paths = ComponentPathBuf::from_path(&global_temp);
@@ -606,52 +3249,306 @@ mod tests {
 
         let mut user_manager = UserManager::init(&user_manager_path).unwrap();
         assert!(user_manager
-            .insert_requirement(one_req, &mut store, &global_paths)
+            .insert_requirement(
+                req_comp("two", ">0.0.0", [Blob::new("lib/shared.so".into())]),
+                &mut store,
+                &global_paths
+            )
             .unwrap());
 
+        // The package which holds the shared objects originally is removed
         let removed = store.remove_unused(&mut user_manager).unwrap();
+        assert_eq!(removed, [one_id]);
 
-        assert_eq!(removed.len(), 1);
-        assert!(removed.contains(&two_id));
+        for id in &shared {
+            assert!(store.objects().contains(id));
+            assert_eq!(store.packages().owners(id).collect::<Vec<_>>(), [&two_id]);
+        }
+        assert!(store.check().unwrap().is_ok());
+
+        // Inserting another package with the same objects links them from the survivor
+        let three = pkg_prov("three", temp_dir.child("three"), "shared");
+        store.insert(three).unwrap();
+        for id in &shared {
+            assert_eq!(store.packages().reference_count(id), 2);
+        }
+        assert!(store.verify().unwrap().iter().all(|report| report.is_ok()));
     }
 
     #[test]
-    fn store_remove_unused_empty() {
+    fn store_stats() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
-        let user_manager_path = temp_dir.child("user");
 
         let mut store = store_create_at_path(&path);
-        let mut user_manager = UserManager::init(&user_manager_path).unwrap();
+        let one = pkg_prov("one", temp_dir.child("one"), "shared");
+        fs::write(one.path.join("lib/shared.so"), "abcd").unwrap();
+        fs::write(one.path.join("lib/one.so"), "123456").unwrap();
+        let two = pkg_req_ver_prov(
+            "two",
+            temp_dir.child("two"),
+            [req_comp(
+                "one",
+                ">0.0.0",
+                [Blob::new("lib/shared.so".into())],
+            )],
+            "1.0.0",
+            "shared",
+        );
+        fs::write(two.path.join("lib/shared.so"), "abcd").unwrap();
+        fs::write(two.path.join("lib/two.so"), "12").unwrap();
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
 
-        let removed = store.remove_unused(&mut user_manager).unwrap();
+        store.insert(one).unwrap();
+        store.insert(two).unwrap();
 
-        assert_eq!(removed.len(), 0);
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.packages.len(), 2);
+
+        let one = stats.get(&one_id).unwrap();
+        assert_eq!(one.name, "one");
+        assert_eq!(one.apparent_size, 10);
+        assert_eq!(one.unique_size, 6);
+        assert_eq!(one.shared_size, 4);
+        assert_eq!(one.blobs, 2);
+        assert_eq!(one.closure_packages, 1);
+        assert_eq!(one.closure_size, Some(10));
+
+        let two = stats.get(&two_id).unwrap();
+        assert_eq!(two.apparent_size, 6);
+        assert_eq!(two.unique_size, 2);
+        assert_eq!(two.shared_size, 4);
+        assert_eq!(two.closure_packages, 2);
+        assert_eq!(two.closure_size, Some(12));
+
+        assert_eq!(stats.total.packages, 2);
+        assert_eq!(stats.total.blobs, 3);
+        assert_eq!(stats.total.apparent_size, 16);
+        assert_eq!(stats.total.disk_size, 12);
+        assert_eq!(stats.total.shared_size, 4);
+        assert_eq!(stats.total.saved_size(), 4);
     }
 
     #[test]
-    fn store_link_package() {
+    fn store_closure() {
         let temp_dir = TempDir::new().unwrap();
         let path = temp_dir.child("store");
-        let package_path = temp_dir.child("package");
 
         let mut store = store_create_at_path(&path);
+        let one = pkg("one", temp_dir.child("one"));
+        let two = pkg_req("two", temp_dir.child("two"), [req("one", ">0.0.0")]);
+        let three = pkg_req(
+            "three",
+            temp_dir.child("three"),
+            [req("one", ">0.0.0"), req("two", ">0.0.0")],
+        );
+        let four = pkg_req("four", temp_dir.child("four"), [req("five", ">0.0.0")]);
+        let ids = [&one, &two, &three, &four]
+            .map(|source| hash::root_hash(&source.path, source.name()).unwrap());
+        let [one_id, two_id, three_id, four_id] = ids;
+
+        for source in [one, two, three, four] {
+            store.insert(source).unwrap();
+        }
 
-        let package = pkg("package", &package_path);
-        let package_id = hash::root_hash(&package.path, package.name()).unwrap();
-        let _package_store_path = package.path_in_store(store.path(), &package_id);
+        assert_eq!(store.closure(&one_id).unwrap(), BTreeSet::from([one_id]));
+        assert_eq!(
+            store.closure(&three_id).unwrap(),
+            BTreeSet::from([one_id, two_id, three_id])
+        );
+        assert_matches!(
+            store.closure(&four_id),
+            Err(StoreError::UnresolvedDependencies { id, .. }) if id == four_id
+        );
+
+        // The closures of all packages are the same as the ones resolved on their own
+        let six = pkg_req("six", temp_dir.child("six"), [req("seven", ">0.0.0")]);
+        let seven = pkg_req("seven", temp_dir.child("seven"), [req("six", ">0.0.0")]);
+        for source in [six, seven] {
+            store.insert(source).unwrap();
+        }
+        let closures = store.closures();
+        assert_eq!(closures.len(), 6);
+        for (id, closure) in &closures {
+            assert_eq!(closure.as_ref(), store.closure(id).ok().as_ref());
+        }
+        assert_eq!(
+            closures
+                .values()
+                .filter(|closure| closure.is_none())
+                .count(),
+            3
+        );
+
+        let closure = store.closure(&three_id).unwrap();
+        let dependencies = store
+            .dependencies(&three_id, &closure)
+            .map(|(req, id)| (req.name().as_str(), *id))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            dependencies,
+            BTreeSet::from([("one", one_id), ("two", two_id)])
+        );
+    }
 
-        let _ = store.insert(package).unwrap();
+    #[test]
+    fn store_dependency_paths_within() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
 
-        let global_temp_path = temp_dir.child("global");
-        fs::create_dir(&global_temp_path).unwrap();
-        let global_paths = ComponentPathBuf::from_path(&global_temp_path);
-        global_paths.create_dirs(true).unwrap();
+        let mut store = store_create_at_path(&path);
+        let old = pkg("one", temp_dir.child("old"));
+        let new = pkg_req_ver_prov("one", temp_dir.child("new"), [], "2.0.0", "one");
+        let two = pkg_req("two", temp_dir.child("two"), [req("one", ">0.0.0")]);
+        let ids =
+            [&old, &new, &two].map(|source| hash::root_hash(&source.path, source.name()).unwrap());
+        let [old_id, new_id, two_id] = ids;
+        for source in [old, new, two] {
+            store.insert(source).unwrap();
+        }
 
-        store.link_package(&package_id, &global_paths).unwrap();
+        let requirements = [req("two", ">0.0.0")];
+        let generation = BTreeSet::from([old_id, two_id]);
+        let paths = store
+            .dependency_paths_within(&requirements, &generation, &[old_id, new_id])
+            .into_iter()
+            .map(|path| path.into_iter().map(|(_req, id)| id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [vec![two_id, old_id]]);
+
+        let paths = store.dependency_paths(&requirements, &[two_id]).unwrap();
+        assert_eq!(paths.len(), 1);
+    }
 
-        let package_link = global_paths.library.join("package.so");
-        assert!(package_link.exists());
-        assert!(package_link.is_symlink());
+    #[test]
+    fn store_copy_from() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let mut from = store_create_at_path(&temp_dir.child("from"));
+        let one = pkg("one", temp_dir.child("one"));
+        let two = pkg_req("two", temp_dir.child("two"), [req("one", ">0.0.0")]);
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+        from.insert(one.clone()).unwrap();
+        from.insert(two).unwrap();
+
+        let mut to = store_create_at_path(&temp_dir.child("to"));
+        to.insert(one).unwrap();
+        // Provides the same blob as two
+        let other = to
+            .insert(pkg_prov("other", temp_dir.child("other"), "two"))
+            .unwrap();
+
+        assert_eq!(to.copy_from(&from, &two_id).unwrap(), [two_id]);
+        assert!(to.packages().contains(&one_id));
+        assert!(to.packages().contains(&two_id));
+        assert!(to.copy_from(&from, &two_id).unwrap().is_empty());
+
+        let two = to.packages().path_in_store(&two_id, to.path()).unwrap();
+        assert_eq!(
+            inode(&two.join("lib/two.so")),
+            inode(&other.join("lib/two.so"))
+        );
+        assert!(to.verify().unwrap().iter().all(|report| report.is_ok()));
+        assert!(to.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn store_copy_from_rollback() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let mut from = store_create_at_path(&temp_dir.child("from"));
+        let one = pkg("one", temp_dir.child("one"));
+        let two = pkg_req("two", temp_dir.child("two"), [req("one", ">0.0.0")]);
+        let one_id = hash::root_hash(&one.path, one.name()).unwrap();
+        let two_id = hash::root_hash(&two.path, two.name()).unwrap();
+        from.insert(one).unwrap();
+        from.insert(two).unwrap();
+
+        // The closure is copied in the order of the ids, the last package fails
+        let (last, name) = if one_id > two_id {
+            (one_id, "one")
+        } else {
+            (two_id, "two")
+        };
+        let last_path = from.packages().path_in_store(&last, from.path()).unwrap();
+        fs::write(last_path.join(format!("lib/{name}.so")), "modified").unwrap();
+
+        let to_path = temp_dir.child("to");
+        let mut to = store_create_at_path(&to_path);
+        assert!(to.copy_from(&from, &two_id).is_err());
+        assert_eq!(to.packages().iter().count(), 0);
+        assert_eq!(to.objects().iter().count(), 0);
+        assert!(to.check().unwrap().is_ok());
+    }
+
+    fn inode(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn store_objects_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+
+        let mut store = store_create_at_path(&path);
+        let one = store
+            .insert(pkg_prov("one", temp_dir.child("one"), "shared"))
+            .unwrap();
+        let two = store
+            .insert(pkg_prov("two", temp_dir.child("two"), "shared"))
+            .unwrap();
+
+        let (id, _blob) = store
+            .objects()
+            .iter()
+            .find(|(_id, object)| object.is_blob())
+            .unwrap();
+        let object = store.object_file(id);
+        assert!(object.is_file());
+        assert_eq!(inode(&object), inode(&one.join("lib/shared.so")));
+        assert_eq!(inode(&object), inode(&two.join("lib/shared.so")));
+        assert!(store.check().unwrap().is_ok());
+
+        // Stray files in the objects are orphans
+        let stray = path.join(OBJECTS_DIR).join("stray");
+        fs::write(&stray, "").unwrap();
+        assert!(store.check().unwrap().orphans.contains(&stray));
+        store.fix().unwrap();
+        assert!(!stray.exists());
+        assert!(object.exists());
+    }
+
+    #[test]
+    fn store_migrate_objects() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("store");
+
+        let mut store = store_create_at_path(&path);
+        let one = store
+            .insert(pkg_prov("one", temp_dir.child("one"), "shared"))
+            .unwrap();
+        let two = store
+            .insert(pkg_prov("two", temp_dir.child("two"), "shared"))
+            .unwrap();
+
+        // Older stores have no objects and copies of the same blob
+        fs::remove_dir_all(path.join(OBJECTS_DIR)).unwrap();
+        let file = two.join("lib/shared.so");
+        fs::remove_file(&file).unwrap();
+        fs::write(&file, "").unwrap();
+        fs::set_permissions(&file, Permissions::from_mode(0o444)).unwrap();
+        assert_ne!(inode(&file), inode(&one.join("lib/shared.so")));
+
+        assert_eq!(store.migrate_objects().unwrap(), 2);
+        assert_eq!(store.migrate_objects().unwrap(), 0);
+
+        for (id, _object) in store.objects().iter().filter(|(_id, o)| o.is_blob()) {
+            assert!(store.object_file(id).is_file());
+        }
+        assert_eq!(inode(&file), inode(&one.join("lib/shared.so")));
+        assert!(store.check().unwrap().is_ok());
+        assert!(store.verify().unwrap().iter().all(|report| report.is_ok()));
     }
 }
@@ -75,9 +75,7 @@ pub fn req(name: &str, version_req: &str) -> Requirement {
     req_comp(
         name,
         version_req,
-        [Blob {
-            path: RelativePathBuf::from(&format!("lib/{name}.so")),
-        }],
+        [Blob::new(RelativePathBuf::from(&format!("lib/{name}.so")))],
     )
 }
 
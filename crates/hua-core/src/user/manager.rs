@@ -1,7 +1,10 @@
 use crate::{
     dependency::Requirement,
-    extra::path::ComponentPathBuf,
-    generation::GenerationManager,
+    extra::{
+        lock::{FileLock, LockMode},
+        path::ComponentPathBuf,
+    },
+    generation::{Generation, GenerationManager},
     store::{backend::ReadBackend, id::PackageId, Store},
     user::User,
     GID, UID,
@@ -9,11 +12,16 @@ use crate::{
 use log::debug;
 use snafu::ResultExt;
 use std::{
-    fs,
+    collections::BTreeMap,
+    fs, io,
     os::unix::{self, prelude::PermissionsExt},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+/// The hidden file in the user manager directory which is locked while it is used.
+const LOCK_FILE: &str = ".lock";
+
 use super::*;
 
 /// Manages all users.
@@ -21,6 +29,20 @@ pub struct UserManager {
     path: PathBuf,
     current: usize,
     users: Vec<User>,
+    lock: FileLock,
+}
+
+/// Locks the user manager at the path, see [FileLock::acquire].
+fn lock(path: &Path, mode: LockMode, timeout: Option<Duration>) -> UserResult<FileLock> {
+    FileLock::acquire(&path.join(LOCK_FILE), mode, timeout).map_err(|source| {
+        if source.kind() == io::ErrorKind::WouldBlock {
+            UserError::Busy {
+                path: path.to_owned(),
+            }
+        } else {
+            UserError::IoError { source }
+        }
+    })
 }
 
 impl UserManager {
@@ -33,6 +55,7 @@ impl UserManager {
         perm.set_mode(0o777);
         fs::set_permissions(path, perm).context(IoSnafu)?;
         unix::fs::chown(&path, UID, GID).context(IoSnafu)?;
+        let lock = lock(path, LockMode::Exclusive, None)?;
 
         debug!("User manager path created at {path:?}");
 
@@ -55,11 +78,24 @@ impl UserManager {
             path: path.to_owned(),
             users: list,
             current,
+            lock,
         })
     }
 
     /// Opens an old user manager under the given path.
     pub fn open<P: AsRef<Path>>(path: P) -> UserResult<Self> {
+        Self::open_with(path, LockMode::Exclusive, None)
+    }
+
+    /// Opens an old user manager under the given path with a lock of the given mode.
+    /// A user manager opened with a [LockMode::Shared] lock cannot be flushed.
+    /// Returns [UserError::Busy] if another process holds a conflicting lock
+    /// after the timeout elapsed.
+    pub fn open_with<P: AsRef<Path>>(
+        path: P,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> UserResult<Self> {
         let path = path.as_ref();
         if !path.exists() {
             return Err(UserError::PathNotExisting {
@@ -67,10 +103,14 @@ impl UserManager {
             });
         }
 
+        let lock = lock(path, mode, timeout)?;
         let mut users = Vec::new();
 
         for entry in path.read_dir().context(IoSnafu)? {
             let entry = entry.context(IoSnafu)?;
+            if entry.file_name() == LOCK_FILE {
+                continue;
+            }
             let user = User::open(entry.path())?;
             users.push(user);
         }
@@ -102,6 +142,7 @@ impl UserManager {
             path: path.to_owned(),
             current,
             users,
+            lock,
         })
     }
 
@@ -125,6 +166,11 @@ impl UserManager {
         self.current_user().generation_manager().current_index()
     }
 
+    /// Returns the generation of the current user with the given id.
+    pub fn get_generation(&self, id: usize) -> Option<&Generation> {
+        self.current_generation_manager().get(id)
+    }
+
     /// Inserts a requiremnt into the current user.
     /// If the requirement was not fullfilled, a try to get a matching package from the store is started.
     /// If the package could be retrieved a new generation is created and true is returned.
@@ -211,6 +257,20 @@ impl UserManager {
         Ok(())
     }
 
+    /// Replaces the ids of migrated packages in the generations of all users.
+    pub fn migrate_packages<B: ReadBackend<Source = PathBuf>>(
+        &mut self,
+        migrated: &BTreeMap<PackageId, PackageId>,
+        store: &Store<PathBuf, B>,
+    ) -> UserResult<()> {
+        for user in &mut self.users {
+            user.generation_manager_mut()
+                .relink(migrated, store)
+                .context(GenerationSnafu)?;
+        }
+        Ok(())
+    }
+
     /// Lists all packages in the current generation.
     pub fn list_current_packages(&self) {
         self.current_generation_manager().list_current_packages();
@@ -240,6 +300,10 @@ impl UserManager {
 
     /// Flushes all data to the backend
     pub fn flush(self) -> UserResult<()> {
+        if !self.lock.is_exclusive() {
+            return Err(UserError::ReadOnly { path: self.path });
+        }
+
         for user in self.users {
             user.flush()?;
         }
@@ -251,11 +315,13 @@ impl UserManager {
 mod tests {
     use super::UserManager;
     use crate::extra::hash;
+    use crate::extra::lock::LockMode;
     use crate::extra::path::ComponentPathBuf;
     use crate::user::UserError;
     use crate::{store::LocalStore, support::*};
     use std::assert_matches::assert_matches;
     use std::fs;
+    use std::time::Duration;
     use temp_dir::TempDir;
 
     // #[test]
@@ -283,6 +349,28 @@ mod tests {
         let _user_manager = UserManager::open(&path).unwrap();
     }
 
+    #[test]
+    fn user_manager_open_locked() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("user");
+        UserManager::init(&path).unwrap().flush().unwrap();
+
+        let reader = UserManager::open_with(&path, LockMode::Shared, None).unwrap();
+        assert_matches!(
+            UserManager::open(&path).err(),
+            Some(UserError::Busy { path: _ })
+        );
+        assert_matches!(reader.flush(), Err(UserError::ReadOnly { path: _ }));
+
+        let writer = UserManager::open(&path).unwrap();
+        let timeout = Some(Duration::from_millis(100));
+        assert_matches!(
+            UserManager::open_with(&path, LockMode::Shared, timeout).err(),
+            Some(UserError::Busy { path: _ })
+        );
+        writer.flush().unwrap();
+    }
+
     #[test]
     fn user_manager_open_err() {
         let temp_dir = TempDir::new().unwrap();
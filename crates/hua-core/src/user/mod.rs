@@ -27,6 +27,10 @@ pub enum UserError {
     },
     #[snafu(display("Path {path:#?} is not existing"))]
     PathNotExisting { path: PathBuf },
+    #[snafu(display("The users are busy, {path:#?} is locked by another process"))]
+    Busy { path: PathBuf },
+    #[snafu(display("The users were opened read-only and cannot be written to {path:#?}"))]
+    ReadOnly { path: PathBuf },
 }
 
 type UserResult<T> = Result<T, UserError>;
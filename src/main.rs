@@ -7,22 +7,36 @@ use console::style;
 use dialoguer::Select;
 use hua_core::{
     cache::CacheBuilder,
-    config::Config,
-    extra::path::ComponentPathBuf,
+    config::{CacheConfig, Config, DEFAULT_PRIORITY, MAX_PRIORITY, MIN_PRIORITY},
+    extra::{hash::PackageHash, lock::LockMode, path::ComponentPathBuf, style::HumanBytes},
     jail::{Bind, JailBuilder},
-    recipe::{self, build_recipe, Derivation},
+    recipe::{self, build_recipe, Derivation, RecipeError},
     shell::ShellBuilder,
     store::{
+        archive,
+        backend::BackendKind,
+        gc::{GcPolicy, Size},
         locator::Locator,
         package::{LocalPackageSource, RemotePackageSource},
-        LocalStore, STORE_PATH,
+        server::StoreServer,
+        signature::{PublicKey, SecretKey, PACKAGES_DB_SIG},
+        LocalStore, RemoteStore, StoreError, PACKAGES_DB, STORE_PATH,
     },
     url::Url,
     user::UserManager,
     GID, HUA_PATH, UID,
 };
 use log::{debug, info};
-use std::{error::Error, fs, os::unix, path::PathBuf};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashSet},
+    error::Error,
+    fs,
+    io::Write,
+    os::unix::{self, fs::OpenOptionsExt},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 const CONFIG_PATH: &str = "/hua/config.toml";
 const USER_MANAGER_PATH: &str = "/hua/user";
@@ -35,14 +49,30 @@ fn main() -> Result<(), Box<dyn Error>> {
         .about("A simple package manager")
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(arg!(--wait <SECONDS> "Waits up to the given seconds if another hua process uses the store").required(false))
         .subcommands([
-            Command::new("init").about("Initialise the folder structure"),
+            Command::new("init").about("Initialise the folder structure").arg(arg!(--backend <BACKEND> "The database format of the store").required(false).possible_values(["rustbreak", "sqlite"]).default_value("rustbreak")),
             Command::new("store")
                 .about("Do operations on the store")
                 .arg_required_else_help(true)
                 .subcommands([
                     Command::new("search").about("Searches the store for the given name").arg(arg!(<NAME> "The name to search for")),
-                    Command::new("collect-garbage").about("Collects all unused packages in the store and deletes them"),
+                    Command::new("collect-garbage").about("Collects all unused packages in the store and deletes them, limited by the policy of the config").args([arg!(--"dry-run" "Lists the packages which would be deleted and their sizes"), arg!(--"older-than" <DURATION> "Only deletes packages unused for longer than the duration, like 30d").required(false), arg!(--"max-size" <SIZE> "Deletes the least recently used packages until the store is not larger than the size, like 10G").required(false)]),
+                    Command::new("roots").about("Lists the garbage collection roots and the packages they keep").arg(arg!(--prune "Removes stale roots")),
+                    Command::new("verify").about("Verifies the contents of all packages in the store").arg(arg!([NAME] "Only verify packages containing the name")),
+                    Command::new("repair").about("Repairs corrupted packages in the store from the caches or by rebuilding them").arg(arg!([NAME] "Only repair packages containing the name")),
+                    Command::new("fsck").about("Checks the store directory against the packages database").arg(arg!(--fix "Removes orphans and dangling entries")),
+                    Command::new("hash").about("Calculates the package id of a directory").args([arg!(<PATH> "The path of the package directory"), arg!(<NAME> "The name of the package"), arg!(--bench "Reports the throughput of sequential and parallel hashing")]),
+                    Command::new("migrate").about("Recalculates the package ids and moves the blobs into the objects of a store created with an older version"),
+                    Command::new("convert").about("Moves the packages database into another format, by default the one of the config").arg(arg!([BACKEND] "The database format to convert to").possible_values(["rustbreak", "sqlite"])),
+                    Command::new("adopt").about("Registers a package directory which is inside the store but not in the packages database").args([arg!(<PATH> "The path of the package directory"), arg!(<RECIPE> "The recipe of the package")]),
+                    Command::new("stats").about("Reports the disk usage of the packages in the store").args([arg!([NAME] "Only report packages containing the name"), arg!(--sort <KEY> "Sorts the packages, largest first").required(false).possible_values(["name", "size", "unique", "shared", "closure"]).default_value("name"), arg!(--json "Prints the report as json")]),
+                    Command::new("closure").about("Lists a package together with all packages it depends on").args([arg!(<NAME> "The name of the package"), arg!(--tree "Prints the dependencies as a tree")]),
+                    Command::new("export").about("Exports packages with their metadata into an archive").args([arg!(<NAME> "Export packages containing the name"), arg!(<PATH> "The path of the archive"), arg!(--closure "Includes all dependencies of the packages"), arg!(--compress "Compresses the archive with zstd"), arg!(--sign <KEY> "Signs the archive with the secret key file to upload it to a cache, the signature is written next to it").required(false)]),
+                    Command::new("import").about("Verifies and imports the packages of an archive").arg(arg!(<PATH> "The path of the archive")),
+                    Command::new("serve").about("Serves the store as a cache over http").args([arg!(--bind <ADDR> "The address to listen on").required(false).default_value("127.0.0.1:8080"), arg!(--filter <NAME> ... "Only publish packages containing the name").required(false), arg!(--"allow-upload" <KEY> ... "Imports archives uploaded with PUT which are signed by one of the public key files").required(false), arg!(--"max-upload" <SIZE> "Refuses uploads larger than the size, like 512M").required(false), arg!(--sign <KEY> "Signs the served database with the secret key file").required(false)]),
+                    Command::new("keygen").about("Generates a key pair to sign a cache").args([arg!(<NAME> "The name of the key"), arg!(<PATH> "The path of the secret key, the public key is written next to it")]),
+                    Command::new("sign").about("Signs the packages database of a store directory").args([arg!(<KEY> "The path of the secret key"), arg!([PATH] "The path of the store directory").default_value(STORE_PATH)]),
                     // Command::new("add").about("Add package to the store").args([arg!(<LOCK_FILE> "The lock file of the package"), arg!(<PATH> "The path of the package files")])
                     ]),
             Command::new("generations")
@@ -68,6 +98,14 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .about("Creates a new generation without the specified package and switches to the generation")
                 .arg_required_else_help(true)
                 .arg(arg!(<NAME> "The name of package")),
+            Command::new("copy")
+                .about("Copies a package together with its dependencies from another store or a cache into a store")
+                .arg_required_else_help(true)
+                .args([arg!(--from <SOURCE> "The path of the store or the url of the cache to copy from"), arg!(--to <PATH> "The path of the store to copy to").required(false).default_value(STORE_PATH), arg!(<NAME> "The name of the package")]),
+            Command::new("why-depends")
+                .about("Shows every dependency path from a package or a generation to another package")
+                .arg_required_else_help(true)
+                .args([arg!(<ROOT> "The name of the package, or the id of the generation with --generation"), arg!(<TARGET> "The name of the package depended on"), arg!(--generation "Starts from a generation of the current user")]),
             // TODO search
             Command::new("build")
                 .about("Builds a recipe to a new package")
@@ -78,16 +116,22 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .arg_required_else_help(true)
                 .arg(arg!(<NAME> ... "The names of the packages to include in scope")),
             Command::new("cache").about("Change caches").arg_required_else_help(true).subcommands([
-                // TODO add list
-                Command::new("add").about("Adds a cache").arg(arg!(<URL> "The url of the cache")),
-                Command::new("remove").about("Removes a cache"),
+                Command::new("list").about("Lists the caches in the order they are searched and whether they are available"),
+                Command::new("add").about("Adds a cache").args([arg!(<URL> "The url of the cache"), arg!(--priority <PRIORITY> "Caches with a higher priority are searched first, from 1 to 10").required(false).default_value("5")]),
+                Command::new("remove").about("Removes a cache, without an url it asks which one").arg(arg!([URL] "The url of the cache")),
+                Command::new("trust").about("Only accepts the cache if it is signed by one of its trusted keys").args([arg!(<URL> "The url of the cache"), arg!(<KEY> "The public key of the cache")]),
             ])
         ]).get_matches();
 
     // TODO capdacoverride is sufficent no nead for read_search
 
+    let wait = matches
+        .value_of("wait")
+        .map(|seconds| seconds.parse().map(Duration::from_secs))
+        .transpose()?;
+
     match matches.subcommand() {
-        Some(("init", _)) => {
+        Some(("init", sub_matches)) => {
             if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
                 && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
             {
@@ -117,13 +161,18 @@ fn main() -> Result<(), Box<dyn Error>> {
             global_paths.create_dirs(true)?;
             debug!("Global component paths created");
 
-            let _store = LocalStore::init(STORE_PATH)?;
-            info!("Local store in {STORE_PATH} initialised");
+            let backend = sub_matches
+                .value_of("backend")
+                .expect("The backend has a default value.")
+                .parse::<BackendKind>()?;
+
+            let _store = LocalStore::init_with(STORE_PATH, backend)?;
+            info!("Local store in {STORE_PATH} initialised with the {backend} backend");
 
             let _user_manager = UserManager::init(USER_MANAGER_PATH)?;
             info!("User manager in {USER_MANAGER_PATH} initialised");
 
-            let _config = Config::init(CONFIG_PATH, Vec::new())?;
+            let _config = Config::init(CONFIG_PATH, Vec::new(), backend)?;
             info!("Config in {CONFIG_PATH} initialised");
 
             println!("Files and folders created");
@@ -141,12 +190,327 @@ fn main() -> Result<(), Box<dyn Error>> {
                 let name = sub_matches
                     .value_of("NAME")
                     .expect("When searching the store a package name has to be given.");
-                let store = LocalStore::open(STORE_PATH)?;
+                let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
                 for (id, desc, _objects) in store.packages().filter_by_name_containing(name) {
                     println!("{} {desc}\n", style(id.truncate()).blue());
                 }
             }
-            Some(("collect-garbage", _)) => {
+            Some(("collect-garbage", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let dry_run = sub_matches.is_present("dry-run");
+                let policy = Config::open(CONFIG_PATH)?.gc_policy().merge(GcPolicy {
+                    older_than: sub_matches
+                        .value_of("older-than")
+                        .map(str::parse)
+                        .transpose()?,
+                    max_size: sub_matches
+                        .value_of("max-size")
+                        .map(str::parse)
+                        .transpose()?,
+                });
+
+                let mode = if dry_run {
+                    LockMode::Shared
+                } else {
+                    LockMode::Exclusive
+                };
+                let mut store = LocalStore::open_with(STORE_PATH, mode, wait)?;
+                let user_manager = UserManager::open_with(USER_MANAGER_PATH, mode, wait)?;
+
+                let report = store.collect_garbage(&user_manager, policy, dry_run)?;
+                if dry_run {
+                    println!("{report}");
+                } else {
+                    store.flush()?;
+                    println!(
+                        "{} Removed {} packages and freed {}",
+                        style("Success").green(),
+                        report.packages.len(),
+                        HumanBytes(report.freed_size()),
+                    );
+                }
+            }
+            Some(("roots", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
+                let roots = store.gc_roots();
+
+                if sub_matches.is_present("prune") {
+                    let pruned = roots.prune(&store)?;
+                    println!(
+                        "{} {} stale roots pruned",
+                        style("Success").green(),
+                        pruned.len()
+                    );
+                } else {
+                    for root in roots.list(&store)? {
+                        match root
+                            .package
+                            .and_then(|id| store.packages().get(&id).map(|drv| (id, drv)))
+                        {
+                            Some((id, drv)) => println!(
+                                "{} -> {} {} {}",
+                                root.target.display(),
+                                style(&drv.name).blue(),
+                                drv.version,
+                                id
+                            ),
+                            None => {
+                                println!("{} -> {}", root.target.display(), style("stale").yellow())
+                            }
+                        }
+                    }
+                }
+            }
+            Some(("verify", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
+
+                let reports = if let Some(name) = sub_matches.value_of("NAME") {
+                    store
+                        .packages()
+                        .filter_by_name_containing(name)
+                        .map(|(id, _desc, _objects)| store.verify_package(id))
+                        .collect::<Result<Vec<_>, _>>()?
+                } else {
+                    store.verify()?
+                };
+
+                let mut corrupted = 0;
+                for report in &reports {
+                    println!("{report}");
+                    if !report.is_ok() {
+                        corrupted += 1;
+                    }
+                }
+
+                if corrupted > 0 {
+                    return Err(
+                        format!("{corrupted} of {} packages corrupted", reports.len()).into(),
+                    );
+                }
+
+                println!(
+                    "{} {} packages verified",
+                    style("Success").green(),
+                    reports.len()
+                );
+            }
+            Some(("repair", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+
+                let reports = if let Some(name) = sub_matches.value_of("NAME") {
+                    store
+                        .packages()
+                        .filter_by_name_containing(name)
+                        .map(|(id, _desc, _objects)| store.verify_package(id))
+                        .collect::<Result<Vec<_>, _>>()?
+                } else {
+                    store.verify()?
+                };
+                let corrupted = reports
+                    .into_iter()
+                    .filter(|report| !report.is_ok())
+                    .collect::<Vec<_>>();
+
+                if corrupted.len() == 0 {
+                    println!("Nothing to repair");
+                    return Ok(());
+                }
+
+                let config = Config::open(CONFIG_PATH)?;
+                let locator = Locator::new(config.to_caches());
+                let cache = CacheBuilder::default().build()?;
+
+                let mut failed = 0;
+                for report in corrupted {
+                    println!("{report}");
+
+                    let drv = store.packages().get(&report.id).unwrap().clone();
+                    let source = locator.search(&drv).find(|source| source.id == report.id);
+
+                    let result = if let Some(source) = source {
+                        info!("Repairing from {}", source.base);
+                        store.repair_remote(source)
+                    } else {
+                        println!("Rebuilding {}", style(&drv.name).blue());
+                        match recipe::repair_recipe(drv, &report.id, &mut store, &cache) {
+                            Err(RecipeError::StoreError { source }) => Err(source),
+                            result => Ok(result?),
+                        }
+                    };
+
+                    match result {
+                        Ok(report) => println!("{report}"),
+                        Err(StoreError::PackageNotRepaired { report }) => {
+                            println!("{report}");
+                            failed += 1;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+
+                store.flush()?;
+
+                if failed > 0 {
+                    return Err(format!("{failed} packages could not be repaired").into());
+                }
+
+                println!("{} store repaired", style("Success").green());
+            }
+            Some(("fsck", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+
+                if sub_matches.is_present("fix") {
+                    let report = store.fix()?;
+                    println!("{report}");
+                    store.flush()?;
+
+                    if !report.is_ok() {
+                        println!("{} store fixed", style("Success").green());
+                    }
+                } else {
+                    let report = store.check()?;
+                    println!("{report}");
+
+                    if !report.is_ok() {
+                        return Err("Run hua store fsck --fix to fix the store".into());
+                    }
+                }
+            }
+            Some(("adopt", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let path = sub_matches
+                    .value_of("PATH")
+                    .expect("When adopting a package, a path has to be given.");
+                let recipe = sub_matches
+                    .value_of("RECIPE")
+                    .expect("When adopting a package, a recipe has to be given.");
+
+                let path = fs::canonicalize(path)?;
+                let data = fs::read(recipe)?;
+                let drv = toml::from_slice::<Derivation>(&data)?;
+                let name = drv.name.clone();
+
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+                let package_id = store.adopt(&path, drv)?;
+                store.flush()?;
+
+                println!(
+                    "{} {name} adopted as {}",
+                    style("Success").green(),
+                    style(package_id.truncate()).blue()
+                );
+            }
+            Some(("hash", sub_matches)) => {
+                let path = sub_matches
+                    .value_of("PATH")
+                    .expect("When hashing a package, a path has to be given.");
+                let name = sub_matches
+                    .value_of("NAME")
+                    .expect("When hashing a package, a name has to be given.");
+
+                if sub_matches.is_present("bench") {
+                    // Warm up the page cache so that both runs read from memory
+                    let expected = PackageHash::from_path(path, name)?.root;
+
+                    for parallel in [false, true] {
+                        let start = Instant::now();
+                        let hash = if parallel {
+                            PackageHash::from_path(path, name)?
+                        } else {
+                            PackageHash::from_path_sequential(path, name)?
+                        };
+                        let elapsed = start.elapsed().as_secs_f64();
+                        if hash.root != expected {
+                            return Err(format!(
+                                "The package changed while it was hashed, got {} instead of {expected}",
+                                hash.root
+                            )
+                            .into());
+                        }
+
+                        let mib = hash.size as f64 / (1024.0 * 1024.0);
+                        println!(
+                            "{} {} files, {mib:.1} MiB in {elapsed:.3}s ({:.1} MiB/s)",
+                            style(if parallel { "parallel" } else { "sequential" }).blue(),
+                            hash.blobs.len(),
+                            mib / elapsed
+                        );
+                    }
+                    println!("{expected}");
+                } else {
+                    println!("{}", PackageHash::from_path(path, name)?.root);
+                }
+            }
+            Some(("migrate", _)) => {
                 if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
                     && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
                     && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
@@ -160,11 +524,348 @@ fn main() -> Result<(), Box<dyn Error>> {
                     );
                 }
 
-                let mut store = LocalStore::open(STORE_PATH)?;
-                let user_manager = UserManager::open(USER_MANAGER_PATH)?;
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+                let mut user_manager =
+                    UserManager::open_with(USER_MANAGER_PATH, LockMode::Exclusive, wait)?;
+
+                let migrated = store.migrate()?;
+                user_manager.migrate_packages(&migrated, &store)?;
+                // The renamed packages are recorded before the blobs are moved
+                store.flush()?;
+                user_manager.flush()?;
 
-                let _removed = store.remove_unused(&user_manager)?;
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+                let blobs = store.migrate_objects()?;
                 store.flush()?;
+
+                println!(
+                    "{} {} packages migrated, {} blobs moved into the objects",
+                    style("Success").green(),
+                    migrated.len(),
+                    blobs
+                );
+            }
+            Some(("convert", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let mut config = Config::open(CONFIG_PATH)?;
+                if let Some(backend) = sub_matches.value_of("BACKEND") {
+                    config.set_backend(backend.parse()?);
+                    config.flush()?;
+                }
+                let backend = config.backend();
+
+                let store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+                let previous = store.backend_kind();
+                let _store = store.convert(backend)?;
+
+                println!(
+                    "{} Store converted from {previous} to {backend}",
+                    style("Success").green(),
+                );
+            }
+            Some(("stats", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
+                let mut stats = store.stats()?;
+
+                if let Some(name) = sub_matches.value_of("NAME") {
+                    stats.packages.retain(|stats| stats.name.contains(name));
+                }
+                match sub_matches.value_of("sort") {
+                    Some("size") => stats
+                        .packages
+                        .sort_by_key(|stats| Reverse(stats.apparent_size)),
+                    Some("unique") => stats
+                        .packages
+                        .sort_by_key(|stats| Reverse(stats.unique_size)),
+                    Some("shared") => stats
+                        .packages
+                        .sort_by_key(|stats| Reverse(stats.shared_size)),
+                    Some("closure") => stats
+                        .packages
+                        .sort_by_key(|stats| Reverse(stats.closure_size)),
+                    _ => (),
+                }
+
+                if sub_matches.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    println!("{stats}");
+                }
+            }
+            Some(("closure", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let name = sub_matches
+                    .value_of("NAME")
+                    .expect("When querying a closure, a name has to be given.");
+
+                let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
+                let (id, _drv) = store
+                    .packages()
+                    .find_by_name(name)
+                    .ok_or(format!("No package found with name {name}"))?;
+                let closure = store.closure(id)?;
+
+                if sub_matches.is_present("tree") {
+                    let mut printed = HashSet::new();
+                    let mut stack = vec![(0, id)];
+
+                    while let Some((depth, id)) = stack.pop() {
+                        let drv = store
+                            .packages()
+                            .get(id)
+                            .expect("Closure contains the package");
+                        let repeated = !printed.insert(id);
+                        println!(
+                            "{}{} {} {}{}",
+                            "  ".repeat(depth),
+                            style(&drv.name).blue(),
+                            drv.version,
+                            HumanBytes(store.size_of([id])),
+                            if repeated { " (*)" } else { "" }
+                        );
+
+                        if !repeated {
+                            let mut dependencies = store
+                                .dependencies(id, &closure)
+                                .map(|(_req, id)| (depth + 1, id))
+                                .collect::<Vec<_>>();
+                            dependencies.reverse();
+                            stack.extend(dependencies);
+                        }
+                    }
+                } else {
+                    for id in &closure {
+                        let drv = store
+                            .packages()
+                            .get(id)
+                            .expect("Closure contains the package");
+                        println!(
+                            "{} {} {} {}",
+                            style(&drv.name).blue(),
+                            drv.version,
+                            id,
+                            HumanBytes(store.size_of([id]))
+                        );
+                    }
+                }
+
+                println!(
+                    "{} {} packages {}",
+                    style("Total").green(),
+                    closure.len(),
+                    HumanBytes(store.size_of(&closure))
+                );
+            }
+            Some(("export", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let name = sub_matches
+                    .value_of("NAME")
+                    .expect("When exporting packages, a name has to be given.");
+                let path = sub_matches
+                    .value_of("PATH")
+                    .expect("When exporting packages, a path has to be given.");
+
+                let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
+                let mut package_ids = BTreeSet::new();
+                for (id, _drv, _objects) in store.packages().filter_by_name_containing(name) {
+                    if sub_matches.is_present("closure") {
+                        package_ids.extend(store.closure(id)?);
+                    } else {
+                        package_ids.insert(*id);
+                    }
+                }
+
+                if package_ids.is_empty() {
+                    return Err(format!("No package found containing {name}").into());
+                }
+
+                store.export(
+                    &package_ids,
+                    path.as_ref(),
+                    sub_matches.is_present("compress"),
+                )?;
+                if let Some(key) = sub_matches.value_of("sign") {
+                    let key = fs::read_to_string(key)?.parse::<SecretKey>()?;
+                    let signature = key.sign(archive::digest(path.as_ref())?.as_bytes());
+                    fs::write(format!("{path}.sig"), format!("{signature}\n"))?;
+                }
+
+                println!(
+                    "{} {} packages exported to {path}",
+                    style("Success").green(),
+                    package_ids.len()
+                );
+            }
+            Some(("import", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let path = sub_matches
+                    .value_of("PATH")
+                    .expect("When importing an archive, a path has to be given.");
+
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+                let imported = store.import(path.as_ref())?;
+
+                for id in &imported {
+                    let drv = store.packages().get(id).unwrap();
+                    println!("{} {}", style(id.truncate()).blue(), drv.name);
+                }
+                store.flush()?;
+
+                println!(
+                    "{} {} packages imported",
+                    style("Success").green(),
+                    imported.len()
+                );
+            }
+            Some(("serve", sub_matches)) => {
+                let allow_upload = sub_matches.is_present("allow-upload");
+
+                if allow_upload {
+                    if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                        && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                        && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                    {
+                        caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                        caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                        caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                    } else {
+                        return Err(
+                            "Please run hua init as root or with the appropiate capabilities"
+                                .into(),
+                        );
+                    }
+                } else if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let bind = sub_matches
+                    .value_of("bind")
+                    .expect("The bind address has a default value.");
+                let filter = sub_matches.values_of("filter").into_iter().flatten();
+
+                let uploaders = sub_matches
+                    .values_of("allow-upload")
+                    .into_iter()
+                    .flatten()
+                    .map(|key| Ok(fs::read_to_string(key)?.parse::<PublicKey>()?))
+                    .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+                let mut server = StoreServer::new(STORE_PATH)
+                    .filter(filter)
+                    .allow_upload(uploaders);
+                if let Some(size) = sub_matches.value_of("max-upload") {
+                    server = server.max_upload(size.parse::<Size>()?.bytes());
+                }
+                if let Some(key) = sub_matches.value_of("sign") {
+                    let key = fs::read_to_string(key)?.parse::<SecretKey>()?;
+                    server = server.sign_with(key);
+                }
+
+                println!(
+                    "Serving {STORE_PATH} on {}",
+                    style(format!("http://{bind}/")).blue()
+                );
+                server.bind(bind)?;
+            }
+            Some(("keygen", sub_matches)) => {
+                let name = sub_matches
+                    .value_of("NAME")
+                    .expect("When generating a key, a name has to be given.");
+                let path = PathBuf::from(
+                    sub_matches
+                        .value_of("PATH")
+                        .expect("When generating a key, a path has to be given."),
+                );
+                let mut public_path = path.clone().into_os_string();
+                public_path.push(".pub");
+
+                let key = SecretKey::generate(name)?;
+                let public_key = key.public_key();
+
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&path)?
+                    .write_all(format!("{key}\n").as_bytes())?;
+                fs::write(&public_path, format!("{public_key}\n"))?;
+
+                println!(
+                    "{} secret key written to {}, trust the cache with",
+                    style("Success").green(),
+                    path.display()
+                );
+                println!("hua cache trust <url> {public_key}");
+            }
+            Some(("sign", sub_matches)) => {
+                let key = sub_matches
+                    .value_of("KEY")
+                    .expect("When signing a store, a key has to be given.");
+                let path = PathBuf::from(
+                    sub_matches
+                        .value_of("PATH")
+                        .expect("The store path has a default value."),
+                );
+
+                let key = fs::read_to_string(key)?.parse::<SecretKey>()?;
+                let database = fs::read(path.join(PACKAGES_DB))?;
+                let signature = key.sign(&database);
+                fs::write(path.join(PACKAGES_DB_SIG), format!("{signature}\n"))?;
+
+                println!(
+                    "{} {} signed with {}",
+                    style("Success").green(),
+                    path.display(),
+                    key.name()
+                );
             }
             // Some(("add", sub_matches)) => {
             //     if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
@@ -226,7 +927,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                     );
                 }
 
-                let user_manager = UserManager::open(USER_MANAGER_PATH)?;
+                let user_manager =
+                    UserManager::open_with(USER_MANAGER_PATH, LockMode::Shared, wait)?;
                 user_manager.list_current_generations();
             }
             Some(("current", _)) => {
@@ -238,13 +940,16 @@ fn main() -> Result<(), Box<dyn Error>> {
                     );
                 }
 
-                let user_manager = UserManager::open(USER_MANAGER_PATH)?;
+                let user_manager =
+                    UserManager::open_with(USER_MANAGER_PATH, LockMode::Shared, wait)?;
                 println!("{}", user_manager.current_generation_index());
             }
             Some(("remove", sub_matches)) => {
-                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
                     && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
                 {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
                     caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
                     caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
                 } else {
@@ -257,16 +962,27 @@ fn main() -> Result<(), Box<dyn Error>> {
                     .value_of("ID")
                     .expect("When removing a generation, an id has to be given.")
                     .parse()?;
-                let mut user_manager = UserManager::open(USER_MANAGER_PATH)?;
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+                let mut user_manager =
+                    UserManager::open_with(USER_MANAGER_PATH, LockMode::Exclusive, wait)?;
+                // The garbage collection counts the age of the packages from now on
+                let packages = user_manager
+                    .get_generation(id)
+                    .map(|generation| generation.packages().clone())
+                    .unwrap_or_default();
                 user_manager.remove_generation(id)?;
+                store.mark_used(&packages);
+                store.flush()?;
                 user_manager.flush()?;
 
                 println!("{} {id} removed", style("Success").green());
             }
             Some(("switch", sub_matches)) => {
-                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
                     && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
                 {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
                     caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
                     caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
                 } else {
@@ -282,8 +998,16 @@ fn main() -> Result<(), Box<dyn Error>> {
 
                 let global_paths = ComponentPathBuf::global();
 
-                let mut user_manager = UserManager::open(USER_MANAGER_PATH)?;
+                let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+                let mut user_manager =
+                    UserManager::open_with(USER_MANAGER_PATH, LockMode::Exclusive, wait)?;
+                let left = user_manager
+                    .get_generation(user_manager.current_generation_index())
+                    .map(|generation| generation.packages().clone())
+                    .unwrap_or_default();
                 user_manager.switch_generation(id, &global_paths)?;
+                store.mark_used(&left);
+                store.flush()?;
                 user_manager.flush()?;
 
                 println!("{} switched to {id}", style("Success").green());
@@ -313,10 +1037,11 @@ fn main() -> Result<(), Box<dyn Error>> {
             let data = fs::read(path)?;
             let drv = toml::from_slice::<Derivation>(&data)?;
 
-            let mut store = LocalStore::open(STORE_PATH)?;
-            let mut user_manager = UserManager::open(USER_MANAGER_PATH)?;
+            let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
+            let mut user_manager =
+                UserManager::open_with(USER_MANAGER_PATH, LockMode::Exclusive, wait)?;
             let config = Config::open(CONFIG_PATH)?;
-            let locator = Locator::new(config.to_caches().into_iter())?;
+            let locator = Locator::new(config.to_caches());
 
             if store.packages().contains_drv(&drv).is_none() {
                 let mut sources = locator.search(&drv).collect::<Vec<_>>();
@@ -383,8 +1108,9 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .value_of("NAME")
                 .expect("When removing a package, a name has to be provided");
 
-            let store = LocalStore::open(STORE_PATH)?;
-            let mut user_manager = UserManager::open(USER_MANAGER_PATH)?;
+            let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
+            let mut user_manager =
+                UserManager::open_with(USER_MANAGER_PATH, LockMode::Exclusive, wait)?;
 
             let (names, reqs): (Vec<_>, Vec<_>) = user_manager
                 .filter_requirements_by_name_containing(name)
@@ -416,6 +1142,152 @@ fn main() -> Result<(), Box<dyn Error>> {
                 println!("Nothing removed");
             }
         }
+        Some(("copy", sub_matches)) => {
+            if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+            {
+                caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+            } else {
+                return Err(
+                    "Please run hua init as root or with the appropiate capabilities".into(),
+                );
+            }
+
+            let from = sub_matches
+                .value_of("from")
+                .expect("When copying packages, a source has to be given.");
+            let to = sub_matches
+                .value_of("to")
+                .expect("When copying packages, a destination has to be given.");
+            let name = sub_matches
+                .value_of("NAME")
+                .expect("When copying packages, a name has to be given.");
+
+            let from_path = match Url::parse(from) {
+                Ok(url) if url.scheme() == "file" => Some(
+                    url.to_file_path()
+                        .map_err(|_| format!("Invalid store path {url}"))?,
+                ),
+                Ok(_) => None,
+                Err(_) => Some(PathBuf::from(from)),
+            };
+            if let Some(path) = &from_path
+                && fs::canonicalize(path)? == fs::canonicalize(to)?
+            {
+                return Err("The source and the destination are the same store".into());
+            }
+
+            let mut store = LocalStore::open_with(to, LockMode::Exclusive, wait)?;
+            let copied = if let Some(path) = from_path {
+                let source = LocalStore::open_with(path, LockMode::Shared, wait)?;
+                let (id, _drv) = source
+                    .packages()
+                    .find_by_name(name)
+                    .ok_or(format!("No package found with name {name}"))?;
+                store.copy_from(&source, id)?
+            } else {
+                let url = Url::parse(from)?;
+                let config = Config::open(CONFIG_PATH)?;
+                let trusted = config.trusted_keys(&url).cloned().collect::<Vec<_>>();
+                let timeout = config.get_cache(&url).and_then(|cache| cache.timeout);
+                let mut builder = CacheBuilder::default();
+                if let Some(timeout) = timeout {
+                    builder = builder.timeout(timeout);
+                }
+                let remote = RemoteStore::open_cached(url, &trusted, &builder.build()?)?;
+                let (id, _drv) = remote
+                    .packages()
+                    .find_by_name(name)
+                    .ok_or(format!("No package found with name {name}"))?;
+                store.copy_from_remote(&remote, id, timeout)?
+            };
+
+            for id in &copied {
+                let drv = store.packages().get(id).unwrap();
+                println!("{} {}", style(id.truncate()).blue(), drv.name);
+            }
+            store.flush()?;
+
+            println!(
+                "{} {} packages copied to {to}",
+                style("Success").green(),
+                copied.len()
+            );
+        }
+        Some(("why-depends", sub_matches)) => {
+            if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+                caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+            } else {
+                return Err(
+                    "Please run hua init as root or with the appropiate capabilities".into(),
+                );
+            }
+
+            let root = sub_matches
+                .value_of("ROOT")
+                .expect("When searching dependency paths, a root has to be given.");
+            let target = sub_matches
+                .value_of("TARGET")
+                .expect("When searching dependency paths, a target has to be given.");
+
+            let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
+            let user_manager = UserManager::open_with(USER_MANAGER_PATH, LockMode::Shared, wait)?;
+
+            let targets = store
+                .packages()
+                .filter(|_id, drv, _objects| drv.name == target)
+                .map(|(id, _drv, _objects)| *id)
+                .collect::<Vec<_>>();
+
+            let paths = if sub_matches.is_present("generation") {
+                let id = root.parse()?;
+                println!("{} {id}", style("Generation").blue());
+                let generation = user_manager
+                    .get_generation(id)
+                    .ok_or(format!("No generation found with id {id}"))?;
+                let packages = generation
+                    .packages()
+                    .iter()
+                    .copied()
+                    .collect::<BTreeSet<_>>();
+                store.dependency_paths_within(generation.requirements(), &packages, &targets)
+            } else {
+                let (_id, drv) = store
+                    .packages()
+                    .find_by_name(root)
+                    .ok_or(format!("No package found with name {root}"))?;
+                println!("{} {}", style(&drv.name).blue(), drv.version);
+                store.dependency_paths(&drv.requires, &targets)?
+            };
+
+            let count = paths.len();
+            for path in paths {
+                println!();
+                for (depth, (req, id)) in path.iter().enumerate() {
+                    let drv = store
+                        .packages()
+                        .get(id)
+                        .expect("Resolved package is in the store");
+                    println!(
+                        "{}requires {} {} {} {} {}",
+                        "  ".repeat(depth + 1),
+                        style(req.name()).green(),
+                        req.version_req(),
+                        style("->").dim(),
+                        style(&drv.name).blue(),
+                        drv.version
+                    );
+                }
+            }
+
+            if count == 0 {
+                return Err(format!("{root} does not depend on {target}").into());
+            }
+            println!("\n{} {count} paths to {target}", style("Found").green());
+        }
         Some(("build", sub_matches)) => {
             if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
                 && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
@@ -436,7 +1308,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             let data = fs::read(path)?;
             let drv = toml::from_slice::<Derivation>(&data)?;
 
-            let mut store = LocalStore::open(STORE_PATH)?;
+            let mut store = LocalStore::open_with(STORE_PATH, LockMode::Exclusive, wait)?;
             let cache = CacheBuilder::default().build()?;
 
             let link = PathBuf::from("result");
@@ -448,6 +1320,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             if let Some(id) = store.packages().contains_drv(&drv) {
                 let path = drv.path_in_store(store.path(), &id);
                 unix::fs::symlink(path, &link)?;
+                store.gc_roots().add_link(&link)?;
                 println!("{} {link:#?}", style("Success").green());
             } else {
                 let path = recipe::build_recipe(drv, &mut store, &cache)?;
@@ -457,7 +1330,10 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
         Some(("shell", sub_matches)) => {
-            if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+            if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+            {
+                caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
                 caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
             } else {
                 return Err(
@@ -470,7 +1346,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .expect("When creating a shell, package names must be provided.");
             let cwd = std::env::current_dir()?;
 
-            let store = LocalStore::open(STORE_PATH)?;
+            let store = LocalStore::open_with(STORE_PATH, LockMode::Shared, wait)?;
 
             let jail = JailBuilder::new()
                 .bind(Bind::read_write(&cwd, &cwd))
@@ -478,6 +1354,10 @@ fn main() -> Result<(), Box<dyn Error>> {
 
             let shell = ShellBuilder::new()?.with_names(names, &store)?;
             let jail = shell.apply(jail)?;
+            // The process roots keep the packages alive, so the store is not locked
+            // while the shell is running
+            let gc_roots = store.gc_roots();
+            drop(store);
 
             let shell_program = match std::env::var("shell") {
                 Ok(p) => p,
@@ -485,6 +1365,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             };
             let mut child = jail.arg(shell_program).run()?;
             child.wait()?;
+            gc_roots.remove_process(std::process::id())?;
         }
         Some(("cache", sub_matches)) => match sub_matches.subcommand() {
             Some(("add", sub_matches)) => {
@@ -504,15 +1385,28 @@ fn main() -> Result<(), Box<dyn Error>> {
                 let url = sub_matches
                     .value_of("URL")
                     .expect("When adding a cache a url has to be provided");
-                let url = Url::parse(url)?;
+                let url = CacheConfig::parse_url(url)?;
+                let priority = sub_matches
+                    .value_of("priority")
+                    .map(str::parse::<u8>)
+                    .transpose()?
+                    .unwrap_or(DEFAULT_PRIORITY);
+                if !(MIN_PRIORITY..=MAX_PRIORITY).contains(&priority) {
+                    return Err(format!(
+                        "The priority has to be between {MIN_PRIORITY} and {MAX_PRIORITY}"
+                    )
+                    .into());
+                }
 
                 let mut config = Config::open(CONFIG_PATH)?;
-                config.add_cache(url.clone());
+                if !config.add_cache(CacheConfig::new(url.clone()).with_priority(priority)) {
+                    return Err(format!("{url} is already a cache").into());
+                }
                 config.flush()?;
 
                 println!("{} {url} added", style("Success").green());
             }
-            Some(("remove", _)) => {
+            Some(("remove", sub_matches)) => {
                 if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
                     && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
                     && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
@@ -533,17 +1427,102 @@ fn main() -> Result<(), Box<dyn Error>> {
                     return Ok(());
                 }
 
-                let selection = Select::new()
-                    .with_prompt("Wich cache to remove (cancel with ESC or q)?")
-                    .items(config.caches())
-                    .interact_opt()?;
+                let url = if let Some(url) = sub_matches.value_of("URL") {
+                    CacheConfig::parse_url(url)?
+                } else {
+                    let urls = config
+                        .caches()
+                        .iter()
+                        .map(|cache| cache.url.clone())
+                        .collect::<Vec<_>>();
+                    let selection = Select::new()
+                        .with_prompt("Wich cache to remove (cancel with ESC or q)?")
+                        .items(&urls)
+                        .interact_opt()?;
 
-                if let Some(index) = selection {
-                    let removed = config.remove_cache(index);
-                    config.flush()?;
-                    println!("{} {removed} removed", style("Success").green());
+                    if let Some(index) = selection {
+                        urls[index].clone()
+                    } else {
+                        println!("Nothing removed");
+                        return Ok(());
+                    }
+                };
+
+                let removed = config
+                    .remove_cache(&url)
+                    .ok_or(format!("{url} is not a cache"))?;
+                config.flush()?;
+                println!("{} {} removed", style("Success").green(), removed.url);
+            }
+            Some(("trust", sub_matches)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_CHOWN)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_OVERRIDE)?
+                    && caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)?
+                {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_CHOWN)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_OVERRIDE)?;
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
                 } else {
-                    println!("Nothing removed");
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let url = sub_matches
+                    .value_of("URL")
+                    .expect("When trusting a key a url has to be provided");
+                let url = CacheConfig::parse_url(url)?;
+                let key = sub_matches
+                    .value_of("KEY")
+                    .expect("When trusting a key a key has to be provided")
+                    .parse::<PublicKey>()?;
+
+                let mut config = Config::open(CONFIG_PATH)?;
+                let name = key.name().to_owned();
+                if config.trust_key(&url, key).is_none() {
+                    return Err(format!("{url} is not a cache, add it first").into());
+                }
+                config.flush()?;
+
+                println!("{} {url} trusts {name}", style("Success").green());
+            }
+            Some(("list", _)) => {
+                if caps::has_cap(None, CapSet::Permitted, Capability::CAP_DAC_READ_SEARCH)? {
+                    caps::raise(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH)?;
+                } else {
+                    return Err(
+                        "Please run hua init as root or with the appropiate capabilities".into(),
+                    );
+                }
+
+                let config = Config::open(CONFIG_PATH)?;
+                if config.caches().is_empty() {
+                    println!("No caches configured");
+                    return Ok(());
+                }
+
+                let locator = Locator::new(config.to_caches());
+                locator.open_all();
+
+                for (cache, status) in locator.status() {
+                    println!(
+                        "{} priority {} {status}",
+                        style(&cache.url).blue(),
+                        cache.priority
+                    );
+                    if let Some(timeout) = cache.timeout {
+                        println!("\ttimeout {}s", timeout.as_secs());
+                    }
+                    if cache.trusted_keys.is_empty() {
+                        println!("\t{}", style("unsigned").yellow());
+                    } else {
+                        let names = cache
+                            .trusted_keys
+                            .iter()
+                            .map(|key| key.name())
+                            .collect::<Vec<_>>();
+                        println!("\ttrusts {}", names.join(", "));
+                    }
                 }
             }
             _ => unreachable!(),